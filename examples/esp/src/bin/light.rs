@@ -32,7 +32,11 @@ use rs_matter_embassy::matter::data_model::objects::{Dataver, Endpoint, HandlerC
 use rs_matter_embassy::matter::data_model::system_model::descriptor;
 use rs_matter_embassy::matter::utils::init::InitMaybeUninit;
 use rs_matter_embassy::matter::utils::select::Coalesce;
+use rs_matter_embassy::rand::drbg::{drbg_rand, init_drbg};
 use rs_matter_embassy::rand::esp::{esp_init_rand, esp_rand};
+use rs_matter_embassy::rand::health::{
+    health_checked_rand, init_health_checked, FailurePolicy, HealthConfig,
+};
 use rs_matter_embassy::stack::persist::DummyPersist;
 use rs_matter_embassy::stack::test_device::{
     TEST_BASIC_COMM_DATA, TEST_DEV_ATT, TEST_PID, TEST_VID,
@@ -85,6 +89,11 @@ async fn main(_s: Spawner) {
         esp_hal_embassy::init(timg0.timer1);
     }
 
+    // The raw hardware entropy is conditioned and stretched by a DRBG, before being used by the Matter stack
+    // ... after checking its health, as per NIST SP 800-90B
+    init_health_checked(esp_rand, HealthConfig::default(), FailurePolicy::Refuse).unwrap();
+    init_drbg(health_checked_rand);
+
     // == Step 2: ==
     // Allocate the Matter stack.
     // For MCUs, it is best to allocate it statically, so as to avoid program stack blowups (its memory footprint is ~ 35 to 50KB).
@@ -105,7 +114,7 @@ async fn main(_s: Spawner) {
         &TEST_DEV_ATT,
         MdnsType::Builtin,
         epoch,
-        esp_rand,
+        drbg_rand,
     ));
 
     // == Step 3: ==
@@ -37,7 +37,11 @@ use rs_matter_embassy::matter::utils::init::InitMaybeUninit;
 use rs_matter_embassy::matter::utils::select::Coalesce;
 use rs_matter_embassy::nal::{create_net_stack, MatterStackResources, MatterUdpBuffers, Udp};
 use rs_matter_embassy::netif::EmbassyNetif;
+use rs_matter_embassy::rand::drbg::{drbg_rand, init_drbg};
 use rs_matter_embassy::rand::esp::{esp_init_rand, esp_rand};
+use rs_matter_embassy::rand::health::{
+    health_checked_rand, init_health_checked, FailurePolicy, HealthConfig,
+};
 use rs_matter_embassy::stack::persist::DummyPersist;
 use rs_matter_embassy::stack::test_device::{
     TEST_BASIC_COMM_DATA, TEST_DEV_ATT, TEST_PID, TEST_VID,
@@ -91,6 +95,11 @@ async fn main(_s: Spawner) {
         esp_hal_embassy::init(timg0.timer1);
     }
 
+    // The raw hardware entropy is conditioned and stretched by a DRBG, before being used by the Matter stack
+    // ... after checking its health, as per NIST SP 800-90B
+    init_health_checked(esp_rand, HealthConfig::default(), FailurePolicy::Refuse).unwrap();
+    init_drbg(health_checked_rand);
+
     let stack = Box::leak(Box::new_uninit()).init_with(EmbassyEthMatterStack::<()>::init(
         &BasicInfoConfig {
             vid: TEST_VID,
@@ -107,7 +116,7 @@ async fn main(_s: Spawner) {
         &TEST_DEV_ATT,
         MdnsType::Builtin,
         epoch,
-        esp_rand,
+        drbg_rand,
     ));
 
     // Configure and start the Wifi first
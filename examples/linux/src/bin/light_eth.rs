@@ -0,0 +1,171 @@
+//! An example utilizing the `EmbassyEthMatterStack` struct, running on a Linux host.
+//!
+//! As the name suggests, this Matter stack assembly uses Ethernet as the main transport, as well as for commissioning.
+//!
+//! Instead of a real Ethernet MAC, `embassy-net` is driven by a Linux TAP interface, so that the very same
+//! stack assembly as on the MCUs can be run (and debugged) on the host.
+//!
+//! The example implements a fictitious Light device (an On-Off Matter cluster).
+
+use core::pin::pin;
+
+use embassy_executor::Spawner;
+use embassy_futures::select::select3;
+use embassy_time::{Duration, Timer};
+
+use log::info;
+
+use rs_matter_embassy::epoch::std_epoch;
+use rs_matter_embassy::matter::data_model::cluster_basic_information::BasicInfoConfig;
+use rs_matter_embassy::matter::data_model::cluster_on_off;
+use rs_matter_embassy::matter::data_model::device_types::DEV_TYPE_ON_OFF_LIGHT;
+use rs_matter_embassy::matter::data_model::objects::{Dataver, Endpoint, HandlerCompat, Node};
+use rs_matter_embassy::matter::data_model::system_model::descriptor;
+use rs_matter_embassy::matter::utils::init::InitMaybeUninit;
+use rs_matter_embassy::matter::utils::select::Coalesce;
+use rs_matter_embassy::nal::{create_net_stack, MatterStackResources, MatterUdpBuffers, Udp};
+use rs_matter_embassy::netif::EmbassyNetif;
+use rs_matter_embassy::rand::std::std_rand;
+use rs_matter_embassy::stack::persist::DummyPersist;
+use rs_matter_embassy::stack::test_device::{
+    TEST_BASIC_COMM_DATA, TEST_DEV_ATT, TEST_PID, TEST_VID,
+};
+use rs_matter_embassy::stack::MdnsType;
+use rs_matter_embassy::tap::TapDriver;
+use rs_matter_embassy::EmbassyEthMatterStack;
+
+/// The MAC address that the `embassy-net` stack uses on the TAP interface
+const MAC: [u8; 6] = [0x02, 0x00, 0x00, 0x4d, 0x54, 0x52];
+
+#[embassy_executor::main]
+async fn main(_s: Spawner) {
+    env_logger::init_from_env(
+        env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
+    );
+
+    info!("Starting...");
+
+    // The name of the (pre-existing) TAP interface to use
+    let tap = std::env::var("TAP_IF").unwrap_or_else(|_| "tap0".into());
+
+    let stack = Box::leak(Box::new_uninit()).init_with(EmbassyEthMatterStack::<()>::init(
+        &BasicInfoConfig {
+            vid: TEST_VID,
+            pid: TEST_PID,
+            hw_ver: 2,
+            sw_ver: 1,
+            sw_ver_str: "1",
+            serial_no: "aabbccdd",
+            device_name: "MyLight",
+            product_name: "ACME Light",
+            vendor_name: "ACME",
+        },
+        TEST_BASIC_COMM_DATA,
+        &TEST_DEV_ATT,
+        MdnsType::Builtin,
+        std_epoch,
+        std_rand,
+    ));
+
+    let mut seed = [0; core::mem::size_of::<u64>()];
+    std_rand(&mut seed);
+
+    let (net_stack, mut net_runner) = create_net_stack(
+        TapDriver::new(&tap, MAC).unwrap(),
+        u64::from_le_bytes(seed),
+        Box::leak(Box::new_uninit()).init_with(MatterStackResources::new()),
+    );
+
+    // Our "light" on-off cluster.
+    // Can be anything implementing `rs_matter::data_model::AsyncHandler`
+    let on_off = cluster_on_off::OnOffCluster::new(Dataver::new_rand(stack.matter().rand()));
+
+    // Chain our endpoint clusters with the
+    // (root) Endpoint 0 system clusters in the final handler
+    let handler = stack
+        .root_handler()
+        // Our on-off cluster, on Endpoint 1
+        .chain(
+            LIGHT_ENDPOINT_ID,
+            cluster_on_off::ID,
+            HandlerCompat(&on_off),
+        )
+        // Each Endpoint needs a Descriptor cluster too
+        // Just use the one that `rs-matter` provides out of the box
+        .chain(
+            LIGHT_ENDPOINT_ID,
+            descriptor::ID,
+            HandlerCompat(descriptor::DescriptorCluster::new(Dataver::new_rand(
+                stack.matter().rand(),
+            ))),
+        );
+
+    // Run the Matter stack with our handler
+    // Using `pin!` is completely optional, but saves some memory due to `rustc`
+    // not being very intelligent w.r.t. stack usage in async functions
+    let mut matter = pin!(stack.run(
+        // The Matter stack needs access to the netif so as to detect network going up/down
+        EmbassyNetif::new(net_stack),
+        // The Matter stack needs to open two UDP sockets
+        Udp::new(
+            net_stack,
+            Box::leak(Box::new_uninit()).init_with(MatterUdpBuffers::new())
+        ),
+        // The Matter stack needs a persister to store its state
+        // `EmbassyPersist`+`EmbassyKvBlobStore` saves to a user-supplied NOR Flash region
+        // However, for this demo and for simplicity, we use a dummy persister that does nothing
+        DummyPersist,
+        // Our `AsyncHandler` + `AsyncMetadata` impl
+        (NODE, handler),
+        // No user future to run
+        core::future::pending(),
+    ));
+
+    // Just for demoing purposes:
+    //
+    // Run a sample loop that simulates state changes triggered by the HAL
+    // Changes will be properly communicated to the Matter controllers
+    // (i.e. Google Home, Alexa) and other Matter devices thanks to subscriptions
+    let mut device = pin!(async {
+        loop {
+            // Simulate user toggling the light with a physical switch every 5 seconds
+            Timer::after(Duration::from_secs(5)).await;
+
+            // Toggle
+            on_off.set(!on_off.get());
+
+            // Let the Matter stack know that we have changed
+            // the state of our Light device
+            stack.notify_changed();
+
+            info!("Light toggled");
+        }
+    });
+
+    // Schedule the Matter run, the device loop and the `embassy-net` runner together
+    select3(&mut matter, &mut device, async {
+        net_runner.run().await;
+        #[allow(unreachable_code)]
+        Ok(())
+    })
+    .coalesce()
+    .await
+    .unwrap();
+}
+
+/// Endpoint 0 (the root endpoint) always runs
+/// the hidden Matter system clusters, so we pick ID=1
+const LIGHT_ENDPOINT_ID: u16 = 1;
+
+/// The Matter Light device Node
+const NODE: Node = Node {
+    id: 0,
+    endpoints: &[
+        EmbassyEthMatterStack::<()>::root_metadata(),
+        Endpoint {
+            id: LIGHT_ENDPOINT_ID,
+            device_types: &[DEV_TYPE_ON_OFF_LIGHT],
+            clusters: &[descriptor::CLUSTER, cluster_on_off::CLUSTER],
+        },
+    ],
+};
@@ -47,6 +47,10 @@ use rs_matter_embassy::nal::{
     create_link_local_ipv6, multicast_mac_for_link_local_ipv6, MDNS_MULTICAST_MAC_IPV4,
     MDNS_MULTICAST_MAC_IPV6,
 };
+use rs_matter_embassy::rand::drbg::{drbg_rand, init_drbg};
+use rs_matter_embassy::rand::health::{
+    health_checked_rand, init_health_checked, FailurePolicy, HealthConfig,
+};
 use rs_matter_embassy::rand::rp::rp_rand;
 use rs_matter_embassy::stack::persist::DummyPersist;
 use rs_matter_embassy::stack::test_device::{
@@ -171,6 +175,11 @@ async fn main(spawner: Spawner) {
 
     let controller: ExternalController<_, 20> = ExternalController::new(bt_device);
 
+    // The raw hardware entropy is conditioned and stretched by a DRBG, before being used by the Matter stack
+    // ... after checking its health, as per NIST SP 800-90B
+    init_health_checked(rp_rand, HealthConfig::default(), FailurePolicy::Refuse).unwrap();
+    init_drbg(health_checked_rand);
+
     // == Step 2: ==
     // Statically allocate the Matter stack.
     // For MCUs, it is best to allocate it statically, so as to avoid program stack blowups (its memory footprint is ~ 35 to 50KB).
@@ -191,7 +200,7 @@ async fn main(spawner: Spawner) {
         &TEST_DEV_ATT,
         MdnsType::Builtin,
         epoch,
-        rp_rand,
+        drbg_rand,
     ));
 
     // == Step 3: ==
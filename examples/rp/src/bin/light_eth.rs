@@ -44,6 +44,10 @@ use rs_matter_embassy::matter::utils::init::InitMaybeUninit;
 use rs_matter_embassy::matter::utils::select::Coalesce;
 use rs_matter_embassy::nal::{create_net_stack, MatterStackResources, MatterUdpBuffers, Udp};
 use rs_matter_embassy::netif::EmbassyNetif;
+use rs_matter_embassy::rand::drbg::{drbg_rand, init_drbg};
+use rs_matter_embassy::rand::health::{
+    health_checked_rand, init_health_checked, FailurePolicy, HealthConfig,
+};
 use rs_matter_embassy::rand::rp::rp_rand;
 use rs_matter_embassy::stack::persist::DummyPersist;
 use rs_matter_embassy::stack::test_device::{
@@ -135,6 +139,11 @@ async fn main(spawner: Spawner) {
         Ok(())
     });
 
+    // The raw hardware entropy is conditioned and stretched by a DRBG, before being used by the Matter stack
+    // ... after checking its health, as per NIST SP 800-90B
+    init_health_checked(rp_rand, HealthConfig::default(), FailurePolicy::Refuse).unwrap();
+    init_drbg(health_checked_rand);
+
     // == Step 2: ==
     // Statically allocate the Matter stack.
     // For MCUs, it is best to allocate it statically, so as to avoid program stack blowups (its memory footprint is ~ 35 to 50KB).
@@ -155,7 +164,7 @@ async fn main(spawner: Spawner) {
         &TEST_DEV_ATT,
         MdnsType::Builtin,
         epoch,
-        rp_rand,
+        drbg_rand,
     ));
 
     // == Step 3: ==
@@ -33,6 +33,8 @@ use trouble_host::att::{AttCfm, AttClient, AttReq, AttRsp, AttUns};
 use trouble_host::prelude::*;
 use trouble_host::{self, Address, BleHostError, Controller, HostResources};
 
+use crate::redact::Redacted;
+
 const MAX_CONNECTIONS: usize = 1;
 // Issue with esp32c6: we can't go lower than 255 on it
 // Issue with esp32: we can't go lower than 251 on it
@@ -275,7 +277,7 @@ where
             )
             .await?;
 
-            debug!("GATT: Indicate {:02x?} len {}", ind.data, ind.data.len());
+            debug!("GATT: Indicate {}", Redacted(&ind.data));
         }
     }
 
@@ -317,9 +319,8 @@ where
                         }) => {
                             if handle == server.matter_service.c1.handle {
                                 debug!(
-                                    "GATT: C1 Write {:02x?} len {} / MTU {}",
-                                    bytes,
-                                    bytes.len(),
+                                    "GATT: C1 Write {} / MTU {}",
+                                    Redacted(bytes),
                                     conn.att_mtu()
                                 );
 
@@ -0,0 +1,30 @@
+//! CRC: the CRC-32 checksum shared by the factory data and the mirrored persistence records
+
+/// CRC-32 (IEEE 802.3), as used by i.e. zlib
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::crc32;
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
@@ -1,6 +1,94 @@
 //! Epoch: an `epoch` function implementation based on `embassy-time`
+//!
+//! Until the wall-clock time is known, `epoch` returns the time since boot.
+//! Once it is known - i.e. after `set_epoch` is called by one of the time sources, like `sntp::run_sntp` -
+//! `epoch` returns the UTC time since the Unix epoch.
+
+use core::cell::Cell;
+use core::time::Duration;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+
+#[cfg(feature = "rs-matter-stack")]
+pub mod rtc;
+pub mod sntp;
+pub mod time_sync;
+
+/// The source which provided the current wall-clock time
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum TimeSource {
+    /// Set by the application
+    Manual,
+    /// Synchronized with an SNTP server
+    Sntp,
+    /// Restored from a hardware RTC on startup
+    Rtc,
+    /// Restored from the persisted last-known-good time on startup
+    LastKnown,
+    /// Set by a controller, via the Time Synchronization cluster
+    Matter,
+}
+
+/// The synchronization status of the wall-clock time
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct SyncStatus {
+    /// The source of the last synchronization
+    pub source: TimeSource,
+    /// When the last synchronization happened
+    pub synced_at: Instant,
+}
+
+#[derive(Copy, Clone)]
+struct EpochSync {
+    /// The UTC time (in microseconds since the Unix epoch) at the moment `Instant` was zero
+    offset_us: u64,
+    status: SyncStatus,
+}
+
+static SYNC: Mutex<CriticalSectionRawMutex, Cell<Option<EpochSync>>> = Mutex::new(Cell::new(None));
 
 /// Get the current epoch time
-pub fn epoch() -> core::time::Duration {
-    core::time::Duration::from_millis(embassy_time::Instant::now().as_millis())
+pub fn epoch() -> Duration {
+    epoch_at(Instant::now())
+}
+
+/// Get the epoch time at the provided instant
+pub fn epoch_at(instant: Instant) -> Duration {
+    let offset_us = SYNC.lock(|sync| sync.get().map(|sync| sync.offset_us).unwrap_or(0));
+
+    Duration::from_micros(offset_us + instant.as_micros())
+}
+
+/// Set the current UTC time (since the Unix epoch), as reported by `source`
+pub fn set_epoch(now: Duration, source: TimeSource) {
+    set_epoch_at(now, Instant::now(), source);
+}
+
+/// Set the UTC time (since the Unix epoch) at the provided instant, as reported by `source`
+pub fn set_epoch_at(utc: Duration, instant: Instant, source: TimeSource) {
+    let offset_us = (utc.as_micros() as u64).saturating_sub(instant.as_micros());
+
+    SYNC.lock(|sync| {
+        sync.set(Some(EpochSync {
+            offset_us,
+            status: SyncStatus {
+                source,
+                synced_at: instant,
+            },
+        }))
+    });
+}
+
+/// Return the synchronization status of the wall-clock time, or `None` if `epoch` still returns the time since boot
+pub fn sync_status() -> Option<SyncStatus> {
+    SYNC.lock(|sync| sync.get().map(|sync| sync.status))
+}
+
+/// Get the current epoch time, as reported by the system clock of the host OS
+#[cfg(feature = "std")]
+pub fn std_epoch() -> core::time::Duration {
+    ::std::time::SystemTime::now()
+        .duration_since(::std::time::UNIX_EPOCH)
+        .unwrap()
 }
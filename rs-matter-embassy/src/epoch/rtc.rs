@@ -0,0 +1,432 @@
+//! RTC: `EpochKeeper` - keeps the wall-clock time of `epoch` across reboots, by combining a hardware RTC
+//! (an `EpochSource`) with a last-known-good UTC time persisted in an `EmbassyKvBlobStore`
+//!
+//! On startup, `EpochKeeper::restore` sets the wall-clock time from the RTC, or - if the RTC lost its time,
+//! or is behind - from the persisted last-known-good time, so that the time never goes backwards across reboots
+//! and is roughly correct before SNTP or the Matter Time Synchronization cluster provide the exact time.
+//!
+//! Afterwards, `EpochKeeper::run` writes each new synchronization (i.e. from SNTP) to the RTC,
+//! and periodically persists the current time.
+
+use core::time::Duration;
+
+use embassy_time::{Instant, Timer};
+use embedded_storage_async::nor_flash::MultiwriteNorFlash;
+
+use log::info;
+
+use rs_matter_stack::matter::error::{Error, ErrorCode};
+
+use sequential_storage::cache::KeyCacheImpl;
+
+use crate::persist::{AppKey, EmbassyKvBlobStore, SharedKvBlobStore};
+
+use super::{epoch, set_epoch, sync_status, TimeSource};
+
+/// Times before 2024-01-01T00:00:00Z are considered invalid, i.e. an RTC which was never set
+pub const MIN_VALID_EPOCH: Duration = Duration::from_secs(1_704_067_200);
+
+/// The default interval between two stores of the last-known-good time
+///
+/// Each store wears the flash, so this should not be too short; the time restored after a reboot
+/// without an RTC is behind by up to this interval (plus the downtime).
+pub const DEFAULT_PERSIST_INTERVAL: embassy_time::Duration =
+    embassy_time::Duration::from_secs(60 * 60);
+
+/// How often `EpochKeeper::run` checks for new synchronizations
+const POLL_INTERVAL: embassy_time::Duration = embassy_time::Duration::from_secs(10);
+
+/// A source of the UTC time which survives reboots, i.e. a hardware RTC
+pub trait EpochSource {
+    /// Return the UTC time (since the Unix epoch), or `None` if the source does not know it
+    fn now(&mut self) -> Option<Duration>;
+
+    /// Set the UTC time (since the Unix epoch)
+    fn set(&mut self, utc: Duration);
+}
+
+impl<T> EpochSource for &mut T
+where
+    T: EpochSource,
+{
+    fn now(&mut self) -> Option<Duration> {
+        (**self).now()
+    }
+
+    fn set(&mut self, utc: Duration) {
+        (**self).set(utc)
+    }
+}
+
+/// No RTC; only the persisted last-known-good time is used
+impl EpochSource for () {
+    fn now(&mut self) -> Option<Duration> {
+        None
+    }
+
+    fn set(&mut self, _utc: Duration) {}
+}
+
+/// Keeps the wall-clock time of `epoch` across reboots, see the module documentation.
+pub struct EpochKeeper<R> {
+    rtc: R,
+    key: AppKey,
+    synced_at: Option<Instant>,
+    persisted_at: Option<Instant>,
+}
+
+impl<R> EpochKeeper<R>
+where
+    R: EpochSource,
+{
+    /// Create a new keeper, which persists the last-known-good time under the application key `key`.
+    pub const fn new(rtc: R, key: AppKey) -> Self {
+        Self {
+            rtc,
+            key,
+            synced_at: None,
+            persisted_at: None,
+        }
+    }
+
+    /// Set the wall-clock time of `epoch` from the RTC or from the persisted last-known-good time,
+    /// whichever is later, returning it.
+    ///
+    /// Returns `None` (and leaves `epoch` returning the time since boot) if neither is known.
+    pub async fn restore<S, C>(
+        &mut self,
+        kvs: &mut EmbassyKvBlobStore<S, C>,
+        buf: &mut [u8],
+    ) -> Result<Option<Duration>, Error>
+    where
+        S: MultiwriteNorFlash,
+        C: KeyCacheImpl<u8>,
+    {
+        let mut last_known = None;
+
+        kvs.load_app(self.key, buf, |data| {
+            if let Some(data) = data {
+                let secs = u64::from_le_bytes(data.try_into().map_err(|_| ErrorCode::Invalid)?);
+
+                last_known = Some(Duration::from_secs(secs));
+            }
+
+            Ok(())
+        })
+        .await?;
+
+        let rtc = self.rtc.now().filter(|rtc| *rtc >= MIN_VALID_EPOCH);
+
+        let (utc, source) = match (rtc, last_known) {
+            (Some(rtc), Some(last_known)) if rtc >= last_known => (rtc, TimeSource::Rtc),
+            (Some(rtc), None) => (rtc, TimeSource::Rtc),
+            (_, Some(last_known)) => {
+                info!("RTC lost its time or is behind, using the last known time");
+
+                self.rtc.set(last_known);
+
+                (last_known, TimeSource::LastKnown)
+            }
+            (None, None) => return Ok(None),
+        };
+
+        set_epoch(utc, source);
+
+        info!("Epoch restored from {source:?}: {}s", utc.as_secs());
+
+        Ok(Some(utc))
+    }
+
+    /// Write a new synchronization (if any) to the RTC, and - if `persist_interval` elapsed since the last store -
+    /// persist the current time as the last-known-good time.
+    pub async fn update<S, C>(
+        &mut self,
+        kvs: &mut EmbassyKvBlobStore<S, C>,
+        persist_interval: embassy_time::Duration,
+        buf: &mut [u8],
+    ) -> Result<(), Error>
+    where
+        S: MultiwriteNorFlash,
+        C: KeyCacheImpl<u8>,
+    {
+        let Some(status) = sync_status() else {
+            // The time is not known yet; nothing to keep
+            return Ok(());
+        };
+
+        let now = epoch();
+
+        if self.synced_at != Some(status.synced_at) {
+            if !matches!(status.source, TimeSource::Rtc | TimeSource::LastKnown) {
+                self.rtc.set(now);
+            }
+
+            self.synced_at = Some(status.synced_at);
+        }
+
+        if self
+            .persisted_at
+            .map(|persisted_at| persisted_at.elapsed() >= persist_interval)
+            .unwrap_or(true)
+        {
+            kvs.store_app(self.key, buf, |buf| {
+                buf.get_mut(..8)
+                    .ok_or(ErrorCode::NoSpace)?
+                    .copy_from_slice(&now.as_secs().to_le_bytes());
+
+                Ok(8)
+            })
+            .await?;
+
+            self.persisted_at = Some(Instant::now());
+        }
+
+        Ok(())
+    }
+
+    /// Run `update` periodically.
+    ///
+    /// Should be run concurrently with the Matter stack (i.e. as part of the user future passed to `MatterStack::run`).
+    pub async fn run<S, C>(
+        &mut self,
+        kvs: &SharedKvBlobStore<EmbassyKvBlobStore<S, C>>,
+        persist_interval: embassy_time::Duration,
+        buf: &mut [u8],
+    ) -> Result<(), Error>
+    where
+        S: MultiwriteNorFlash,
+        C: KeyCacheImpl<u8>,
+    {
+        loop {
+            self.update(&mut *kvs.lock().await, persist_interval, buf)
+                .await?;
+
+            Timer::after(POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Convert days since the Unix epoch to a (year, month, day) civil date
+#[cfg_attr(not(feature = "rp"), allow(unused))]
+fn days_to_civil(days: u64) -> (u16, u8, u8) {
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as u64;
+
+    (year as u16, month as u8, day as u8)
+}
+
+/// Convert a (year, month, day) civil date (not before 1970-01-01) to days since the Unix epoch
+#[cfg_attr(not(feature = "rp"), allow(unused))]
+fn civil_to_days(year: u16, month: u8, day: u8) -> u64 {
+    // See http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = year as u64 - (month <= 2) as u64;
+    let month = month as u64;
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day as u64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146_097 + doe - 719_468
+}
+
+/// `EpochSource` for the RTC of the esp chips family.
+///
+/// NOTE: Unless the chip is powered from a backup battery in deep sleep, the RTC only survives
+/// resets and deep sleep, not power loss.
+#[cfg(feature = "esp")]
+pub mod esp {
+    use core::time::Duration;
+
+    use super::EpochSource;
+
+    /// An `EpochSource` backed by the RTC of the esp chips
+    pub struct EspRtc<'d>(pub esp_hal::rtc_cntl::Rtc<'d>);
+
+    impl EpochSource for EspRtc<'_> {
+        fn now(&mut self) -> Option<Duration> {
+            Some(Duration::from_micros(self.0.current_time_us()))
+        }
+
+        fn set(&mut self, utc: Duration) {
+            self.0.set_current_time_us(utc.as_micros() as u64);
+        }
+    }
+}
+
+/// `EpochSource` for the RTC of the RP2040.
+///
+/// NOTE: The RTC is not running (and thus the time is unknown) after power loss.
+#[cfg(feature = "rp")]
+pub mod rp {
+    use core::time::Duration;
+
+    use embassy_rp::peripherals::RTC;
+    use embassy_rp::rtc::{DateTime, DayOfWeek, Rtc};
+
+    use super::{civil_to_days, days_to_civil, EpochSource};
+
+    const DAY_SECS: u64 = 24 * 60 * 60;
+
+    /// An `EpochSource` backed by the RTC of the RP2040
+    pub struct RpRtc<'d>(pub Rtc<'d, RTC>);
+
+    impl EpochSource for RpRtc<'_> {
+        fn now(&mut self) -> Option<Duration> {
+            let now = self.0.now().ok()?;
+
+            let secs = civil_to_days(now.year, now.month, now.day) * DAY_SECS
+                + now.hour as u64 * 60 * 60
+                + now.minute as u64 * 60
+                + now.second as u64;
+
+            Some(Duration::from_secs(secs))
+        }
+
+        fn set(&mut self, utc: Duration) {
+            let secs = utc.as_secs();
+            let days = secs / DAY_SECS;
+            let secs = secs % DAY_SECS;
+
+            let (year, month, day) = days_to_civil(days);
+
+            // 1970-01-01 was a Thursday
+            let day_of_week = match (days + 4) % 7 {
+                0 => DayOfWeek::Sunday,
+                1 => DayOfWeek::Monday,
+                2 => DayOfWeek::Tuesday,
+                3 => DayOfWeek::Wednesday,
+                4 => DayOfWeek::Thursday,
+                5 => DayOfWeek::Friday,
+                _ => DayOfWeek::Saturday,
+            };
+
+            let _ = self.0.set_datetime(DateTime {
+                year,
+                month,
+                day,
+                day_of_week,
+                hour: (secs / (60 * 60)) as u8,
+                minute: (secs / 60 % 60) as u8,
+                second: (secs % 60) as u8,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::time::Duration;
+
+    use embassy_futures::block_on;
+
+    use crate::persist::test::{TestFlash, BUF_SIZE};
+    use crate::persist::{AppKey, EmbassyKvBlobStore};
+
+    use super::super::{epoch, set_epoch, sync_status, TimeSource};
+    use super::{
+        civil_to_days, days_to_civil, EpochKeeper, EpochSource, DEFAULT_PERSIST_INTERVAL,
+        MIN_VALID_EPOCH,
+    };
+
+    const KEY: AppKey = AppKey::new(0);
+
+    /// 2024-05-01T12:00:00Z
+    const UTC: Duration = Duration::from_secs(1_714_564_800);
+
+    struct MockRtc(Option<Duration>);
+
+    impl EpochSource for MockRtc {
+        fn now(&mut self) -> Option<Duration> {
+            self.0
+        }
+
+        fn set(&mut self, utc: Duration) {
+            self.0 = Some(utc);
+        }
+    }
+
+    #[test]
+    fn keeper() {
+        let mut flash = TestFlash::new();
+        let mut buf = [0; BUF_SIZE];
+
+        block_on(async {
+            let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+
+            // Nothing known yet
+            let mut keeper = EpochKeeper::new(MockRtc(Some(Duration::from_secs(5))), KEY);
+            assert_eq!(keeper.restore(&mut kvs, &mut buf).await.unwrap(), None);
+            assert_eq!(sync_status(), None);
+
+            keeper
+                .update(&mut kvs, DEFAULT_PERSIST_INTERVAL, &mut buf)
+                .await
+                .unwrap();
+            assert_eq!(keeper.rtc.0, Some(Duration::from_secs(5)));
+
+            // A synchronization is written to the RTC and persisted
+            set_epoch(UTC, TimeSource::Manual);
+            keeper
+                .update(&mut kvs, DEFAULT_PERSIST_INTERVAL, &mut buf)
+                .await
+                .unwrap();
+
+            let rtc = keeper.rtc.0.unwrap();
+            assert!(rtc >= UTC && rtc < UTC + Duration::from_secs(1));
+
+            // Reboot with an RTC which lost its time: the last known time is used, and written to the RTC
+            let mut keeper = EpochKeeper::new(MockRtc(None), KEY);
+            assert_eq!(keeper.restore(&mut kvs, &mut buf).await.unwrap(), Some(UTC));
+            assert_eq!(sync_status().unwrap().source, TimeSource::LastKnown);
+            assert_eq!(keeper.rtc.0, Some(UTC));
+            assert!(epoch() >= UTC);
+
+            // Reboot with an RTC which is behind the last known time
+            let mut keeper = EpochKeeper::new(MockRtc(Some(UTC - Duration::from_secs(60))), KEY);
+            assert_eq!(keeper.restore(&mut kvs, &mut buf).await.unwrap(), Some(UTC));
+            assert_eq!(sync_status().unwrap().source, TimeSource::LastKnown);
+
+            // Reboot with a valid RTC
+            let later = UTC + Duration::from_secs(3600);
+            let mut keeper = EpochKeeper::new(MockRtc(Some(later)), KEY);
+            assert_eq!(
+                keeper.restore(&mut kvs, &mut buf).await.unwrap(),
+                Some(later)
+            );
+            assert_eq!(sync_status().unwrap().source, TimeSource::Rtc);
+
+            // Restoring from the RTC is not a new synchronization, so the RTC is left alone
+            keeper
+                .update(&mut kvs, DEFAULT_PERSIST_INTERVAL, &mut buf)
+                .await
+                .unwrap();
+            assert_eq!(keeper.rtc.0, Some(later));
+        });
+
+        assert!(MIN_VALID_EPOCH < UTC);
+    }
+
+    #[test]
+    fn civil() {
+        for (days, date) in [
+            (0, (1970, 1, 1)),
+            (11_016, (2000, 2, 29)),
+            (19_844, (2024, 5, 1)),
+            (24_855, (2038, 1, 19)),
+            (47_540, (2100, 2, 28)),
+            (47_541, (2100, 3, 1)),
+        ] {
+            assert_eq!(days_to_civil(days), date);
+            assert_eq!(civil_to_days(date.0, date.1, date.2), days);
+        }
+    }
+}
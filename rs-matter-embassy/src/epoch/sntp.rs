@@ -0,0 +1,401 @@
+//! SNTP: `run_sntp` - an SNTP (RFC 4330) client over `embassy-net`, which keeps the wall-clock time of `epoch` synchronized
+//!
+//! NOTE: The client needs one UDP socket, on top of the `MIN_SOCKET_SET` sockets used by the Matter stack,
+//! so the `embassy-net` `StackResources` should be sized accordingly (i.e. `StackResources<{ MIN_SOCKET_SET + 1 }>`).
+
+use core::time::Duration;
+
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint, Stack};
+use embassy_time::{with_timeout, Instant, Timer};
+
+use log::{info, warn};
+
+use rs_matter_stack::matter::error::Error;
+
+use super::{set_epoch_at, TimeSource};
+
+/// The UDP port of the SNTP servers
+pub const SNTP_PORT: u16 = 123;
+
+/// The default interval between two synchronizations
+pub const DEFAULT_SYNC_INTERVAL: embassy_time::Duration =
+    embassy_time::Duration::from_secs(60 * 60);
+
+/// The interval between two synchronization attempts, when the previous one failed with all servers
+pub const RETRY_INTERVAL: embassy_time::Duration = embassy_time::Duration::from_secs(30);
+
+/// How long to wait for the response of a server
+pub const RESPONSE_TIMEOUT: embassy_time::Duration = embassy_time::Duration::from_secs(5);
+
+/// The length of an SNTP packet (without the optional authentication fields)
+const PACKET_LEN: usize = 48;
+
+/// Seconds between the NTP epoch (1900-01-01) and the Unix epoch (1970-01-01)
+const NTP_UNIX_OFFSET_SECS: u64 = 2_208_988_800;
+
+/// LI = 0, VN = 4, Mode = 3 (client)
+const CLIENT_HEADER: u8 = 0x23;
+
+/// Mode = 4 (server)
+const MODE_SERVER: u8 = 4;
+
+/// LI = 3 (clock not synchronized)
+const LI_ALARM: u8 = 3;
+
+/// An SNTP synchronization error
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum SntpError {
+    /// The socket could not be bound, or the request could not be sent
+    Io,
+    /// The server did not respond in time
+    Timeout,
+    /// The response is malformed, or does not match the request
+    InvalidResponse,
+    /// The server sent a "kiss-o'-death" response, i.e. asked the client to back off
+    KissOfDeath,
+    /// The server is not synchronized itself
+    Unsynchronized,
+}
+
+/// The timestamps of an SNTP response, as durations since the Unix epoch
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct SntpResponse {
+    /// When the request arrived at the server
+    pub receive: Duration,
+    /// When the response left the server
+    pub transmit: Duration,
+}
+
+impl SntpResponse {
+    /// Return the UTC time at the moment the response was received,
+    /// given the (local, monotonic) instants when the request was sent and the response received.
+    ///
+    /// Assumes that the network delay is symmetric, i.e. adds half of the round-trip delay
+    /// (minus the processing time of the server) to the transmit timestamp of the server.
+    pub fn utc_at(&self, sent: Instant, received: Instant) -> Duration {
+        let round_trip =
+            Duration::from_micros(received.as_micros().saturating_sub(sent.as_micros()));
+        let delay = round_trip.saturating_sub(self.transmit.saturating_sub(self.receive));
+
+        self.transmit + delay / 2
+    }
+}
+
+/// Run an SNTP client, which synchronizes the wall-clock time of `epoch` every `interval`.
+///
+/// The `servers` are tried in order, until one of them responds.
+///
+/// Should be run concurrently with the Matter stack (i.e. as part of the user future passed to `MatterStack::run`).
+pub async fn run_sntp(
+    stack: Stack<'_>,
+    servers: &[IpAddress],
+    interval: embassy_time::Duration,
+) -> Result<(), Error> {
+    loop {
+        stack.wait_config_up().await;
+
+        let mut synced = false;
+
+        for server in servers {
+            match sntp_sync(stack, *server).await {
+                Ok(utc) => {
+                    info!(
+                        "SNTP: synchronized with {server}, UTC time: {}s",
+                        utc.as_secs()
+                    );
+
+                    synced = true;
+                    break;
+                }
+                Err(err) => warn!("SNTP: synchronization with {server} failed: {err:?}"),
+            }
+        }
+
+        Timer::after(if synced { interval } else { RETRY_INTERVAL }).await;
+    }
+}
+
+/// Synchronize the wall-clock time of `epoch` with the SNTP `server`, returning the new UTC time.
+pub async fn sntp_sync(stack: Stack<'_>, server: IpAddress) -> Result<Duration, SntpError> {
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buf = [0; PACKET_LEN * 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buf = [0; PACKET_LEN];
+
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+
+    socket.bind(0).map_err(|_| SntpError::Io)?;
+
+    let server = IpEndpoint::new(server, SNTP_PORT);
+
+    let sent = Instant::now();
+
+    // The server echoes the transmit timestamp of the request in the originate timestamp of the response;
+    // as the UTC time is not known yet, use the local time, which also makes the timestamp unique
+    let originate = ntp_timestamp(Duration::from_micros(sent.as_micros()));
+
+    let mut packet = [0; PACKET_LEN];
+    packet[0] = CLIENT_HEADER;
+    packet[40..48].copy_from_slice(&originate);
+
+    socket
+        .send_to(&packet, server)
+        .await
+        .map_err(|_| SntpError::Io)?;
+
+    let (response, received) = with_timeout(RESPONSE_TIMEOUT, async {
+        loop {
+            let (len, meta) = socket
+                .recv_from(&mut packet)
+                .await
+                .map_err(|_| SntpError::Io)?;
+
+            // Ignore stray packets
+            if meta.endpoint == server {
+                break Ok::<_, SntpError>((
+                    parse_response(&packet[..len], &originate)?,
+                    Instant::now(),
+                ));
+            }
+        }
+    })
+    .await
+    .map_err(|_| SntpError::Timeout)??;
+
+    let utc = response.utc_at(sent, received);
+
+    set_epoch_at(utc, received, TimeSource::Sntp);
+
+    Ok(utc)
+}
+
+/// Parse and validate an SNTP response to a request with the provided transmit timestamp
+pub fn parse_response(packet: &[u8], originate: &[u8; 8]) -> Result<SntpResponse, SntpError> {
+    if packet.len() < PACKET_LEN {
+        return Err(SntpError::InvalidResponse);
+    }
+
+    let leap = packet[0] >> 6;
+    let version = (packet[0] >> 3) & 0x07;
+    let mode = packet[0] & 0x07;
+    let stratum = packet[1];
+
+    if mode != MODE_SERVER || !(1..=4).contains(&version) || &packet[24..32] != originate {
+        return Err(SntpError::InvalidResponse);
+    }
+
+    if stratum == 0 {
+        return Err(SntpError::KissOfDeath);
+    }
+
+    if leap == LI_ALARM || stratum > 15 {
+        return Err(SntpError::Unsynchronized);
+    }
+
+    let receive = unix_time(packet[32..40].try_into().unwrap());
+    let transmit = unix_time(packet[40..48].try_into().unwrap());
+
+    match (receive, transmit) {
+        (Some(receive), Some(transmit)) if receive <= transmit => {
+            Ok(SntpResponse { receive, transmit })
+        }
+        _ => Err(SntpError::InvalidResponse),
+    }
+}
+
+/// Convert an NTP timestamp (32.32 fixed point seconds since 1900) to a duration since the Unix epoch,
+/// or `None` if the timestamp is zero (i.e. not set by the server) or before the Unix epoch
+fn unix_time(timestamp: &[u8; 8]) -> Option<Duration> {
+    let secs = u32::from_be_bytes(timestamp[..4].try_into().unwrap()) as u64;
+    let fraction = u32::from_be_bytes(timestamp[4..].try_into().unwrap()) as u64;
+
+    if secs == 0 && fraction == 0 {
+        return None;
+    }
+
+    // Timestamps with the most significant bit cleared are in era 1, i.e. after 2036-02-07
+    let secs = if secs & 0x8000_0000 == 0 {
+        secs + (1 << 32)
+    } else {
+        secs
+    };
+
+    // Era 0 timestamps before 1970 cannot come from a synchronized server
+    let secs = secs.checked_sub(NTP_UNIX_OFFSET_SECS)?;
+
+    Some(Duration::from_secs(secs) + Duration::from_nanos((fraction * 1_000_000_000) >> 32))
+}
+
+/// Convert a duration to an NTP timestamp (32.32 fixed point seconds)
+fn ntp_timestamp(time: Duration) -> [u8; 8] {
+    let secs = time.as_secs() as u32;
+    let fraction = ((time.subsec_nanos() as u64) << 32) / 1_000_000_000;
+
+    let mut timestamp = [0; 8];
+    timestamp[..4].copy_from_slice(&secs.to_be_bytes());
+    timestamp[4..].copy_from_slice(&(fraction as u32).to_be_bytes());
+
+    timestamp
+}
+
+#[cfg(test)]
+mod test {
+    use core::time::Duration;
+
+    use embassy_time::Instant;
+
+    use super::{ntp_timestamp, parse_response, unix_time, SntpError, SntpResponse};
+
+    /// 2024-05-01T12:00:00Z
+    const UNIX_SECS: u64 = 1_714_564_800;
+    const NTP_SECS: u32 = (UNIX_SECS + 2_208_988_800) as u32;
+
+    const ORIGINATE: [u8; 8] = [0, 0, 0, 42, 0x80, 0, 0, 0];
+
+    fn response(header: u8, stratum: u8, receive: [u8; 8], transmit: [u8; 8]) -> [u8; 48] {
+        let mut packet = [0; 48];
+
+        packet[0] = header;
+        packet[1] = stratum;
+        packet[24..32].copy_from_slice(&ORIGINATE);
+        packet[32..40].copy_from_slice(&receive);
+        packet[40..48].copy_from_slice(&transmit);
+
+        packet
+    }
+
+    fn timestamp(secs: u32, fraction: u32) -> [u8; 8] {
+        let mut timestamp = [0; 8];
+        timestamp[..4].copy_from_slice(&secs.to_be_bytes());
+        timestamp[4..].copy_from_slice(&fraction.to_be_bytes());
+
+        timestamp
+    }
+
+    #[test]
+    fn parse() {
+        // LI = 0, VN = 4, Mode = 4
+        let packet = response(
+            0x24,
+            2,
+            timestamp(NTP_SECS, 0),
+            timestamp(NTP_SECS, 0x4000_0000),
+        );
+
+        assert_eq!(
+            parse_response(&packet, &ORIGINATE),
+            Ok(SntpResponse {
+                receive: Duration::from_secs(UNIX_SECS),
+                transmit: Duration::from_secs(UNIX_SECS) + Duration::from_millis(250),
+            })
+        );
+
+        // VN = 3 is accepted too
+        assert!(parse_response(
+            &response(0x1c, 2, timestamp(NTP_SECS, 0), timestamp(NTP_SECS, 0)),
+            &ORIGINATE
+        )
+        .is_ok());
+
+        // Truncated
+        assert_eq!(
+            parse_response(&packet[..47], &ORIGINATE),
+            Err(SntpError::InvalidResponse)
+        );
+
+        // Not a response to our request
+        assert_eq!(
+            parse_response(&packet, &[0; 8]),
+            Err(SntpError::InvalidResponse)
+        );
+
+        // Mode = 3 (client)
+        let mut invalid = packet;
+        invalid[0] = 0x23;
+        assert_eq!(
+            parse_response(&invalid, &ORIGINATE),
+            Err(SntpError::InvalidResponse)
+        );
+
+        // Kiss-o'-death
+        let mut invalid = packet;
+        invalid[1] = 0;
+        assert_eq!(
+            parse_response(&invalid, &ORIGINATE),
+            Err(SntpError::KissOfDeath)
+        );
+
+        // LI = 3
+        let mut invalid = packet;
+        invalid[0] |= 0xc0;
+        assert_eq!(
+            parse_response(&invalid, &ORIGINATE),
+            Err(SntpError::Unsynchronized)
+        );
+
+        // No transmit timestamp
+        let mut invalid = packet;
+        invalid[40..48].fill(0);
+        assert_eq!(
+            parse_response(&invalid, &ORIGINATE),
+            Err(SntpError::InvalidResponse)
+        );
+
+        // A transmit timestamp before the Unix epoch
+        let mut invalid = packet;
+        invalid[40..48].copy_from_slice(&timestamp(0x8000_0000, 0));
+        assert_eq!(
+            parse_response(&invalid, &ORIGINATE),
+            Err(SntpError::InvalidResponse)
+        );
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(
+            unix_time(&timestamp(NTP_SECS, 0x8000_0000)),
+            Some(Duration::from_secs(UNIX_SECS) + Duration::from_millis(500))
+        );
+
+        // Era 1: 2036-02-07T06:28:16Z is the first second after the rollover
+        assert_eq!(
+            unix_time(&timestamp(0, 1)),
+            Some(Duration::from_secs(2_085_978_496))
+        );
+        assert_eq!(
+            unix_time(&timestamp(1, 0)),
+            Some(Duration::from_secs(2_085_978_497))
+        );
+
+        assert_eq!(unix_time(&[0; 8]), None);
+
+        // Era 0, but before the Unix epoch
+        assert_eq!(unix_time(&timestamp(0x8000_0000, 0)), None);
+        assert_eq!(unix_time(&timestamp(0x83aa_7e7f, 0xffff_ffff)), None);
+        assert_eq!(
+            unix_time(&timestamp(0x83aa_7e80, 0)),
+            Some(Duration::from_secs(0))
+        );
+
+        let time = Duration::from_secs(42) + Duration::from_millis(125);
+        assert_eq!(ntp_timestamp(time), timestamp(42, 0x2000_0000));
+    }
+
+    #[test]
+    fn round_trip() {
+        let response = SntpResponse {
+            receive: Duration::from_secs(UNIX_SECS),
+            transmit: Duration::from_secs(UNIX_SECS) + Duration::from_millis(10),
+        };
+
+        // 110ms round trip, of which 10ms were spent by the server: 50ms one-way delay
+        let sent = Instant::from_millis(1000);
+        let received = Instant::from_millis(1110);
+
+        assert_eq!(
+            response.utc_at(sent, received),
+            Duration::from_secs(UNIX_SECS) + Duration::from_millis(60)
+        );
+    }
+}
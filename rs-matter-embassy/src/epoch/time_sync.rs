@@ -0,0 +1,887 @@
+//! Time Synchronization: `TimeSyncCluster` - the server side of the Matter Time Synchronization cluster,
+//! with the Time Zone feature
+//!
+//! UTC time pushed by a controller with `SetUTCTime` adjusts the wall-clock time of `epoch`;
+//! the time zone and DST offsets pushed with `SetTimeZone` and `SetDSTOffset` are used for the `LocalTime` attribute.
+//!
+//! The time zone and DST offsets are only kept in RAM (and thus are not declared non-volatile);
+//! after a reboot, `LocalTime` is unknown until a controller provides them again.
+//!
+//! The cluster should be added to the root endpoint (0) of the node.
+
+use core::cell::{Cell, RefCell};
+use core::time::Duration;
+
+use log::info;
+
+use rs_matter::data_model::objects::{
+    Access, AttrDataEncoder, AttrDataWriter, AttrDetails, Attribute, Cluster, CmdDataEncoder,
+    CmdDetails, Dataver, Handler, NonBlockingHandler, Quality, ATTRIBUTE_LIST, FEATURE_MAP,
+};
+use rs_matter::error::{Error, ErrorCode};
+use rs_matter::tlv::{FromTLV, Nullable, TLVArray, TLVElement, TLVTag, TLVWrite, ToTLV};
+use rs_matter::transport::exchange::Exchange;
+
+use super::{epoch, set_epoch, sync_status, TimeSource};
+
+/// The ID of the Time Synchronization cluster
+pub const ID: u32 = 0x0038;
+
+/// Seconds between the Unix epoch (1970-01-01) and the Matter epoch (2000-01-01)
+pub const MATTER_EPOCH_OFFSET_SECS: u64 = 946_684_800;
+
+/// The maximum number of entries in the `TimeZone` list
+pub const TIME_ZONE_LIST_MAX_SIZE: usize = 2;
+
+/// The maximum number of entries in the `DSTOffset` list
+pub const DST_OFFSET_LIST_MAX_SIZE: usize = 2;
+
+/// The maximum length of the name of a time zone
+pub const TIME_ZONE_NAME_MAX_LEN: usize = 64;
+
+/// The Time Zone feature
+const FEATURE_TIME_ZONE: u32 = 0x01;
+
+const MIN_TIME_ZONE_OFFSET: i32 = -12 * 60 * 60;
+const MAX_TIME_ZONE_OFFSET: i32 = 14 * 60 * 60;
+
+const MICROS: u64 = 1_000_000;
+
+/// The attributes of the cluster
+pub mod attributes {
+    pub const UTC_TIME: u16 = 0x0000;
+    pub const GRANULARITY: u16 = 0x0001;
+    pub const TIME_SOURCE: u16 = 0x0002;
+    pub const TIME_ZONE: u16 = 0x0005;
+    pub const DST_OFFSET: u16 = 0x0006;
+    pub const LOCAL_TIME: u16 = 0x0007;
+    pub const TIME_ZONE_DATABASE: u16 = 0x0008;
+    pub const TIME_ZONE_LIST_MAX_SIZE: u16 = 0x000a;
+    pub const DST_OFFSET_LIST_MAX_SIZE: u16 = 0x000b;
+}
+
+/// The cluster-specific status codes of the cluster
+pub mod status {
+    /// `SetUTCTime` was rejected, i.e. because the node already has a time with a finer granularity
+    pub const TIME_NOT_ACCEPTED: u16 = 0x02;
+}
+
+/// The commands of the cluster
+pub mod commands {
+    pub const SET_UTC_TIME: u32 = 0x00;
+    pub const SET_TIME_ZONE: u32 = 0x02;
+    pub const SET_TIME_ZONE_RESPONSE: u32 = 0x03;
+    pub const SET_DST_OFFSET: u32 = 0x04;
+}
+
+/// The metadata of the cluster
+pub const CLUSTER: Cluster<'static> = Cluster {
+    id: ID as _,
+    feature_map: FEATURE_TIME_ZONE,
+    attributes: &[
+        FEATURE_MAP,
+        ATTRIBUTE_LIST,
+        Attribute::new(attributes::UTC_TIME, Access::RV, Quality::X),
+        Attribute::new(attributes::GRANULARITY, Access::RV, Quality::NONE),
+        Attribute::new(attributes::TIME_SOURCE, Access::RV, Quality::NONE),
+        Attribute::new(attributes::TIME_ZONE, Access::RV, Quality::NONE),
+        Attribute::new(attributes::DST_OFFSET, Access::RV, Quality::NONE),
+        Attribute::new(attributes::LOCAL_TIME, Access::RV, Quality::X),
+        Attribute::new(attributes::TIME_ZONE_DATABASE, Access::RV, Quality::F),
+        Attribute::new(attributes::TIME_ZONE_LIST_MAX_SIZE, Access::RV, Quality::F),
+        Attribute::new(attributes::DST_OFFSET_LIST_MAX_SIZE, Access::RV, Quality::F),
+    ],
+    commands: &[
+        commands::SET_UTC_TIME,
+        commands::SET_TIME_ZONE,
+        commands::SET_DST_OFFSET,
+    ],
+};
+
+/// The granularity of the UTC time of the node
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[repr(u8)]
+pub enum Granularity {
+    /// The UTC time is not known
+    NoTimeGranularity = 0,
+    /// Accurate to within minutes
+    Minutes = 1,
+    /// Accurate to within seconds
+    Seconds = 2,
+    /// Accurate to within milliseconds
+    Milliseconds = 3,
+    /// Accurate to within microseconds
+    Microseconds = 4,
+}
+
+impl Granularity {
+    fn from_u8(value: u8) -> Result<Self, Error> {
+        Ok(match value {
+            0 => Self::NoTimeGranularity,
+            1 => Self::Minutes,
+            2 => Self::Seconds,
+            3 => Self::Milliseconds,
+            4 => Self::Microseconds,
+            _ => Err(ErrorCode::ConstraintError)?,
+        })
+    }
+}
+
+/// The `TimeSourceEnum` values reported by the cluster
+mod time_source {
+    pub const NONE: u8 = 0;
+    pub const UNKNOWN: u8 = 1;
+    pub const ADMIN: u8 = 2;
+    pub const NON_MATTER_SNTP: u8 = 4;
+    /// The largest valid value (`GNSS`)
+    pub const MAX: u8 = 16;
+}
+
+/// `TimeZoneDatabaseEnum::None`: the node has no time zone database, and thus needs the DST offsets from the controller
+const TIME_ZONE_DATABASE_NONE: u8 = 2;
+
+/// A time zone, as set by `SetTimeZone`
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct TimeZone {
+    /// The offset from UTC, in seconds
+    pub offset: i32,
+    /// When the time zone becomes valid (Matter epoch, microseconds)
+    pub valid_at: u64,
+    /// The name of the time zone, i.e. "Europe/Sofia"
+    pub name: Option<heapless::String<TIME_ZONE_NAME_MAX_LEN>>,
+}
+
+/// A DST offset, as set by `SetDSTOffset`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct DstOffset {
+    /// The offset from the standard time of the time zone, in seconds
+    pub offset: i32,
+    /// When the offset starts to apply (Matter epoch, microseconds)
+    pub valid_starting: u64,
+    /// When the offset stops to apply (Matter epoch, microseconds); `None` means forever
+    pub valid_until: Option<u64>,
+}
+
+impl DstOffset {
+    fn is_active(&self, utc: u64) -> bool {
+        self.valid_starting <= utc && self.valid_until.map(|until| utc < until).unwrap_or(true)
+    }
+}
+
+/// Validate a time zone list: there should be at least one entry, the first entry should be valid
+/// from the beginning of time, the entries should be sorted by `valid_at`, and the offsets should be within -12h and +14h.
+///
+/// More than `TIME_ZONE_LIST_MAX_SIZE` entries result in `ErrorCode::ResourceExhausted`.
+pub fn validate_time_zones(time_zones: &[TimeZone]) -> Result<(), Error> {
+    if time_zones.len() > TIME_ZONE_LIST_MAX_SIZE {
+        return Err(ErrorCode::ResourceExhausted.into());
+    }
+
+    if time_zones.is_empty() {
+        return Err(ErrorCode::ConstraintError.into());
+    }
+
+    if time_zones.first().is_some_and(|tz| tz.valid_at != 0) {
+        return Err(ErrorCode::ConstraintError.into());
+    }
+
+    let sorted = time_zones
+        .windows(2)
+        .all(|pair| pair[0].valid_at < pair[1].valid_at);
+
+    let offsets_valid = time_zones
+        .iter()
+        .all(|tz| (MIN_TIME_ZONE_OFFSET..=MAX_TIME_ZONE_OFFSET).contains(&tz.offset));
+
+    if !sorted || !offsets_valid {
+        return Err(ErrorCode::ConstraintError.into());
+    }
+
+    Ok(())
+}
+
+/// Validate a DST offset list: the entries should be sorted by `valid_starting` and should not overlap,
+/// and only the last one can be valid forever.
+///
+/// More than `DST_OFFSET_LIST_MAX_SIZE` entries result in `ErrorCode::ResourceExhausted`.
+pub fn validate_dst_offsets(dst_offsets: &[DstOffset]) -> Result<(), Error> {
+    if dst_offsets.len() > DST_OFFSET_LIST_MAX_SIZE {
+        return Err(ErrorCode::ResourceExhausted.into());
+    }
+
+    let ordered = dst_offsets.iter().all(|dst| {
+        dst.valid_until
+            .map(|until| dst.valid_starting < until)
+            .unwrap_or(true)
+    }) && dst_offsets.windows(2).all(|pair| {
+        pair[0]
+            .valid_until
+            .map(|until| until <= pair[1].valid_starting)
+            .unwrap_or(false)
+    });
+
+    if !ordered {
+        return Err(ErrorCode::ConstraintError.into());
+    }
+
+    Ok(())
+}
+
+/// Compute the local time (Matter epoch, microseconds) for the provided UTC time (Matter epoch, microseconds).
+///
+/// Returns `None` if there is no time zone, or no DST offset valid at `utc`: as per the spec,
+/// the local time is unknown until the controller provides the DST offsets (zero ones for time zones without DST).
+pub fn local_time(utc: u64, time_zones: &[TimeZone], dst_offsets: &[DstOffset]) -> Option<u64> {
+    let time_zone = time_zones.iter().rev().find(|tz| tz.valid_at <= utc)?;
+    let dst_offset = dst_offsets.iter().find(|dst| dst.is_active(utc))?;
+
+    let offset = (time_zone.offset as i64 + dst_offset.offset as i64) * MICROS as i64;
+
+    u64::try_from(utc as i64 + offset).ok()
+}
+
+/// Convert a time since the Unix epoch to microseconds since the Matter epoch
+pub fn to_matter_epoch_us(unix: Duration) -> u64 {
+    (unix.as_micros() as u64).saturating_sub(MATTER_EPOCH_OFFSET_SECS * MICROS)
+}
+
+/// Convert microseconds since the Matter epoch to a time since the Unix epoch
+pub fn from_matter_epoch_us(matter_us: u64) -> Duration {
+    Duration::from_micros(matter_us) + Duration::from_secs(MATTER_EPOCH_OFFSET_SECS)
+}
+
+#[derive(FromTLV, ToTLV, Debug)]
+#[tlvargs(lifetime = "'a")]
+struct TimeZoneStruct<'a> {
+    offset: i32,
+    valid_at: u64,
+    name: Option<&'a str>,
+}
+
+#[derive(FromTLV, ToTLV, Debug)]
+struct DstOffsetStruct {
+    offset: i32,
+    valid_starting: u64,
+    valid_until: Nullable<u64>,
+}
+
+#[derive(FromTLV, Debug)]
+struct SetUtcTimeRequest {
+    utc_time: u64,
+    granularity: u8,
+    time_source: Option<u8>,
+}
+
+#[derive(FromTLV, Debug)]
+#[tlvargs(lifetime = "'a")]
+struct SetTimeZoneRequest<'a> {
+    time_zone: TLVArray<'a, TimeZoneStruct<'a>>,
+}
+
+#[derive(FromTLV, Debug)]
+#[tlvargs(lifetime = "'a")]
+struct SetDstOffsetRequest<'a> {
+    dst_offset: TLVArray<'a, DstOffsetStruct>,
+}
+
+#[derive(ToTLV, Debug)]
+struct SetTimeZoneResponse {
+    dst_offset_required: bool,
+}
+
+struct State {
+    time_zones: heapless::Vec<TimeZone, TIME_ZONE_LIST_MAX_SIZE>,
+    dst_offsets: heapless::Vec<DstOffset, DST_OFFSET_LIST_MAX_SIZE>,
+}
+
+/// The server side of the Time Synchronization cluster.
+pub struct TimeSyncCluster {
+    data_ver: Dataver,
+    /// The granularity and the `TimeSourceEnum` value provided with the last `SetUTCTime`
+    admin: Cell<Option<(Granularity, u8)>>,
+    state: RefCell<State>,
+}
+
+impl TimeSyncCluster {
+    /// Create a new Time Synchronization cluster.
+    pub const fn new(data_ver: Dataver) -> Self {
+        Self {
+            data_ver,
+            admin: Cell::new(None),
+            state: RefCell::new(State {
+                time_zones: heapless::Vec::new(),
+                dst_offsets: heapless::Vec::new(),
+            }),
+        }
+    }
+
+    /// Return the granularity of the UTC time of the node.
+    pub fn granularity(&self) -> Granularity {
+        self.source().0
+    }
+
+    /// Return the current UTC time (Matter epoch, microseconds), or `None` if it is not known.
+    pub fn utc_time(&self) -> Option<u64> {
+        (self.granularity() != Granularity::NoTimeGranularity).then(|| to_matter_epoch_us(epoch()))
+    }
+
+    /// Return the current local time (Matter epoch, microseconds), or `None` if it is not known.
+    pub fn local_time(&self) -> Option<u64> {
+        let state = self.state.borrow();
+
+        local_time(self.utc_time()?, &state.time_zones, &state.dst_offsets)
+    }
+
+    /// Set the UTC time (Matter epoch, microseconds), as per the `SetUTCTime` command.
+    ///
+    /// The time is not accepted - with the `TimeNotAccepted` cluster-specific status - if the node
+    /// already has a time with a finer granularity.
+    pub fn set_utc_time(
+        &self,
+        utc: u64,
+        granularity: Granularity,
+        time_source: Option<u8>,
+    ) -> Result<(), Error> {
+        let time_source = check_utc_time(granularity, time_source, self.granularity())?;
+
+        set_epoch(from_matter_epoch_us(utc), TimeSource::Matter);
+
+        self.admin.set(Some((granularity, time_source)));
+        self.data_ver.changed();
+
+        info!("UTC time set by a controller: {utc}us ({granularity:?})");
+
+        Ok(())
+    }
+
+    /// Set the time zones, as per the `SetTimeZone` command.
+    ///
+    /// As the node has no time zone database, the DST offsets are cleared,
+    /// and need to be provided again with `set_dst_offsets`.
+    pub fn set_time_zones(&self, time_zones: &[TimeZone]) -> Result<(), Error> {
+        validate_time_zones(time_zones)?;
+
+        let mut state = self.state.borrow_mut();
+
+        state.time_zones = heapless::Vec::from_slice(time_zones).unwrap();
+        state.dst_offsets.clear();
+
+        self.data_ver.changed();
+
+        Ok(())
+    }
+
+    /// Set the DST offsets, as per the `SetDSTOffset` command.
+    pub fn set_dst_offsets(&self, dst_offsets: &[DstOffset]) -> Result<(), Error> {
+        validate_dst_offsets(dst_offsets)?;
+
+        self.state.borrow_mut().dst_offsets = heapless::Vec::from_slice(dst_offsets).unwrap();
+
+        self.data_ver.changed();
+
+        Ok(())
+    }
+
+    /// Return the granularity and the `TimeSourceEnum` value of the UTC time of the node.
+    fn source(&self) -> (Granularity, u8) {
+        match sync_status().map(|status| status.source) {
+            Some(TimeSource::Matter) => self
+                .admin
+                .get()
+                .unwrap_or((Granularity::Seconds, time_source::ADMIN)),
+            Some(TimeSource::Sntp) => (Granularity::Milliseconds, time_source::NON_MATTER_SNTP),
+            Some(TimeSource::Manual) | Some(TimeSource::Rtc) => {
+                (Granularity::Seconds, time_source::UNKNOWN)
+            }
+            // The last known time might be arbitrarily behind, so it is not reported
+            Some(TimeSource::LastKnown) | None => {
+                (Granularity::NoTimeGranularity, time_source::NONE)
+            }
+        }
+    }
+
+    fn read(&self, attr: &AttrDetails, encoder: AttrDataEncoder) -> Result<(), Error> {
+        let Some(mut writer) = encoder.with_dataver(self.data_ver.get())? else {
+            return Ok(());
+        };
+
+        if attr.is_system() {
+            return CLUSTER.read(attr.attr_id, writer);
+        }
+
+        let tag = &AttrDataWriter::TAG;
+
+        match attr.attr_id {
+            attributes::UTC_TIME => write_nullable_u64(&mut *writer, tag, self.utc_time())?,
+            attributes::GRANULARITY => writer.u8(tag, self.granularity() as u8)?,
+            attributes::TIME_SOURCE => writer.u8(tag, self.source().1)?,
+            attributes::TIME_ZONE => {
+                writer.start_array(tag)?;
+
+                for tz in &self.state.borrow().time_zones {
+                    TimeZoneStruct {
+                        offset: tz.offset,
+                        valid_at: tz.valid_at,
+                        name: tz.name.as_deref(),
+                    }
+                    .to_tlv(&TLVTag::Anonymous, &mut *writer)?;
+                }
+
+                writer.end_container()?;
+            }
+            attributes::DST_OFFSET => {
+                let now = self.utc_time();
+
+                writer.start_array(tag)?;
+
+                // Expired offsets are not reported
+                for dst in self.state.borrow().dst_offsets.iter().filter(|dst| {
+                    !matches!((dst.valid_until, now), (Some(until), Some(now)) if until <= now)
+                }) {
+                    DstOffsetStruct {
+                        offset: dst.offset,
+                        valid_starting: dst.valid_starting,
+                        valid_until: match dst.valid_until {
+                            Some(until) => Nullable::some(until),
+                            None => Nullable::none(),
+                        },
+                    }
+                    .to_tlv(&TLVTag::Anonymous, &mut *writer)?;
+                }
+
+                writer.end_container()?;
+            }
+            attributes::LOCAL_TIME => write_nullable_u64(&mut *writer, tag, self.local_time())?,
+            attributes::TIME_ZONE_DATABASE => writer.u8(tag, TIME_ZONE_DATABASE_NONE)?,
+            attributes::TIME_ZONE_LIST_MAX_SIZE => writer.u8(tag, TIME_ZONE_LIST_MAX_SIZE as _)?,
+            attributes::DST_OFFSET_LIST_MAX_SIZE => {
+                writer.u8(tag, DST_OFFSET_LIST_MAX_SIZE as _)?
+            }
+            _ => Err(ErrorCode::AttributeNotFound)?,
+        }
+
+        writer.complete()
+    }
+
+    fn invoke(
+        &self,
+        cmd: &CmdDetails,
+        data: &TLVElement,
+        encoder: CmdDataEncoder,
+    ) -> Result<(), Error> {
+        match cmd.cmd_id {
+            commands::SET_UTC_TIME => {
+                let req = SetUtcTimeRequest::from_tlv(data)?;
+
+                self.set_utc_time(
+                    req.utc_time,
+                    Granularity::from_u8(req.granularity)?,
+                    req.time_source,
+                )
+            }
+            commands::SET_TIME_ZONE => {
+                self.handle_set_time_zone(data)?;
+
+                encoder
+                    .with_command(commands::SET_TIME_ZONE_RESPONSE as _)?
+                    .set(SetTimeZoneResponse {
+                        dst_offset_required: true,
+                    })
+            }
+            commands::SET_DST_OFFSET => self.handle_set_dst_offset(data),
+            _ => Err(ErrorCode::CommandNotFound.into()),
+        }
+    }
+
+    /// Handle the request of a `SetTimeZone` command; more entries than the node can hold
+    /// result in `RESOURCE_EXHAUSTED`, as per the spec.
+    fn handle_set_time_zone(&self, data: &TLVElement) -> Result<(), Error> {
+        let req = SetTimeZoneRequest::from_tlv(data)?;
+
+        let mut time_zones = heapless::Vec::<_, TIME_ZONE_LIST_MAX_SIZE>::new();
+
+        for tz in req.time_zone.iter() {
+            let tz = tz?;
+
+            let name = tz
+                .name
+                .map(heapless::String::try_from)
+                .transpose()
+                .map_err(|_| ErrorCode::ConstraintError)?;
+
+            time_zones
+                .push(TimeZone {
+                    offset: tz.offset,
+                    valid_at: tz.valid_at,
+                    name,
+                })
+                .map_err(|_| ErrorCode::ResourceExhausted)?;
+        }
+
+        self.set_time_zones(&time_zones)
+    }
+
+    /// Handle the request of a `SetDSTOffset` command; more entries than the node can hold
+    /// result in `RESOURCE_EXHAUSTED`, as per the spec.
+    fn handle_set_dst_offset(&self, data: &TLVElement) -> Result<(), Error> {
+        let req = SetDstOffsetRequest::from_tlv(data)?;
+
+        let mut dst_offsets = heapless::Vec::<_, DST_OFFSET_LIST_MAX_SIZE>::new();
+
+        for dst in req.dst_offset.iter() {
+            let dst = dst?;
+
+            dst_offsets
+                .push(DstOffset {
+                    offset: dst.offset,
+                    valid_starting: dst.valid_starting,
+                    valid_until: dst.valid_until.into_option(),
+                })
+                .map_err(|_| ErrorCode::ResourceExhausted)?;
+        }
+
+        self.set_dst_offsets(&dst_offsets)
+    }
+}
+
+impl Handler for TimeSyncCluster {
+    fn read(
+        &self,
+        _exchange: &Exchange,
+        attr: &AttrDetails,
+        encoder: AttrDataEncoder,
+    ) -> Result<(), Error> {
+        TimeSyncCluster::read(self, attr, encoder)
+    }
+
+    fn invoke(
+        &self,
+        _exchange: &Exchange,
+        cmd: &CmdDetails,
+        data: &TLVElement,
+        encoder: CmdDataEncoder,
+    ) -> Result<(), Error> {
+        TimeSyncCluster::invoke(self, cmd, data, encoder)
+    }
+}
+
+impl NonBlockingHandler for TimeSyncCluster {}
+
+/// Check whether a time with the provided granularity and `TimeSourceEnum` value is acceptable, given the
+/// granularity of the current time of the node, and return the `TimeSourceEnum` value to be reported
+fn check_utc_time(
+    granularity: Granularity,
+    time_source: Option<u8>,
+    current: Granularity,
+) -> Result<u8, Error> {
+    let time_source = time_source.unwrap_or(time_source::ADMIN);
+
+    if time_source > time_source::MAX {
+        return Err(ErrorCode::ConstraintError.into());
+    }
+
+    if granularity == Granularity::NoTimeGranularity || granularity < current {
+        return Err(Error::new_with_cluster_status(
+            ErrorCode::Failure,
+            status::TIME_NOT_ACCEPTED,
+        ));
+    }
+
+    Ok(time_source)
+}
+
+fn write_nullable_u64<W: TLVWrite>(
+    mut writer: W,
+    tag: &TLVTag,
+    value: Option<u64>,
+) -> Result<(), Error> {
+    match value {
+        Some(value) => writer.u64(tag, value),
+        None => writer.null(tag),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::time::Duration;
+
+    use rs_matter::data_model::objects::Dataver;
+    use rs_matter::error::ErrorCode;
+    use rs_matter::tlv::{Nullable, TLVElement, TLVTag, TLVWrite, ToTLV};
+    use rs_matter::utils::storage::WriteBuf;
+
+    use super::{
+        check_utc_time, from_matter_epoch_us, local_time, time_source, to_matter_epoch_us,
+        validate_dst_offsets, validate_time_zones, DstOffset, DstOffsetStruct, Granularity,
+        TimeSyncCluster, TimeZone, TimeZoneStruct, MICROS,
+    };
+
+    const HOUR: u64 = 60 * 60 * MICROS;
+
+    /// 2024-03-31T01:00:00Z, when DST starts in the EU
+    const DST_START: u64 = (1_711_846_800 - 946_684_800) * MICROS;
+    /// 2024-10-27T01:00:00Z, when DST ends in the EU
+    const DST_END: u64 = (1_729_990_800 - 946_684_800) * MICROS;
+
+    fn sofia() -> TimeZone {
+        TimeZone {
+            offset: 2 * 60 * 60,
+            valid_at: 0,
+            name: Some("Europe/Sofia".try_into().unwrap()),
+        }
+    }
+
+    fn eu_dst() -> [DstOffset; 2] {
+        [
+            DstOffset {
+                offset: 60 * 60,
+                valid_starting: DST_START,
+                valid_until: Some(DST_END),
+            },
+            DstOffset {
+                offset: 0,
+                valid_starting: DST_END,
+                valid_until: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn dst_transitions() {
+        let tz = [sofia()];
+        let dst = eu_dst();
+
+        // Before the first DST offset is valid, the local time is unknown
+        assert_eq!(local_time(DST_START - 1, &tz, &dst), None);
+
+        // Summer time: UTC+3
+        assert_eq!(local_time(DST_START, &tz, &dst), Some(DST_START + 3 * HOUR));
+        assert_eq!(
+            local_time(DST_END - 1, &tz, &dst),
+            Some(DST_END - 1 + 3 * HOUR)
+        );
+
+        // Standard time: UTC+2
+        assert_eq!(local_time(DST_END, &tz, &dst), Some(DST_END + 2 * HOUR));
+        assert_eq!(
+            local_time(DST_END + 1000 * HOUR, &tz, &dst),
+            Some(DST_END + 1002 * HOUR)
+        );
+
+        // No time zone or no DST offsets
+        assert_eq!(local_time(DST_START, &[], &dst), None);
+        assert_eq!(local_time(DST_START, &tz, &[]), None);
+    }
+
+    #[test]
+    fn time_zone_transitions() {
+        // Moving from UTC+2 to UTC-5 (i.e. the device is shipped to New York) at `DST_END`
+        let tz = [
+            sofia(),
+            TimeZone {
+                offset: -5 * 60 * 60,
+                valid_at: DST_END,
+                name: None,
+            },
+        ];
+        let dst = [DstOffset {
+            offset: 0,
+            valid_starting: 0,
+            valid_until: None,
+        }];
+
+        assert_eq!(local_time(DST_END - HOUR, &tz, &dst), Some(DST_END + HOUR));
+        assert_eq!(local_time(DST_END, &tz, &dst), Some(DST_END - 5 * HOUR));
+
+        // Negative offsets cannot result in a local time before the Matter epoch
+        let tz = [TimeZone {
+            offset: -5 * 60 * 60,
+            valid_at: 0,
+            name: None,
+        }];
+        assert_eq!(local_time(HOUR, &tz, &dst), None);
+    }
+
+    #[test]
+    fn validation() {
+        // At least one time zone is required
+        assert!(validate_time_zones(&[]).is_err());
+        assert!(validate_time_zones(&[sofia()]).is_ok());
+
+        // The first time zone should be valid from the beginning
+        let mut invalid = sofia();
+        invalid.valid_at = 1;
+        assert!(validate_time_zones(&[invalid]).is_err());
+
+        // Offset out of range
+        let mut invalid = sofia();
+        invalid.offset = 15 * 60 * 60;
+        assert!(validate_time_zones(&[invalid]).is_err());
+
+        // Unsorted or too many
+        assert!(validate_time_zones(&[sofia(), sofia()]).is_err());
+        assert!(validate_time_zones(&[sofia(), sofia(), sofia()]).is_err());
+
+        assert!(validate_dst_offsets(&[]).is_ok());
+        assert!(validate_dst_offsets(&eu_dst()).is_ok());
+
+        // Overlapping
+        let mut invalid = eu_dst();
+        invalid[1].valid_starting = DST_END - 1;
+        assert!(validate_dst_offsets(&invalid).is_err());
+
+        // Only the last offset can be valid forever
+        let mut invalid = eu_dst();
+        invalid[0].valid_until = None;
+        assert!(validate_dst_offsets(&invalid).is_err());
+
+        // Empty validity range
+        let mut invalid = eu_dst();
+        invalid[0].valid_until = Some(DST_START);
+        assert!(validate_dst_offsets(&invalid).is_err());
+    }
+
+    #[test]
+    fn granularity() {
+        // Nothing known yet: any granularity but `NoTimeGranularity` is accepted
+        assert_eq!(
+            check_utc_time(Granularity::Minutes, None, Granularity::NoTimeGranularity).unwrap(),
+            time_source::ADMIN
+        );
+        assert!(check_utc_time(
+            Granularity::NoTimeGranularity,
+            None,
+            Granularity::NoTimeGranularity
+        )
+        .is_err());
+
+        // Same or finer granularity than the current time
+        assert!(check_utc_time(Granularity::Seconds, None, Granularity::Seconds).is_ok());
+        assert!(check_utc_time(Granularity::Microseconds, None, Granularity::Seconds).is_ok());
+
+        // Coarser granularity than the current time
+        assert!(check_utc_time(Granularity::Minutes, None, Granularity::Seconds).is_err());
+        assert!(check_utc_time(Granularity::Seconds, None, Granularity::Milliseconds).is_err());
+
+        // The time source is reported as provided, and should be a valid `TimeSourceEnum`
+        assert_eq!(
+            check_utc_time(
+                Granularity::Seconds,
+                Some(time_source::NON_MATTER_SNTP),
+                Granularity::Minutes
+            )
+            .unwrap(),
+            time_source::NON_MATTER_SNTP
+        );
+        assert!(check_utc_time(
+            Granularity::Seconds,
+            Some(time_source::MAX + 1),
+            Granularity::Minutes
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn time_zone_clears_dst() {
+        let cluster = TimeSyncCluster::new(Dataver::new(0));
+
+        cluster.set_time_zones(&[sofia()]).unwrap();
+        cluster.set_dst_offsets(&eu_dst()).unwrap();
+        assert_eq!(cluster.state.borrow().dst_offsets.len(), 2);
+
+        // A rejected time zone list leaves the DST offsets alone
+        assert!(cluster.set_time_zones(&[]).is_err());
+        assert_eq!(cluster.state.borrow().dst_offsets.len(), 2);
+
+        // Without a time zone database, the DST offsets of the previous time zone do not apply anymore
+        let mut new_york = sofia();
+        new_york.offset = -5 * 60 * 60;
+        new_york.name = Some("America/New_York".try_into().unwrap());
+
+        cluster.set_time_zones(&[new_york.clone()]).unwrap();
+        assert!(cluster.state.borrow().dst_offsets.is_empty());
+        assert_eq!(cluster.state.borrow().time_zones.as_slice(), &[new_york]);
+    }
+
+    /// Encode a `SetTimeZone` / `SetDSTOffset` request with `items` as its list (field 0)
+    fn request<'a, T: ToTLV>(buf: &'a mut [u8], items: &[T]) -> &'a [u8] {
+        let mut wb = WriteBuf::new(buf);
+
+        wb.start_struct(&TLVTag::Anonymous).unwrap();
+        wb.start_array(&TLVTag::Context(0)).unwrap();
+
+        for item in items {
+            item.to_tlv(&TLVTag::Anonymous, &mut wb).unwrap();
+        }
+
+        wb.end_container().unwrap();
+        wb.end_container().unwrap();
+
+        let len = wb.as_slice().len();
+
+        &buf[..len]
+    }
+
+    #[test]
+    fn too_many_entries() {
+        let cluster = TimeSyncCluster::new(Dataver::new(0));
+
+        cluster.set_time_zones(&[sofia()]).unwrap();
+
+        let time_zone = |valid_at| TimeZoneStruct {
+            offset: 0,
+            valid_at,
+            name: None,
+        };
+
+        let mut buf = [0; 256];
+        let data = request(
+            &mut buf,
+            &[time_zone(0), time_zone(HOUR), time_zone(2 * HOUR)],
+        );
+
+        let err = cluster
+            .handle_set_time_zone(&TLVElement::new(data))
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::ResourceExhausted);
+        assert_eq!(cluster.state.borrow().time_zones.as_slice(), &[sofia()]);
+
+        // ... while two entries fit
+        let data = request(&mut buf, &[time_zone(0), time_zone(HOUR)]);
+        cluster
+            .handle_set_time_zone(&TLVElement::new(data))
+            .unwrap();
+        assert_eq!(cluster.state.borrow().time_zones.len(), 2);
+
+        let dst_offset = |valid_starting| DstOffsetStruct {
+            offset: 0,
+            valid_starting,
+            valid_until: Nullable::some(valid_starting + HOUR),
+        };
+
+        let data = request(
+            &mut buf,
+            &[dst_offset(0), dst_offset(HOUR), dst_offset(2 * HOUR)],
+        );
+
+        let err = cluster
+            .handle_set_dst_offset(&TLVElement::new(data))
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::ResourceExhausted);
+        assert!(cluster.state.borrow().dst_offsets.is_empty());
+    }
+
+    #[test]
+    fn matter_epoch() {
+        // 2000-01-01T00:00:00Z
+        assert_eq!(to_matter_epoch_us(Duration::from_secs(946_684_800)), 0);
+        assert_eq!(
+            from_matter_epoch_us(DST_START),
+            Duration::from_secs(1_711_846_800)
+        );
+        assert_eq!(
+            to_matter_epoch_us(from_matter_epoch_us(DST_END + 1)),
+            DST_END + 1
+        );
+    }
+}
@@ -1,10 +1,118 @@
+//! Error: conversion of `sequential_storage` errors to `rs-matter` errors
+//!
+//! The `rs-matter` error codes are too generic to tell apart e.g. a full flash partition from a corrupted one,
+//! so the cause of the last persistence error is additionally reported as a `PersistErrorKind`, both via
+//! `last_persist_error` and via an optional, user-installed hook that also receives the original error.
+
+use core::cell::Cell;
+use core::fmt::Debug;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+use log::error;
+
 use rs_matter_stack::matter::error::{Error, ErrorCode};
 
 use sequential_storage::Error as SError;
 
+/// The cause of a persistence error
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum PersistErrorKind {
+    /// The flash range is full, and no more space could be reclaimed
+    StorageFull,
+    /// The flash range contains corrupted data which could not be repaired
+    Corrupted,
+    /// The buffer provided for the operation is too small (or too big) for the flash range
+    BufferTooSmall,
+    /// The item is too big to fit in a single flash page
+    ItemTooBig,
+    /// The item could not be serialized or deserialized
+    Serialization,
+    /// The flash driver reported an I/O error; the original error is passed to the hook
+    Flash,
+    /// Any other error
+    Other,
+}
+
+impl PersistErrorKind {
+    /// Classify a `sequential_storage` error
+    pub fn of<E>(err: &SError<E>) -> Self {
+        match err {
+            SError::Storage { .. } => Self::Flash,
+            SError::FullStorage => Self::StorageFull,
+            SError::Corrupted { .. } => Self::Corrupted,
+            SError::BufferTooBig | SError::BufferTooSmall(_) => Self::BufferTooSmall,
+            SError::ItemTooBig => Self::ItemTooBig,
+            SError::SerializationError(_) => Self::Serialization,
+            _ => Self::Other,
+        }
+    }
+
+    /// Return the `rs-matter` error code corresponding to this error kind
+    pub const fn code(&self) -> ErrorCode {
+        match self {
+            Self::StorageFull | Self::ItemTooBig => ErrorCode::NoSpace,
+            Self::Corrupted | Self::Serialization => ErrorCode::InvalidData,
+            Self::BufferTooSmall => ErrorCode::BufferTooSmall,
+            Self::Flash | Self::Other => ErrorCode::StdIoError,
+        }
+    }
+}
+
+/// A hook called for every persistence error, with the cause and the original error
+/// (i.e. the `sequential_storage::Error` wrapping the flash driver error).
+///
+/// Called from within the persistence operation which failed, so it should not block.
+pub type PersistErrorHook = fn(PersistErrorKind, &dyn Debug);
+
+static HOOK: Mutex<CriticalSectionRawMutex, Cell<Option<PersistErrorHook>>> =
+    Mutex::new(Cell::new(None));
+
+static LAST: Mutex<CriticalSectionRawMutex, Cell<Option<PersistErrorKind>>> =
+    Mutex::new(Cell::new(None));
+
+/// Install (or - with `None` - uninstall) a hook that is called on every persistence error.
+///
+/// Useful for e.g. reporting the error to a diagnostics cluster, or for triggering a
+/// factory reset when the flash range is found to be full or corrupted.
+pub fn set_persist_error_hook(hook: Option<PersistErrorHook>) {
+    HOOK.lock(|h| h.set(hook));
+}
+
+/// Return - and clear - the cause of the last persistence error, if any.
+pub fn take_last_persist_error() -> Option<PersistErrorKind> {
+    LAST.lock(|last| last.take())
+}
+
 /// Converts a `sequential_storage` error to an `rs-matter` error
-pub fn to_persist_error<E>(_err: SError<E>) -> Error {
-    // TODO: The `rs-matter` error code is too generic
-    // TODO: Capture the backtrace and the original error
-    ErrorCode::StdIoError.into()
+///
+/// The original error is logged, recorded for `take_last_persist_error`
+/// and passed to the persistence error hook, if one is installed.
+pub fn to_persist_error<E>(err: SError<E>) -> Error
+where
+    E: Debug,
+{
+    report(PersistErrorKind::of(&err), &err)
+}
+
+/// Converts an error of the flash driver itself (i.e. of a direct `NorFlash::read`) to an `rs-matter` error
+///
+/// The error is reported as `PersistErrorKind::Flash`, the same way as `to_persist_error` does.
+pub fn to_flash_error<E>(err: E) -> Error
+where
+    E: Debug,
+{
+    report(PersistErrorKind::Flash, &err)
+}
+
+fn report(kind: PersistErrorKind, err: &dyn Debug) -> Error {
+    error!("Persistence error {kind:?}: {err:?}");
+
+    LAST.lock(|last| last.set(Some(kind)));
+
+    if let Some(hook) = HOOK.lock(|h| h.get()) {
+        hook(kind, err);
+    }
+
+    kind.code().into()
 }
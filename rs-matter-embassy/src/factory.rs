@@ -0,0 +1,499 @@
+//! Factory data: `FactoryData` - per-unit device attestation credentials and commissioning data,
+//! read from a dedicated (read-only) NOR flash range
+//!
+//! The factory data is written once, during production provisioning, and contains the
+//! Device Attestation Certificate (DAC) and its key pair, the Product Attestation Intermediate (PAI) certificate,
+//! the Certification Declaration (CD), as well as the VID/PID, the serial number, the discriminator and the passcode
+//! of the unit.
+//!
+//! # Format
+//!
+//! All integers are little-endian.
+//!
+//! | Offset   | Size | Content                                                         |
+//! |----------|------|-----------------------------------------------------------------|
+//! | 0        | 4    | Magic: `MTFD`                                                   |
+//! | 4        | 1    | Format version: `1`                                             |
+//! | 5        | 3    | Reserved: `0`                                                   |
+//! | 8        | 4    | `len`: length of the entries                                    |
+//! | 12       | len  | Entries                                                         |
+//! | 12 + len | 4    | CRC-32 (IEEE) of everything above                               |
+//!
+//! Each entry is a tag (`u8`, see `FactoryTag`), followed by the length of the value (`u16`), followed by the value.
+//! Entries with unknown tags are skipped, so that newer images can be read by older firmware.
+
+use embedded_storage_async::nor_flash::ReadNorFlash;
+
+use rs_matter_stack::matter::data_model::sdm::dev_att::{DataType, DevAttDataFetcher};
+use rs_matter_stack::matter::error::{Error, ErrorCode};
+use rs_matter_stack::matter::BasicCommData;
+
+use crate::crc::crc32;
+use crate::error::to_flash_error;
+
+/// The magic at the start of a factory data image
+pub const MAGIC: [u8; 4] = *b"MTFD";
+
+/// The version of the factory data format
+pub const VERSION: u8 = 1;
+
+/// The length of the factory data header
+pub const HEADER_LEN: usize = 12;
+
+/// The length of the factory data trailer (the CRC)
+pub const TRAILER_LEN: usize = 4;
+
+/// Passcodes which are not allowed by the Matter spec, as they are trivial to guess
+const INVALID_PASSCODES: &[u32] = &[
+    0, 11111111, 22222222, 33333333, 44444444, 55555555, 66666666, 77777777, 88888888, 99999999,
+    12345678, 87654321,
+];
+
+/// Return `true` if `passcode` is a valid Matter setup passcode.
+pub fn is_valid_passcode(passcode: u32) -> bool {
+    passcode <= 99999998 && !INVALID_PASSCODES.contains(&passcode)
+}
+
+/// The tags of the factory data entries.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[repr(u8)]
+pub enum FactoryTag {
+    /// Vendor ID (`u16`)
+    Vid = 1,
+    /// Product ID (`u16`)
+    Pid = 2,
+    /// Serial number (UTF-8)
+    SerialNo = 3,
+    /// Discriminator (`u16`, 12 bits)
+    Discriminator = 4,
+    /// Setup passcode (`u32`, 27 bits)
+    Passcode = 5,
+    /// Device Attestation Certificate (DER)
+    Dac = 6,
+    /// DAC public key (uncompressed SEC1 point)
+    DacPubKey = 7,
+    /// DAC private key (raw scalar)
+    DacPrivKey = 8,
+    /// Product Attestation Intermediate certificate (DER)
+    Pai = 9,
+    /// Certification Declaration
+    CertDeclaration = 10,
+}
+
+impl FactoryTag {
+    /// All tags which must be present in a factory data image
+    pub const ALL: &'static [FactoryTag] = &[
+        Self::Vid,
+        Self::Pid,
+        Self::SerialNo,
+        Self::Discriminator,
+        Self::Passcode,
+        Self::Dac,
+        Self::DacPubKey,
+        Self::DacPrivKey,
+        Self::Pai,
+        Self::CertDeclaration,
+    ];
+}
+
+/// Parsed factory data.
+///
+/// Implements `DevAttDataFetcher`, so it can be passed to the Matter stack instead of `TEST_DEV_ATT`.
+/// `comm_data` can be passed instead of `TEST_BASIC_COMM_DATA`.
+#[derive(Debug, Clone)]
+pub struct FactoryData<'a> {
+    entries: &'a [u8],
+}
+
+impl<'a> FactoryData<'a> {
+    /// Load and parse the factory data image stored at `offset` in `flash`.
+    ///
+    /// `buf` should be big enough to hold the whole image, which should fit in the flash.
+    /// As the Matter stack needs a `&'static dyn DevAttDataFetcher`, `buf` would usually be `'static` too
+    /// (i.e. allocated with `StaticCell`).
+    pub async fn load<S>(flash: &mut S, offset: u32, buf: &'a mut [u8]) -> Result<Self, Error>
+    where
+        S: ReadNorFlash,
+    {
+        let header_len = align_up(HEADER_LEN, S::READ_SIZE);
+        if buf.len() < header_len {
+            return Err(ErrorCode::BufferTooSmall.into());
+        }
+
+        flash
+            .read(offset, &mut buf[..header_len])
+            .await
+            .map_err(to_flash_error)?;
+
+        // The length comes from the flash, so it might be corrupted
+        let len = Self::parse_header(&buf[..HEADER_LEN])?
+            .checked_add(HEADER_LEN + TRAILER_LEN)
+            .filter(|len| {
+                (offset as usize)
+                    .checked_add(*len)
+                    .is_some_and(|end| end <= flash.capacity())
+            })
+            .ok_or(ErrorCode::InvalidData)?;
+
+        let read_len = align_up(len, S::READ_SIZE);
+        if buf.len() < read_len {
+            return Err(ErrorCode::BufferTooSmall.into());
+        }
+
+        flash
+            .read(offset, &mut buf[..read_len])
+            .await
+            .map_err(to_flash_error)?;
+
+        Self::parse(&buf[..len])
+    }
+
+    /// Parse a factory data image.
+    ///
+    /// Fails if the image is malformed, if its CRC does not match, or if any of the `FactoryTag::ALL` tags is missing.
+    pub fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        let len = Self::parse_header(data.get(..HEADER_LEN).ok_or(ErrorCode::InvalidData)?)?;
+
+        if len.checked_add(HEADER_LEN + TRAILER_LEN) != Some(data.len()) {
+            return Err(ErrorCode::InvalidData.into());
+        }
+
+        let (image, crc) = data.split_at(HEADER_LEN + len);
+        if crc32(image).to_le_bytes() != crc {
+            return Err(ErrorCode::InvalidData.into());
+        }
+
+        let this = Self {
+            entries: &image[HEADER_LEN..],
+        };
+
+        // Validate the framing of the entries
+        let mut entries = this.entries;
+        while !entries.is_empty() {
+            entries = next_entry(entries)?.2;
+        }
+
+        for tag in FactoryTag::ALL {
+            this.get(*tag).ok_or(ErrorCode::InvalidData)?;
+        }
+
+        if this.discriminator()? > 0xfff
+            || !is_valid_passcode(this.passcode()?)
+            || core::str::from_utf8(this.serial_no_bytes()).is_err()
+        {
+            return Err(ErrorCode::InvalidData.into());
+        }
+
+        Ok(this)
+    }
+
+    /// Return the value of the entry with the provided tag, if present.
+    pub fn get(&self, tag: FactoryTag) -> Option<&'a [u8]> {
+        let mut entries = self.entries;
+
+        while let Ok((entry_tag, value, rest)) = next_entry(entries) {
+            if entry_tag == tag as u8 {
+                return Some(value);
+            }
+
+            entries = rest;
+        }
+
+        None
+    }
+
+    /// Return the Vendor ID.
+    pub fn vid(&self) -> Result<u16, Error> {
+        self.get_u16(FactoryTag::Vid)
+    }
+
+    /// Return the Product ID.
+    pub fn pid(&self) -> Result<u16, Error> {
+        self.get_u16(FactoryTag::Pid)
+    }
+
+    /// Return the serial number.
+    pub fn serial_no(&self) -> &'a str {
+        // Validated in `parse`
+        core::str::from_utf8(self.serial_no_bytes()).unwrap()
+    }
+
+    /// Return the discriminator.
+    pub fn discriminator(&self) -> Result<u16, Error> {
+        self.get_u16(FactoryTag::Discriminator)
+    }
+
+    /// Return the setup passcode.
+    pub fn passcode(&self) -> Result<u32, Error> {
+        let value = self.get(FactoryTag::Passcode).ok_or(ErrorCode::NotFound)?;
+
+        Ok(u32::from_le_bytes(
+            value.try_into().map_err(|_| ErrorCode::InvalidData)?,
+        ))
+    }
+
+    /// Return the commissioning data (discriminator and passcode) of the unit.
+    pub fn comm_data(&self) -> Result<BasicCommData, Error> {
+        Ok(BasicCommData {
+            password: self.passcode()?,
+            discriminator: self.discriminator()?,
+        })
+    }
+
+    fn serial_no_bytes(&self) -> &'a [u8] {
+        self.get(FactoryTag::SerialNo).unwrap_or(&[])
+    }
+
+    fn get_u16(&self, tag: FactoryTag) -> Result<u16, Error> {
+        let value = self.get(tag).ok_or(ErrorCode::NotFound)?;
+
+        Ok(u16::from_le_bytes(
+            value.try_into().map_err(|_| ErrorCode::InvalidData)?,
+        ))
+    }
+
+    fn parse_header(header: &[u8]) -> Result<usize, Error> {
+        if header[..4] != MAGIC || header[4] != VERSION {
+            return Err(ErrorCode::InvalidData.into());
+        }
+
+        Ok(u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as usize)
+    }
+}
+
+impl DevAttDataFetcher for FactoryData<'_> {
+    fn get_devatt_data(&self, data_type: DataType, data: &mut [u8]) -> Result<usize, Error> {
+        let tag = match data_type {
+            DataType::CertDeclaration => FactoryTag::CertDeclaration,
+            DataType::PAI => FactoryTag::Pai,
+            DataType::DAC => FactoryTag::Dac,
+            DataType::DACPubKey => FactoryTag::DacPubKey,
+            DataType::DACPrivKey => FactoryTag::DacPrivKey,
+        };
+
+        let value = self.get(tag).ok_or(ErrorCode::NotFound)?;
+
+        data.get_mut(..value.len())
+            .ok_or(ErrorCode::NoSpace)?
+            .copy_from_slice(value);
+
+        Ok(value.len())
+    }
+}
+
+/// A writer of factory data images, in the format read by `FactoryData`.
+pub struct FactoryDataWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> FactoryDataWriter<'a> {
+    /// Create a new writer, which writes the image into `buf`.
+    pub fn new(buf: &'a mut [u8]) -> Result<Self, Error> {
+        let header = buf.get_mut(..HEADER_LEN).ok_or(ErrorCode::NoSpace)?;
+
+        header.fill(0);
+        header[..4].copy_from_slice(&MAGIC);
+        header[4] = VERSION;
+
+        Ok(Self {
+            buf,
+            len: HEADER_LEN,
+        })
+    }
+
+    /// Add an entry to the image.
+    pub fn add(&mut self, tag: FactoryTag, value: &[u8]) -> Result<&mut Self, Error> {
+        self.add_raw(tag as u8, value)
+    }
+
+    /// Add an entry with an arbitrary tag to the image.
+    pub fn add_raw(&mut self, tag: u8, value: &[u8]) -> Result<&mut Self, Error> {
+        let value_len: u16 = value.len().try_into().map_err(|_| ErrorCode::NoSpace)?;
+
+        let entry = self
+            .buf
+            .get_mut(self.len..self.len + 3 + value.len())
+            .ok_or(ErrorCode::NoSpace)?;
+
+        entry[0] = tag;
+        entry[1..3].copy_from_slice(&value_len.to_le_bytes());
+        entry[3..].copy_from_slice(value);
+
+        self.len += entry.len();
+
+        Ok(self)
+    }
+
+    /// Finish the image by writing its length and CRC, and return the length of the image.
+    pub fn finish(self) -> Result<usize, Error> {
+        let entries_len = (self.len - HEADER_LEN) as u32;
+        self.buf[8..HEADER_LEN].copy_from_slice(&entries_len.to_le_bytes());
+
+        let crc = crc32(&self.buf[..self.len]);
+
+        self.buf
+            .get_mut(self.len..self.len + TRAILER_LEN)
+            .ok_or(ErrorCode::NoSpace)?
+            .copy_from_slice(&crc.to_le_bytes());
+
+        Ok(self.len + TRAILER_LEN)
+    }
+}
+
+fn next_entry(entries: &[u8]) -> Result<(u8, &[u8], &[u8]), Error> {
+    if entries.len() < 3 {
+        return Err(ErrorCode::InvalidData.into());
+    }
+
+    let len = u16::from_le_bytes([entries[1], entries[2]]) as usize;
+    let rest = &entries[3..];
+    if rest.len() < len {
+        return Err(ErrorCode::InvalidData.into());
+    }
+
+    Ok((entries[0], &rest[..len], &rest[len..]))
+}
+
+const fn align_up(len: usize, align: usize) -> usize {
+    len.div_ceil(align) * align
+}
+
+#[cfg(test)]
+mod test {
+    use embassy_futures::block_on;
+    use embedded_storage_async::nor_flash::NorFlash;
+
+    use rs_matter_stack::matter::data_model::sdm::dev_att::{DataType, DevAttDataFetcher};
+
+    use crate::flash::RamFlash;
+
+    use super::{is_valid_passcode, FactoryData, FactoryDataWriter, FactoryTag};
+
+    fn image(buf: &mut [u8]) -> usize {
+        image_with_passcode(buf, 20202021)
+    }
+
+    fn image_with_passcode(buf: &mut [u8], passcode: u32) -> usize {
+        let mut writer = FactoryDataWriter::new(buf).unwrap();
+
+        writer
+            .add(FactoryTag::Vid, &0xfff1_u16.to_le_bytes())
+            .unwrap()
+            .add(FactoryTag::Pid, &0x8000_u16.to_le_bytes())
+            .unwrap()
+            .add(FactoryTag::SerialNo, b"SN-0001")
+            .unwrap()
+            // An unknown tag, which should be skipped
+            .add_raw(0xee, &[1, 2, 3])
+            .unwrap()
+            .add(FactoryTag::Discriminator, &3840_u16.to_le_bytes())
+            .unwrap()
+            .add(FactoryTag::Passcode, &passcode.to_le_bytes())
+            .unwrap()
+            .add(FactoryTag::Dac, &[0xda; 400])
+            .unwrap()
+            .add(FactoryTag::DacPubKey, &[0x04; 65])
+            .unwrap()
+            .add(FactoryTag::DacPrivKey, &[0x55; 32])
+            .unwrap()
+            .add(FactoryTag::Pai, &[0xba; 450])
+            .unwrap()
+            .add(FactoryTag::CertDeclaration, &[0xcd; 240])
+            .unwrap();
+
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let mut buf = [0; 2048];
+        let len = image(&mut buf);
+
+        let data = FactoryData::parse(&buf[..len]).unwrap();
+
+        assert_eq!(data.vid().unwrap(), 0xfff1);
+        assert_eq!(data.pid().unwrap(), 0x8000);
+        assert_eq!(data.serial_no(), "SN-0001");
+
+        let comm_data = data.comm_data().unwrap();
+        assert_eq!(comm_data.discriminator, 3840);
+        assert_eq!(comm_data.password, 20202021);
+
+        let mut out = [0; 512];
+        for (data_type, expected) in [
+            (DataType::CertDeclaration, [0xcd; 240].as_slice()),
+            (DataType::PAI, [0xba; 450].as_slice()),
+            (DataType::DAC, [0xda; 400].as_slice()),
+            (DataType::DACPubKey, [0x04; 65].as_slice()),
+            (DataType::DACPrivKey, [0x55; 32].as_slice()),
+        ] {
+            let len = data.get_devatt_data(data_type, &mut out).unwrap();
+            assert_eq!(&out[..len], expected);
+        }
+
+        assert!(data.get_devatt_data(DataType::DAC, &mut out[..10]).is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        let mut buf = [0; 2048];
+        let len = image(&mut buf);
+
+        // Truncated
+        assert!(FactoryData::parse(&buf[..len - 1]).is_err());
+
+        // Corrupted
+        for offset in [0, 4, 8, 20, len - 1] {
+            let mut corrupted = buf;
+            corrupted[offset] ^= 1;
+            assert!(FactoryData::parse(&corrupted[..len]).is_err());
+        }
+
+        // Trivial passcodes
+        for passcode in [0, 11111111, 12345678, 87654321, 99999999, 100_000_000] {
+            let mut buf = [0; 2048];
+            let len = image_with_passcode(&mut buf, passcode);
+            assert!(!is_valid_passcode(passcode));
+            assert!(FactoryData::parse(&buf[..len]).is_err());
+        }
+
+        // Missing entries
+        let mut buf = [0; 2048];
+        let mut writer = FactoryDataWriter::new(&mut buf).unwrap();
+        writer
+            .add(FactoryTag::Vid, &0xfff1_u16.to_le_bytes())
+            .unwrap();
+        let len = writer.finish().unwrap();
+        assert!(FactoryData::parse(&buf[..len]).is_err());
+    }
+
+    #[test]
+    fn load() {
+        let mut image_buf = [0; 2048];
+        let len = image(&mut image_buf);
+
+        let mut flash = RamFlash::<8192>::new();
+
+        let mut buf = [0; 2048];
+
+        block_on(async {
+            // Pad to the flash write size
+            let padded = len.div_ceil(4) * 4;
+            flash.write(4096, &image_buf[..padded]).await.unwrap();
+
+            let data = FactoryData::load(&mut flash, 4096, &mut buf).await.unwrap();
+            assert_eq!(data.serial_no(), "SN-0001");
+
+            // Empty (erased) flash
+            assert!(FactoryData::load(&mut flash, 0, &mut buf).await.is_err());
+
+            // A corrupted length, pointing beyond the end of the flash
+            let mut corrupted = image_buf;
+            corrupted[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+            flash.erase(0, 4096).await.unwrap();
+            flash.write(0, &corrupted[..padded]).await.unwrap();
+            assert!(FactoryData::load(&mut flash, 0, &mut buf).await.is_err());
+        });
+    }
+}
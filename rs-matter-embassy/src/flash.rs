@@ -0,0 +1,550 @@
+//! Flash: `RamFlash` - an in-memory `MultiwriteNorFlash` implementation,
+//! and `WearFlash` - a wrapper counting the erases of each flash page
+//!
+//! `RamFlash` is useful for testing the persistence layer (i.e. `EmbassyKvBlobStore`) on the host, as well as
+//! on MCUs which do not (yet) have a NOR Flash driver.
+
+use embedded_storage_async::nor_flash::{
+    ErrorType, MultiwriteNorFlash, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+/// The errors returned by `RamFlash`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum RamFlashError {
+    /// The offset or the length of the operation is not aligned to the read/write/erase size
+    NotAligned,
+    /// The operation is out of the bounds of the flash
+    OutOfBounds,
+    /// The operation failed because a failure was injected with `RamFlash::fail_after`
+    Injected,
+    /// The operation failed because of a simulated power loss, see `RamFlash::cut_power_after`
+    PowerLoss,
+}
+
+impl NorFlashError for RamFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Self::NotAligned => NorFlashErrorKind::NotAligned,
+            Self::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            Self::Injected | Self::PowerLoss => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// The type of a `RamFlash` operation
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum RamFlashOp {
+    Read,
+    Write,
+    Erase,
+}
+
+/// Operation counters of a `RamFlash` instance
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct RamFlashStats {
+    /// Number of read operations
+    pub reads: usize,
+    /// Number of bytes read
+    pub read_bytes: usize,
+    /// Number of write operations
+    pub writes: usize,
+    /// Number of bytes written
+    pub written_bytes: usize,
+    /// Number of erased pages
+    pub erases: usize,
+}
+
+/// An in-memory NOR Flash of `N` bytes, with a page (erase) size of `PAGE_SIZE` bytes
+/// and a write granularity of `WORD_SIZE` bytes.
+///
+/// Follows the semantics of NOR Flash:
+/// - Erasing sets all bits of the erased pages to 1
+/// - Writing can only flip bits from 1 to 0, i.e. the result of a write is the logical AND
+///   of the previous data and the written data (hence `MultiwriteNorFlash`)
+pub struct RamFlash<const N: usize, const PAGE_SIZE: usize = 4096, const WORD_SIZE: usize = 4> {
+    data: [u8; N],
+    stats: RamFlashStats,
+    failure: Option<(RamFlashOp, usize)>,
+    power: Power,
+}
+
+/// The power supply state of a `RamFlash` instance
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Power {
+    /// Powered on
+    On,
+    /// Powered on, but the power would be lost after the given number of written words or erased pages
+    CutAfter(usize),
+    /// Powered off - during an operation of the given type; all operations fail
+    Off(RamFlashOp),
+}
+
+impl<const N: usize, const PAGE_SIZE: usize, const WORD_SIZE: usize>
+    RamFlash<N, PAGE_SIZE, WORD_SIZE>
+{
+    /// Create a new, fully erased instance.
+    pub const fn new() -> Self {
+        assert!(PAGE_SIZE > 0 && N % PAGE_SIZE == 0);
+        assert!(WORD_SIZE > 0 && PAGE_SIZE % WORD_SIZE == 0);
+
+        Self {
+            data: [0xff; N],
+            stats: RamFlashStats {
+                reads: 0,
+                read_bytes: 0,
+                writes: 0,
+                written_bytes: 0,
+                erases: 0,
+            },
+            failure: None,
+            power: Power::On,
+        }
+    }
+
+    /// Return the raw content of the flash.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Return the raw content of the flash for modification,
+    /// i.e. for simulating bit rot.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    /// Return the operation counters.
+    pub fn stats(&self) -> &RamFlashStats {
+        &self.stats
+    }
+
+    /// Reset the operation counters.
+    pub fn reset_stats(&mut self) {
+        self.stats = Default::default();
+    }
+
+    /// Inject a failure: after `count` more successful operations of type `op`,
+    /// all subsequent operations of that type would fail with `RamFlashError::Injected`,
+    /// until `clear_failure` is called.
+    pub fn fail_after(&mut self, op: RamFlashOp, count: usize) {
+        self.failure = Some((op, count));
+    }
+
+    /// Clear a failure injected with `fail_after`.
+    pub fn clear_failure(&mut self) {
+        self.failure = None;
+    }
+
+    /// Simulate a power loss (i.e. a brown-out) after `steps` more steps, where a step is
+    /// either the write of a word, or the erase of a page.
+    ///
+    /// The operation during which the power is lost is interrupted:
+    /// - For a write, the words preceding the cut point are written, the word at the cut point is left
+    ///   half-written (as the content of a word whose write is interrupted is undefined)
+    ///   and the rest of the words are not written at all
+    /// - For an erase, the pages preceding the cut point are erased, the page at the cut point is left
+    ///   half-erased and the rest of the pages are not erased at all
+    ///
+    /// Once the power is lost, all operations fail with `RamFlashError::PowerLoss`
+    /// until `restore_power` is called.
+    pub fn cut_power_after(&mut self, steps: usize) {
+        self.power = Power::CutAfter(steps);
+    }
+
+    /// Restore the power after a power loss, or cancel a pending power cut.
+    ///
+    /// The content of the flash is retained, hence this simulates a reboot.
+    pub fn restore_power(&mut self) {
+        self.power = Power::On;
+    }
+
+    /// Return `true` if the power was lost due to `cut_power_after`.
+    pub fn is_power_lost(&self) -> bool {
+        self.power_lost_during().is_some()
+    }
+
+    /// Return the type of the operation interrupted by the power loss, if the power was lost
+    /// due to `cut_power_after`.
+    pub fn power_lost_during(&self) -> Option<RamFlashOp> {
+        match self.power {
+            Power::Off(op) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn check(&mut self, op: RamFlashOp, offset: u32, len: usize) -> Result<(), RamFlashError> {
+        let align = match op {
+            RamFlashOp::Read => 1,
+            RamFlashOp::Write => WORD_SIZE,
+            RamFlashOp::Erase => PAGE_SIZE,
+        };
+
+        if self.is_power_lost() {
+            return Err(RamFlashError::PowerLoss);
+        }
+
+        let offset = offset as usize;
+
+        if offset % align != 0 || len % align != 0 {
+            return Err(RamFlashError::NotAligned);
+        }
+
+        if offset.checked_add(len).map(|end| end > N).unwrap_or(true) {
+            return Err(RamFlashError::OutOfBounds);
+        }
+
+        if let Some((failure_op, count)) = self.failure.as_mut() {
+            if *failure_op == op {
+                if *count == 0 {
+                    return Err(RamFlashError::Injected);
+                }
+
+                *count -= 1;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<const N: usize, const PAGE_SIZE: usize, const WORD_SIZE: usize> Default
+    for RamFlash<N, PAGE_SIZE, WORD_SIZE>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, const PAGE_SIZE: usize, const WORD_SIZE: usize> ErrorType
+    for RamFlash<N, PAGE_SIZE, WORD_SIZE>
+{
+    type Error = RamFlashError;
+}
+
+impl<const N: usize, const PAGE_SIZE: usize, const WORD_SIZE: usize> ReadNorFlash
+    for RamFlash<N, PAGE_SIZE, WORD_SIZE>
+{
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.check(RamFlashOp::Read, offset, bytes.len())?;
+
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+
+        self.stats.reads += 1;
+        self.stats.read_bytes += bytes.len();
+
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<const N: usize, const PAGE_SIZE: usize, const WORD_SIZE: usize> NorFlash
+    for RamFlash<N, PAGE_SIZE, WORD_SIZE>
+{
+    const WRITE_SIZE: usize = WORD_SIZE;
+    const ERASE_SIZE: usize = PAGE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        if to < from {
+            return Err(RamFlashError::OutOfBounds);
+        }
+
+        self.check(RamFlashOp::Erase, from, (to - from) as usize)?;
+
+        let from = from as usize;
+        let pages = (to as usize - from) / PAGE_SIZE;
+
+        let (len, result) = match &mut self.power {
+            Power::CutAfter(steps) if *steps < pages => {
+                // Erase the pages before the cut point, and half of the page at the cut point
+                let len = *steps * PAGE_SIZE + PAGE_SIZE / 2;
+                self.power = Power::Off(RamFlashOp::Erase);
+
+                (len, Err(RamFlashError::PowerLoss))
+            }
+            Power::CutAfter(steps) => {
+                *steps -= pages;
+
+                (pages * PAGE_SIZE, Ok(()))
+            }
+            _ => (pages * PAGE_SIZE, Ok(())),
+        };
+
+        self.data[from..from + len].fill(0xff);
+
+        // Only the completely erased pages are counted
+        self.stats.erases += len / PAGE_SIZE;
+
+        result
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.check(RamFlashOp::Write, offset, bytes.len())?;
+
+        let offset = offset as usize;
+
+        let (len, result) = match &mut self.power {
+            Power::CutAfter(words) if *words * WORD_SIZE < bytes.len() => {
+                // Write the words before the cut point, and half of the word at the cut point
+                let len = *words * WORD_SIZE + WORD_SIZE / 2;
+                self.power = Power::Off(RamFlashOp::Write);
+
+                (len, Err(RamFlashError::PowerLoss))
+            }
+            Power::CutAfter(words) => {
+                *words -= bytes.len() / WORD_SIZE;
+
+                (bytes.len(), Ok(()))
+            }
+            _ => (bytes.len(), Ok(())),
+        };
+
+        self.data[offset..offset + len]
+            .iter_mut()
+            .zip(bytes)
+            .for_each(|(dst, src)| *dst &= *src);
+
+        self.stats.writes += 1;
+        self.stats.written_bytes += len;
+
+        result
+    }
+}
+
+impl<const N: usize, const PAGE_SIZE: usize, const WORD_SIZE: usize> MultiwriteNorFlash
+    for RamFlash<N, PAGE_SIZE, WORD_SIZE>
+{
+}
+
+/// Allows the flash to be lent to e.g. `EmbassyKvBlobStore` and then reused,
+/// which is handy for simulating reboots.
+impl<const N: usize, const PAGE_SIZE: usize, const WORD_SIZE: usize> MultiwriteNorFlash
+    for &mut RamFlash<N, PAGE_SIZE, WORD_SIZE>
+{
+}
+
+/// A NOR Flash wrapper which counts the erases of each of the `PAGES` pages
+/// of a flash range starting at `base`.
+///
+/// The counts are kept in RAM, i.e. they reflect the erases since the wrapper was created (usually since boot).
+/// Erases outside of the flash range are not counted.
+pub struct WearFlash<S, const PAGES: usize> {
+    flash: S,
+    base: u32,
+    erases: [u32; PAGES],
+}
+
+impl<S, const PAGES: usize> WearFlash<S, PAGES>
+where
+    S: NorFlash,
+{
+    /// Create a new wrapper for the flash range of `PAGES` pages starting at `base`.
+    pub const fn new(flash: S, base: u32) -> Self {
+        Self {
+            flash,
+            base,
+            erases: [0; PAGES],
+        }
+    }
+
+    /// Return the number of erases of each page of the flash range.
+    pub fn erase_counts(&self) -> &[u32; PAGES] {
+        &self.erases
+    }
+
+    /// Return the total number of page erases in the flash range.
+    pub fn total_erases(&self) -> u32 {
+        self.erases.iter().sum()
+    }
+
+    /// Return the wrapped flash.
+    pub fn flash(&self) -> &S {
+        &self.flash
+    }
+
+    /// Return the wrapped flash for modification.
+    pub fn flash_mut(&mut self) -> &mut S {
+        &mut self.flash
+    }
+}
+
+impl<S, const PAGES: usize> ErrorType for WearFlash<S, PAGES>
+where
+    S: ErrorType,
+{
+    type Error = S::Error;
+}
+
+impl<S, const PAGES: usize> ReadNorFlash for WearFlash<S, PAGES>
+where
+    S: ReadNorFlash,
+{
+    const READ_SIZE: usize = S::READ_SIZE;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.flash.read(offset, bytes).await
+    }
+
+    fn capacity(&self) -> usize {
+        self.flash.capacity()
+    }
+}
+
+impl<S, const PAGES: usize> NorFlash for WearFlash<S, PAGES>
+where
+    S: NorFlash,
+{
+    const WRITE_SIZE: usize = S::WRITE_SIZE;
+    const ERASE_SIZE: usize = S::ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.flash.erase(from, to).await?;
+
+        for offset in (from..to).step_by(S::ERASE_SIZE) {
+            if let Some(page) = offset.checked_sub(self.base) {
+                if let Some(erases) = self.erases.get_mut(page as usize / S::ERASE_SIZE) {
+                    *erases += 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.flash.write(offset, bytes).await
+    }
+}
+
+impl<S, const PAGES: usize> MultiwriteNorFlash for WearFlash<S, PAGES> where S: MultiwriteNorFlash {}
+
+/// Allows the wrapper to be lent to e.g. `EmbassyKvBlobStore`, while keeping access to the erase counts.
+impl<S, const PAGES: usize> MultiwriteNorFlash for &mut WearFlash<S, PAGES> where
+    S: MultiwriteNorFlash
+{
+}
+
+#[cfg(test)]
+mod test {
+    use embassy_futures::block_on;
+
+    use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+
+    use super::{RamFlash, RamFlashError, RamFlashOp, WearFlash};
+
+    #[test]
+    fn nor_semantics() {
+        let mut flash = RamFlash::<256, 64, 4>::new();
+
+        block_on(async {
+            flash.write(0, &[0x0f, 0xf0, 0xff, 0x00]).await.unwrap();
+            flash.write(0, &[0xff, 0x30, 0x0f, 0xff]).await.unwrap();
+
+            let mut buf = [0; 4];
+            flash.read(0, &mut buf).await.unwrap();
+            assert_eq!(buf, [0x0f, 0x30, 0x0f, 0x00]);
+
+            flash.erase(0, 64).await.unwrap();
+            flash.read(0, &mut buf).await.unwrap();
+            assert_eq!(buf, [0xff; 4]);
+
+            assert_eq!(
+                flash.write(2, &[0; 4]).await,
+                Err(RamFlashError::NotAligned)
+            );
+            assert_eq!(flash.erase(0, 32).await, Err(RamFlashError::NotAligned));
+            assert_eq!(
+                flash.read(254, &mut buf).await,
+                Err(RamFlashError::OutOfBounds)
+            );
+
+            assert_eq!(flash.stats().writes, 2);
+            assert_eq!(flash.stats().erases, 1);
+        });
+    }
+
+    #[test]
+    fn injected_failures() {
+        let mut flash = RamFlash::<256, 64, 4>::new();
+
+        flash.fail_after(RamFlashOp::Write, 1);
+
+        block_on(async {
+            flash.write(0, &[0; 4]).await.unwrap();
+            assert_eq!(flash.write(4, &[0; 4]).await, Err(RamFlashError::Injected));
+            flash.erase(0, 64).await.unwrap();
+
+            flash.clear_failure();
+            flash.write(4, &[0; 4]).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn power_loss() {
+        let mut flash = RamFlash::<256, 64, 4>::new();
+
+        flash.cut_power_after(2);
+
+        block_on(async {
+            flash.write(0, &[0; 4]).await.unwrap();
+            assert_eq!(
+                flash.write(4, &[0; 12]).await,
+                Err(RamFlashError::PowerLoss)
+            );
+            assert_eq!(flash.power_lost_during(), Some(RamFlashOp::Write));
+            assert_eq!(flash.erase(0, 64).await, Err(RamFlashError::PowerLoss));
+
+            flash.restore_power();
+
+            let mut buf = [0; 16];
+            flash.read(0, &mut buf).await.unwrap();
+            assert_eq!(
+                buf,
+                [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]
+            );
+
+            // Interrupted erase: one word written, then one page erased, and the power is lost in the second page
+            flash.write(64, &[0; 4]).await.unwrap();
+            flash.write(128, &[0; 4]).await.unwrap();
+            flash.write(128 + 60, &[0; 4]).await.unwrap();
+
+            flash.cut_power_after(2);
+
+            flash.write(192, &[0; 4]).await.unwrap();
+            assert_eq!(flash.erase(64, 256).await, Err(RamFlashError::PowerLoss));
+            assert_eq!(flash.power_lost_during(), Some(RamFlashOp::Erase));
+
+            flash.restore_power();
+
+            let mut buf = [0; 4];
+            flash.read(64, &mut buf).await.unwrap();
+            assert_eq!(buf, [0xff; 4]);
+            flash.read(128, &mut buf).await.unwrap();
+            assert_eq!(buf, [0xff; 4]);
+            flash.read(128 + 60, &mut buf).await.unwrap();
+            assert_eq!(buf, [0; 4]);
+            flash.read(192, &mut buf).await.unwrap();
+            assert_eq!(buf, [0; 4]);
+
+            assert_eq!(flash.stats().erases, 1);
+        });
+    }
+
+    #[test]
+    fn erase_counts() {
+        let mut flash = WearFlash::<_, 2>::new(RamFlash::<256, 64, 4>::new(), 64);
+
+        block_on(async {
+            flash.erase(0, 256).await.unwrap();
+            flash.erase(128, 192).await.unwrap();
+
+            assert_eq!(flash.erase_counts(), &[1, 2]);
+            assert_eq!(flash.total_erases(), 3);
+            assert_eq!(flash.flash().stats().erases, 5);
+        });
+    }
+}
@@ -1,4 +1,4 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 #![allow(async_fn_in_trait)]
 #![allow(unknown_lints)]
 #![allow(renamed_and_removed_lints)]
@@ -10,17 +10,25 @@
 #[cfg(feature = "rs-matter-stack")]
 pub use eth::*;
 pub mod ble;
+#[cfg(feature = "rs-matter-stack")]
+mod crc;
 pub mod epoch;
 pub mod error;
 #[cfg(feature = "rs-matter-stack")]
 pub mod eth;
+#[cfg(feature = "rs-matter-stack")]
+pub mod factory;
+pub mod flash;
 pub mod matter;
 pub mod nal;
 pub mod netif;
 #[cfg(feature = "rs-matter-stack")]
 pub mod persist;
 pub mod rand;
+pub mod redact;
 #[cfg(feature = "rs-matter-stack")]
 pub mod stack;
+#[cfg(feature = "linux")]
+pub mod tap;
 #[cfg(feature = "rs-matter-stack")]
 pub mod wireless;
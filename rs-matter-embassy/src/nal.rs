@@ -10,6 +10,9 @@ use embassy_net::{Config, ConfigV6, Ipv6Cidr, Runner, Stack, StackResources, Sta
 
 use rs_matter_stack::matter::transport::network::{MAX_RX_PACKET_SIZE, MAX_TX_PACKET_SIZE};
 
+#[cfg(feature = "slaac")]
+pub mod slaac;
+
 /// Re-export the `embassy_net` crate
 pub mod net {
     pub use ::embassy_net::*;
@@ -62,21 +65,31 @@ pub fn create_net_stack<const N: usize, D: Driver>(
 /// - Ipv6 enabled with a static configuration that uses the link-local address derived from the MAC address
 /// - Ipv4 enabled with DHCPv4; structly speaking this is not necessary for the Matter stack, but it is
 ///   useful in that the `rs-matter` mDNS responder would also answer ipv4 queries
+///
+/// To get a global or ULA IPv6 address from the routers on the network, opt into `slaac::run_slaac`
+/// (with the `slaac` feature). Note that `embassy-net` supports a single IPv6 address, so the SLAAC address
+/// replaces the link-local one rather than being added next to it: while the SLAAC address is in use, the
+/// device has no link-local address - contrary to RFC 4291 - and is only reachable on the SLAAC address.
 pub fn create_net_config<D: Driver>(driver: &D) -> Config {
     let HardwareAddress::Ethernet(mac) = driver.hardware_address() else {
         unreachable!();
     };
 
     let mut config = Config::dhcpv4(Default::default());
-    config.ipv6 = ConfigV6::Static(StaticConfigV6 {
-        address: Ipv6Cidr::new(create_link_local_ipv6(&mac), 10),
-        gateway: None,
-        dns_servers: heapless::Vec::new(),
-    });
+    config.ipv6 = create_link_local_ipv6_config(&mac);
 
     config
 }
 
+/// Create a static IPv6 configuration that uses the link-local address derived from the MAC address
+fn create_link_local_ipv6_config(mac: &[u8; 6]) -> ConfigV6 {
+    ConfigV6::Static(StaticConfigV6 {
+        address: Ipv6Cidr::new(create_link_local_ipv6(mac), 10),
+        gateway: None,
+        dns_servers: heapless::Vec::new(),
+    })
+}
+
 /// Create a link-local IPv6 address from a MAC address.
 pub fn create_link_local_ipv6(mac: &[u8; 6]) -> Ipv6Addr {
     Ipv6Addr::new(
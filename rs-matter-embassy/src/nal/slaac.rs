@@ -0,0 +1,759 @@
+//! SLAAC: `run_slaac` - IPv6 Stateless Address Autoconfiguration (RFC 4862) for `embassy-net`
+//!
+//! Listens for ICMPv6 Router Advertisements on a raw socket, and configures the `embassy-net` stack with
+//! a global (or - if there is none - a ULA) address derived from the advertised /64 prefix and the MAC address,
+//! so that the device is reachable across routers (i.e. from a Thread border router or from a hub on another subnet).
+//! `EmbassyNetif` then reports the new address to the Matter stack, and mDNS advertises it.
+//!
+//! The address is kept for as long as its prefix is valid, even if the routers start advertising other prefixes
+//! (a ULA address is only replaced by a global one), and the valid lifetimes advertised for its prefix are
+//! processed as per RFC 4862 section 5.5.3 (e), so that a spoofed advertisement cannot expire it prematurely.
+//!
+//! NOTE: `embassy-net` (and thus `smoltcp`, which drops the packets for addresses not assigned to the interface)
+//! supports a single IPv6 address per stack, so while the SLAAC address is in use, the `fe80::` link-local address
+//! is not assigned, which RFC 4291 requires. Peers on the local link need to use the SLAAC address, as advertised
+//! over mDNS, and the Router Solicitations sent after the SLAAC address expired (when the link-local address is
+//! restored) might briefly leave from an address the stack does not own. Hence, SLAAC is an explicit opt-in,
+//! for networks where the device needs to be reachable beyond the local link; see `nal::create_net_config`.
+//!
+//! NOTE: The client needs one raw socket, on top of the `MIN_SOCKET_SET` sockets used by the Matter stack,
+//! so the `embassy-net` `StackResources` should be sized accordingly (i.e. `StackResources<{ MIN_SOCKET_SET + 1 }>`).
+//!
+//! Only available with the `slaac` feature, which enables the `raw` feature of `embassy-net`.
+
+use core::net::Ipv6Addr;
+
+use embassy_futures::select::{select, Either};
+use embassy_net::raw::{IpProtocol, IpVersion, PacketMetadata, RawSocket};
+use embassy_net::{ConfigV6, HardwareAddress, Ipv6Cidr, Stack, StaticConfigV6};
+use embassy_time::{Duration, Instant, Timer};
+
+use log::{info, warn};
+
+use rs_matter_stack::matter::error::Error;
+
+use super::create_link_local_ipv6_config;
+
+/// The maximum number of prefixes processed per Router Advertisement
+pub const MAX_PREFIXES: usize = 4;
+
+/// The MAC address used for IPv6 all-nodes multicast, where the routers send their unsolicited advertisements
+///
+/// Useful with wifi stack implementations (i.e. cyw43) that require explicit
+/// allowlisting of the multicast MAC addresses they should be listening on.
+pub const ALL_NODES_MULTICAST_MAC_IPV6: [u8; 6] = [0x33, 0x33, 0x00, 0x00, 0x00, 0x01];
+
+/// The number of Router Solicitations sent when there is no SLAAC address
+const MAX_RTR_SOLICITATIONS: u8 = 3;
+
+/// The interval between two Router Solicitations
+const RTR_SOLICITATION_INTERVAL: Duration = Duration::from_secs(4);
+
+/// The valid lifetime below which the advertisements cannot shorten the lifetime of the current address
+/// (RFC 4862 section 5.5.3 (e))
+const MIN_VALID_LIFETIME: Duration = Duration::from_secs(2 * 60 * 60);
+
+const IPV6_HEADER_LEN: usize = 40;
+const ICMPV6_RA_HEADER_LEN: usize = 16;
+
+const ICMPV6_ROUTER_SOLICITATION: u8 = 133;
+const ICMPV6_ROUTER_ADVERTISEMENT: u8 = 134;
+
+const OPTION_SOURCE_LINK_LAYER_ADDRESS: u8 = 1;
+const OPTION_PREFIX_INFORMATION: u8 = 3;
+
+const PREFIX_FLAG_AUTONOMOUS: u8 = 0x40;
+
+/// All-routers multicast
+const ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2);
+
+/// The length of a Router Solicitation packet (IPv6 header included)
+const ROUTER_SOLICITATION_LEN: usize = IPV6_HEADER_LEN + 16;
+
+/// A Prefix Information option of a Router Advertisement
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct PrefixInfo {
+    pub prefix: Ipv6Addr,
+    pub prefix_len: u8,
+    /// Whether the prefix can be used for SLAAC
+    pub autonomous: bool,
+    /// In seconds; `u32::MAX` means infinity
+    pub valid_lifetime: u32,
+    /// In seconds; `u32::MAX` means infinity
+    pub preferred_lifetime: u32,
+}
+
+impl PrefixInfo {
+    fn is_ula(&self) -> bool {
+        self.prefix.segments()[0] & 0xfe00 == 0xfc00
+    }
+
+    fn is_link_local(&self) -> bool {
+        self.prefix.segments()[0] & 0xffc0 == 0xfe80
+    }
+
+    /// Return `true` if the prefix can be used for SLAAC: an autonomous, non-link-local /64 prefix,
+    /// with a preferred lifetime not exceeding its valid lifetime
+    fn is_autoconf(&self) -> bool {
+        self.autonomous
+            && self.prefix_len == 64
+            && !self.is_link_local()
+            && self.preferred_lifetime <= self.valid_lifetime
+    }
+
+    /// Return `true` if the first 64 bits of `address` match the prefix
+    fn contains(&self, address: &Ipv6Addr) -> bool {
+        self.prefix.octets()[..8] == address.octets()[..8]
+    }
+}
+
+/// A (parsed) Router Advertisement
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct RouterAdvertisement {
+    /// The (link-local) address of the router
+    pub router: Ipv6Addr,
+    /// How long the router can be used as a default router, in seconds
+    pub router_lifetime: u16,
+    pub prefixes: heapless::Vec<PrefixInfo, MAX_PREFIXES>,
+}
+
+impl RouterAdvertisement {
+    /// Return the prefix to use for a new SLAAC address, if any: an autonomous, valid and preferred /64 prefix -
+    /// preferably a global one
+    pub fn slaac_prefix(&self) -> Option<&PrefixInfo> {
+        let mut usable = self
+            .prefixes
+            .iter()
+            .filter(|prefix| prefix.is_autoconf() && prefix.preferred_lifetime > 0);
+
+        let first = usable.clone().next();
+
+        usable.find(|prefix| !prefix.is_ula()).or(first)
+    }
+}
+
+/// A SLAAC address, and the parameters it was configured with
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Lease {
+    /// The address (with a /64 prefix)
+    pub address: Ipv6Addr,
+    /// The router whose advertisements maintain `gateway`; initially, the one which advertised the prefix
+    pub router: Ipv6Addr,
+    /// `router`, if it can be used as a default router
+    pub gateway: Option<Ipv6Addr>,
+    /// When the prefix - and thus the address - stops being valid
+    pub expires: Instant,
+}
+
+impl Lease {
+    fn is_ula(&self) -> bool {
+        self.address.segments()[0] & 0xfe00 == 0xfc00
+    }
+
+    /// Update the default router as per a Router Advertisement, returning `true` if it changed.
+    ///
+    /// An advertisement from `router` with a zero router lifetime withdraws it as a default router;
+    /// once there is no default router, another router advertising itself as one is taken instead.
+    fn update_gateway(&mut self, ra: &RouterAdvertisement) -> bool {
+        let gateway = if ra.router == self.router {
+            (ra.router_lifetime > 0).then_some(ra.router)
+        } else if self.gateway.is_none() && ra.router_lifetime > 0 {
+            self.router = ra.router;
+            Some(ra.router)
+        } else {
+            self.gateway
+        };
+
+        let changed = gateway != self.gateway;
+        self.gateway = gateway;
+
+        changed
+    }
+}
+
+/// The SLAAC state machine: tracks the current address as Router Advertisements arrive and time passes.
+///
+/// Independent of the `embassy-net` stack, which is configured by `run_slaac` as per its outcomes.
+#[derive(Debug, Clone)]
+pub struct Slaac {
+    mac: [u8; 6],
+    lease: Option<Lease>,
+}
+
+impl Slaac {
+    /// Create a new state machine for an interface with the provided MAC address, and no address yet
+    pub const fn new(mac: [u8; 6]) -> Self {
+        Self { mac, lease: None }
+    }
+
+    /// Return the current address, if any
+    pub fn lease(&self) -> Option<&Lease> {
+        self.lease.as_ref()
+    }
+
+    /// Process a Router Advertisement received at `now`.
+    ///
+    /// Returns the new lease if the address or the default router changed, and thus the stack needs to be reconfigured.
+    pub fn advertisement(&mut self, ra: &RouterAdvertisement, now: Instant) -> Option<Lease> {
+        let mut gateway_changed = false;
+
+        if let Some(lease) = self.lease.as_mut() {
+            // Refresh the lifetime of the current address
+            for prefix in ra
+                .prefixes
+                .iter()
+                .filter(|prefix| prefix.is_autoconf() && prefix.contains(&lease.address))
+            {
+                lease.expires = refresh(lease.expires, prefix.valid_lifetime, now);
+            }
+
+            if lease.expires <= now {
+                // Can only happen if the address was not valid anymore anyway
+                return None;
+            }
+
+            gateway_changed = lease.update_gateway(ra);
+        }
+
+        // Keep the current address while it is valid, unless it is a ULA one and a global prefix is advertised
+        let Some(prefix) = ra.slaac_prefix().filter(|prefix| {
+            !self.lease.is_some_and(|lease| {
+                prefix.contains(&lease.address) || !lease.is_ula() || prefix.is_ula()
+            })
+        }) else {
+            return self.lease.filter(|_| gateway_changed);
+        };
+
+        let lease = Lease {
+            address: slaac_address(&prefix.prefix, &self.mac),
+            router: ra.router,
+            gateway: (ra.router_lifetime > 0).then_some(ra.router),
+            expires: expiry(prefix.valid_lifetime, now),
+        };
+
+        self.lease = Some(lease);
+
+        Some(lease)
+    }
+
+    /// Expire the current address, if its prefix is not valid anymore at `now`.
+    ///
+    /// Returns the expired lease, if any, in which case the stack needs to be reconfigured with the link-local address.
+    pub fn expire(&mut self, now: Instant) -> Option<Lease> {
+        if self.lease.is_some_and(|lease| lease.expires <= now) {
+            self.lease.take()
+        } else {
+            None
+        }
+    }
+}
+
+/// Return when a prefix with the provided valid lifetime (in seconds; `u32::MAX` means infinity), received at `now`,
+/// expires
+fn expiry(valid_lifetime: u32, now: Instant) -> Instant {
+    if valid_lifetime == u32::MAX {
+        Instant::MAX
+    } else {
+        now + Duration::from_secs(valid_lifetime as _)
+    }
+}
+
+/// Return the new expiry of the current address, when a valid lifetime (in seconds) for its prefix is received at `now`,
+/// as per the two-hour rule of RFC 4862 section 5.5.3 (e)
+fn refresh(expires: Instant, valid_lifetime: u32, now: Instant) -> Instant {
+    let received = expiry(valid_lifetime, now);
+    let remaining = expires.saturating_duration_since(now);
+
+    if received > now + MIN_VALID_LIFETIME || received > expires {
+        received
+    } else if remaining <= MIN_VALID_LIFETIME {
+        // Ignore the advertised lifetime, so that a spoofed advertisement cannot expire the address
+        expires
+    } else {
+        now + MIN_VALID_LIFETIME
+    }
+}
+
+/// Run IPv6 SLAAC on the provided `embassy-net` stack, which should be configured with `nal::create_net_config`.
+///
+/// Should be run concurrently with the Matter stack (i.e. as part of the user future passed to `MatterStack::run`).
+pub async fn run_slaac(stack: Stack<'_>) -> Result<(), Error> {
+    #[allow(irrefutable_let_patterns)]
+    let HardwareAddress::Ethernet(mac) = stack.hardware_address() else {
+        panic!("Invalid hardware address");
+    };
+
+    let mac = mac.0;
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buf = [0; 1280];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buf = [0; ROUTER_SOLICITATION_LEN];
+
+    let socket = RawSocket::new(
+        stack,
+        IpVersion::Ipv6,
+        IpProtocol::Icmpv6,
+        &mut rx_meta,
+        &mut rx_buf,
+        &mut tx_meta,
+        &mut tx_buf,
+    );
+
+    let mut buf = [0; 1280];
+
+    let mut slaac = Slaac::new(mac);
+    let mut solicitations = 0;
+    let mut next_solicitation = Instant::now();
+
+    loop {
+        if slaac.lease().is_none()
+            && solicitations < MAX_RTR_SOLICITATIONS
+            && Instant::now() >= next_solicitation
+        {
+            let len = router_solicitation(&mac, &mut buf);
+            socket.send(&buf[..len]).await;
+
+            solicitations += 1;
+            next_solicitation = Instant::now() + RTR_SOLICITATION_INTERVAL;
+        }
+
+        let deadline = match slaac.lease() {
+            Some(lease) => lease.expires,
+            None if solicitations < MAX_RTR_SOLICITATIONS => next_solicitation,
+            // Wait for the unsolicited advertisements of the routers
+            None => Instant::MAX,
+        };
+
+        match select(socket.recv(&mut buf), Timer::at(deadline)).await {
+            Either::First(Ok(len)) => {
+                let Some(ra) = parse_router_advertisement(&buf[..len]) else {
+                    continue;
+                };
+
+                if let Some(lease) = slaac.advertisement(&ra, Instant::now()) {
+                    info!(
+                        "SLAAC: using address {} (default router {:?})",
+                        lease.address, lease.gateway
+                    );
+
+                    stack.set_config_v6(ConfigV6::Static(StaticConfigV6 {
+                        address: Ipv6Cidr::new(lease.address, 64),
+                        gateway: lease.gateway,
+                        dns_servers: heapless::Vec::new(),
+                    }));
+                }
+            }
+            Either::First(Err(_)) => warn!("SLAAC: received a truncated packet"),
+            Either::Second(_) => {
+                if let Some(lease) = slaac.expire(Instant::now()) {
+                    info!(
+                        "SLAAC: address {} expired, reverting to the link-local address",
+                        lease.address
+                    );
+
+                    stack.set_config_v6(create_link_local_ipv6_config(&mac));
+
+                    solicitations = 0;
+                    next_solicitation = Instant::now();
+                }
+            }
+        }
+    }
+}
+
+/// Derive the SLAAC address for the provided /64 prefix from the MAC address (modified EUI-64)
+pub fn slaac_address(prefix: &Ipv6Addr, mac: &[u8; 6]) -> Ipv6Addr {
+    let mut octets = super::create_link_local_ipv6(mac).octets();
+    octets[..8].copy_from_slice(&prefix.octets()[..8]);
+
+    Ipv6Addr::from(octets)
+}
+
+/// Parse and validate a Router Advertisement (IPv6 header included)
+pub fn parse_router_advertisement(packet: &[u8]) -> Option<RouterAdvertisement> {
+    if packet.len() < IPV6_HEADER_LEN + ICMPV6_RA_HEADER_LEN || packet[0] >> 4 != 6 {
+        return None;
+    }
+
+    let payload_len = u16::from_be_bytes([packet[4], packet[5]]) as usize;
+    let next_header = packet[6];
+    let hop_limit = packet[7];
+
+    let src = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).unwrap());
+    let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[24..40]).unwrap());
+
+    let icmp = packet.get(IPV6_HEADER_LEN..IPV6_HEADER_LEN + payload_len)?;
+
+    // As per RFC 4861, routers advertise from their link-local address, and the hop limit should be 255,
+    // i.e. the advertisement was not forwarded
+    if next_header != u8::from(IpProtocol::Icmpv6)
+        || hop_limit != 255
+        || src.segments()[0] & 0xffc0 != 0xfe80
+        || icmp.len() < ICMPV6_RA_HEADER_LEN
+        || icmp[0] != ICMPV6_ROUTER_ADVERTISEMENT
+        || icmp[1] != 0
+        || icmpv6_checksum(&src, &dst, icmp) != 0
+    {
+        return None;
+    }
+
+    let mut ra = RouterAdvertisement {
+        router: src,
+        router_lifetime: u16::from_be_bytes([icmp[6], icmp[7]]),
+        prefixes: heapless::Vec::new(),
+    };
+
+    let mut options = &icmp[ICMPV6_RA_HEADER_LEN..];
+
+    while !options.is_empty() {
+        let len = *options.get(1)? as usize * 8;
+        if len == 0 {
+            return None;
+        }
+
+        let option = options.get(..len)?;
+
+        if option[0] == OPTION_PREFIX_INFORMATION && len == 32 {
+            // Ignore the prefixes which do not fit
+            let _ = ra.prefixes.push(PrefixInfo {
+                prefix_len: option[2],
+                autonomous: option[3] & PREFIX_FLAG_AUTONOMOUS != 0,
+                valid_lifetime: u32::from_be_bytes(option[4..8].try_into().unwrap()),
+                preferred_lifetime: u32::from_be_bytes(option[8..12].try_into().unwrap()),
+                prefix: Ipv6Addr::from(<[u8; 16]>::try_from(&option[16..32]).unwrap()),
+            });
+        }
+
+        options = &options[len..];
+    }
+
+    Some(ra)
+}
+
+/// Build a Router Solicitation (IPv6 header included) in `buf`, returning its length
+fn router_solicitation(mac: &[u8; 6], buf: &mut [u8]) -> usize {
+    let src = super::create_link_local_ipv6(mac);
+
+    let packet = &mut buf[..ROUTER_SOLICITATION_LEN];
+    packet.fill(0);
+
+    // IPv6 header
+    packet[0] = 0x60;
+    packet[4..6]
+        .copy_from_slice(&((ROUTER_SOLICITATION_LEN - IPV6_HEADER_LEN) as u16).to_be_bytes());
+    packet[6] = u8::from(IpProtocol::Icmpv6);
+    packet[7] = 255;
+    packet[8..24].copy_from_slice(&src.octets());
+    packet[24..40].copy_from_slice(&ALL_ROUTERS.octets());
+
+    // ICMPv6 Router Solicitation, with a Source Link-Layer Address option
+    let icmp = &mut packet[IPV6_HEADER_LEN..];
+    icmp[0] = ICMPV6_ROUTER_SOLICITATION;
+    icmp[8] = OPTION_SOURCE_LINK_LAYER_ADDRESS;
+    icmp[9] = 1;
+    icmp[10..16].copy_from_slice(mac);
+
+    let checksum = icmpv6_checksum(&src, &ALL_ROUTERS, icmp);
+    icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    ROUTER_SOLICITATION_LEN
+}
+
+/// Compute the ICMPv6 checksum of `icmp` (with the IPv6 pseudo-header);
+/// zero if `icmp` already contains a valid checksum
+fn icmpv6_checksum(src: &Ipv6Addr, dst: &Ipv6Addr, icmp: &[u8]) -> u16 {
+    let mut sum = 0_u32;
+
+    let mut add = |data: &[u8]| {
+        for chunk in data.chunks(2) {
+            sum += u16::from_be_bytes([chunk[0], chunk.get(1).copied().unwrap_or(0)]) as u32;
+        }
+    };
+
+    add(&src.octets());
+    add(&dst.octets());
+    add(&(icmp.len() as u32).to_be_bytes());
+    add(&[0, 0, 0, u8::from(IpProtocol::Icmpv6)]);
+    add(icmp);
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod test {
+    use core::net::Ipv6Addr;
+
+    use embassy_time::{Duration, Instant};
+
+    use super::{
+        icmpv6_checksum, parse_router_advertisement, router_solicitation, slaac_address,
+        RouterAdvertisement, Slaac, ROUTER_SOLICITATION_LEN,
+    };
+
+    const MAC: [u8; 6] = [0x52, 0x74, 0xf2, 0xb1, 0xa8, 0x7f];
+
+    const ROUTER: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+    const OTHER_ROUTER: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 2);
+    const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+    const GLOBAL: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 0, 0, 0, 0);
+    const ULA: Ipv6Addr = Ipv6Addr::new(0xfd12, 0x3456, 0x789a, 1, 0, 0, 0, 0);
+    const OTHER_GLOBAL: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 3, 4, 0, 0, 0, 0);
+
+    const HOUR: u32 = 60 * 60;
+
+    fn prefix_option(prefix: &Ipv6Addr, prefix_len: u8, flags: u8, valid: u32) -> [u8; 32] {
+        let mut option = [0; 32];
+
+        option[0] = 3;
+        option[1] = 4;
+        option[2] = prefix_len;
+        option[3] = flags;
+        option[4..8].copy_from_slice(&valid.to_be_bytes());
+        option[8..12].copy_from_slice(&valid.to_be_bytes());
+        option[16..32].copy_from_slice(&prefix.octets());
+
+        option
+    }
+
+    fn router_advertisement(options: &[&[u8]], buf: &mut [u8]) -> usize {
+        let options_len: usize = options.iter().map(|option| option.len()).sum();
+        let icmp_len = 16 + options_len;
+
+        buf[..40 + icmp_len].fill(0);
+
+        buf[0] = 0x60;
+        buf[4..6].copy_from_slice(&(icmp_len as u16).to_be_bytes());
+        buf[6] = 58;
+        buf[7] = 255;
+        buf[8..24].copy_from_slice(&ROUTER.octets());
+        buf[24..40].copy_from_slice(&ALL_NODES.octets());
+
+        let icmp = &mut buf[40..40 + icmp_len];
+        icmp[0] = 134;
+        icmp[4] = 64;
+        icmp[6..8].copy_from_slice(&1800_u16.to_be_bytes());
+
+        let mut offset = 16;
+        for option in options {
+            icmp[offset..offset + option.len()].copy_from_slice(option);
+            offset += option.len();
+        }
+
+        let checksum = icmpv6_checksum(&ROUTER, &ALL_NODES, icmp);
+        icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+        40 + icmp_len
+    }
+
+    #[test]
+    fn address() {
+        assert_eq!(
+            slaac_address(&GLOBAL, &MAC),
+            Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 0x5074, 0xf2ff, 0xfeb1, 0xa87f)
+        );
+    }
+
+    #[test]
+    fn solicitation() {
+        let mut buf = [0; 128];
+        let len = router_solicitation(&MAC, &mut buf);
+
+        assert_eq!(len, ROUTER_SOLICITATION_LEN);
+
+        let packet = &buf[..len];
+        assert_eq!(packet[40], 133);
+        assert_eq!(&packet[50..56], &MAC);
+
+        let src = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).unwrap());
+        let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[24..40]).unwrap());
+        assert_eq!(icmpv6_checksum(&src, &dst, &packet[40..]), 0);
+    }
+
+    #[test]
+    fn advertisement() {
+        let mut buf = [0; 256];
+
+        // A source link-layer address option, an ULA and a global prefix
+        let slla = [1, 1, 0x02, 0, 0, 0, 0, 1];
+        let ula = prefix_option(&ULA, 64, 0xc0, 3600);
+        let global = prefix_option(&GLOBAL, 64, 0xc0, u32::MAX);
+
+        let len = router_advertisement(&[&slla, &ula, &global], &mut buf);
+        let ra = parse_router_advertisement(&buf[..len]).unwrap();
+
+        assert_eq!(ra.router, ROUTER);
+        assert_eq!(ra.router_lifetime, 1800);
+        assert_eq!(ra.prefixes.len(), 2);
+        assert_eq!(ra.prefixes[0].prefix, ULA);
+        assert_eq!(ra.prefixes[0].valid_lifetime, 3600);
+
+        // Global addresses are preferred
+        assert_eq!(ra.slaac_prefix().unwrap().prefix, GLOBAL);
+
+        // ... but ULA ones are used when there are no global ones
+        let len = router_advertisement(&[&ula], &mut buf);
+        let ra = parse_router_advertisement(&buf[..len]).unwrap();
+        assert_eq!(ra.slaac_prefix().unwrap().prefix, ULA);
+
+        // Prefixes which are not autonomous, not /64 or expired cannot be used
+        let not_autonomous = prefix_option(&GLOBAL, 64, 0x80, 3600);
+        let not_64 = prefix_option(&GLOBAL, 48, 0xc0, 3600);
+        let expired = prefix_option(&GLOBAL, 64, 0xc0, 0);
+
+        let len = router_advertisement(&[&not_autonomous, &not_64, &expired], &mut buf);
+        let ra = parse_router_advertisement(&buf[..len]).unwrap();
+        assert_eq!(ra.prefixes.len(), 3);
+        assert_eq!(ra.slaac_prefix(), None);
+    }
+
+    #[test]
+    fn invalid_advertisement() {
+        let mut buf = [0; 256];
+
+        let global = prefix_option(&GLOBAL, 64, 0xc0, 3600);
+        let len = router_advertisement(&[&global], &mut buf);
+
+        assert!(parse_router_advertisement(&buf[..len]).is_some());
+
+        // Truncated
+        assert!(parse_router_advertisement(&buf[..len - 1]).is_none());
+
+        // Bad checksum
+        let mut invalid = buf;
+        invalid[len - 1] ^= 1;
+        assert!(parse_router_advertisement(&invalid[..len]).is_none());
+
+        // Forwarded
+        let mut invalid = buf;
+        invalid[7] = 64;
+        assert!(parse_router_advertisement(&invalid[..len]).is_none());
+
+        // Zero-length option
+        let len = router_advertisement(&[&[3, 0, 0, 0, 0, 0, 0, 0]], &mut buf);
+        assert!(parse_router_advertisement(&buf[..len]).is_none());
+    }
+
+    /// Parse a Router Advertisement with the provided prefix options
+    fn ra(options: &[&[u8]]) -> RouterAdvertisement {
+        let mut buf = [0; 256];
+        let len = router_advertisement(options, &mut buf);
+
+        parse_router_advertisement(&buf[..len]).unwrap()
+    }
+
+    #[test]
+    fn lease() {
+        let start = Instant::from_secs(1000);
+        let at = |secs: u32| start + Duration::from_secs(secs as _);
+
+        let mut slaac = Slaac::new(MAC);
+
+        // Only a ULA prefix: use it
+        let lease = slaac
+            .advertisement(&ra(&[&prefix_option(&ULA, 64, 0xc0, 3 * HOUR)]), start)
+            .unwrap();
+        assert_eq!(lease.address, slaac_address(&ULA, &MAC));
+        assert_eq!(lease.gateway, Some(ROUTER));
+        assert_eq!(lease.expires, at(3 * HOUR));
+
+        // A global prefix is strictly better: switch to it
+        let lease = slaac
+            .advertisement(&ra(&[&prefix_option(&GLOBAL, 64, 0xc0, 3 * HOUR)]), at(10))
+            .unwrap();
+        assert_eq!(lease.address, slaac_address(&GLOBAL, &MAC));
+        assert_eq!(lease.expires, at(10 + 3 * HOUR));
+
+        // Neither another global prefix, nor a ULA one replace the current address while its prefix is valid
+        assert_eq!(
+            slaac.advertisement(
+                &ra(&[&prefix_option(&OTHER_GLOBAL, 64, 0xc0, 3 * HOUR)]),
+                at(20)
+            ),
+            None
+        );
+        assert_eq!(
+            slaac.advertisement(&ra(&[&prefix_option(&ULA, 64, 0xc0, 3 * HOUR)]), at(20)),
+            None
+        );
+        assert_eq!(slaac.lease().unwrap().address, lease.address);
+
+        // A longer valid lifetime is taken as is
+        slaac.advertisement(&ra(&[&prefix_option(&GLOBAL, 64, 0xc0, 4 * HOUR)]), at(30));
+        assert_eq!(slaac.lease().unwrap().expires, at(30 + 4 * HOUR));
+
+        // A short valid lifetime only brings the remaining lifetime down to two hours...
+        slaac.advertisement(&ra(&[&prefix_option(&GLOBAL, 64, 0xc0, 60)]), at(40));
+        assert_eq!(slaac.lease().unwrap().expires, at(40 + 2 * HOUR));
+
+        // ... and is ignored once the remaining lifetime is two hours or less, even if it is zero
+        slaac.advertisement(&ra(&[&prefix_option(&GLOBAL, 64, 0xc0, 0)]), at(50));
+        assert_eq!(slaac.lease().unwrap().expires, at(40 + 2 * HOUR));
+
+        // Expiry
+        assert_eq!(slaac.expire(at(40 + 2 * HOUR - 1)), None);
+        assert_eq!(
+            slaac.expire(at(40 + 2 * HOUR)).unwrap().address,
+            lease.address
+        );
+        assert_eq!(slaac.lease(), None);
+        assert_eq!(slaac.expire(at(40 + 3 * HOUR)), None);
+
+        // Once expired, fall back to whatever prefix is advertised
+        let lease = slaac
+            .advertisement(
+                &ra(&[&prefix_option(&OTHER_GLOBAL, 64, 0xc0, u32::MAX)]),
+                at(3 * HOUR),
+            )
+            .unwrap();
+        assert_eq!(lease.address, slaac_address(&OTHER_GLOBAL, &MAC));
+        assert_eq!(lease.expires, Instant::MAX);
+        assert_eq!(slaac.expire(at(u32::MAX)), None);
+    }
+
+    #[test]
+    fn gateway() {
+        let start = Instant::from_secs(1000);
+
+        let mut slaac = Slaac::new(MAC);
+
+        let lease = slaac
+            .advertisement(&ra(&[&prefix_option(&GLOBAL, 64, 0xc0, u32::MAX)]), start)
+            .unwrap();
+        assert_eq!(lease.gateway, Some(ROUTER));
+
+        // The router withdraws itself as a default router: same address, no gateway
+        let mut withdrawn = ra(&[]);
+        withdrawn.router_lifetime = 0;
+
+        let lease = slaac.advertisement(&withdrawn, start).unwrap();
+        assert_eq!(lease.address, slaac_address(&GLOBAL, &MAC));
+        assert_eq!(lease.gateway, None);
+        assert_eq!(slaac.advertisement(&withdrawn, start), None);
+
+        // Another router advertising itself as a default router is taken instead
+        let mut other = ra(&[]);
+        other.router = OTHER_ROUTER;
+
+        let lease = slaac.advertisement(&other, start).unwrap();
+        assert_eq!(lease.gateway, Some(OTHER_ROUTER));
+        assert_eq!(slaac.advertisement(&other, start), None);
+
+        // ... and the advertisements of the previous router do not change it anymore
+        assert_eq!(slaac.advertisement(&ra(&[]), start), None);
+        assert_eq!(slaac.advertisement(&withdrawn, start), None);
+        assert_eq!(slaac.lease().unwrap().gateway, Some(OTHER_ROUTER));
+
+        // ... unlike a withdrawal by the new one
+        other.router_lifetime = 0;
+
+        let lease = slaac.advertisement(&other, start).unwrap();
+        assert_eq!(lease.gateway, None);
+        assert_eq!(lease.address, slaac_address(&GLOBAL, &MAC));
+    }
+}
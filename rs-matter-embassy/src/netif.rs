@@ -1,21 +1,88 @@
 //! Network interface: `EmbassyNetif - a `Netif` trait implementation for `embassy-net`
 
-use core::cell::Cell;
+use core::cell::RefCell;
+use core::future::{poll_fn, Future};
+use core::net::{Ipv4Addr, Ipv6Addr};
+use core::pin::pin;
+use core::task::Poll;
 
-use embassy_futures::select::select;
 use embassy_net::{HardwareAddress, Stack};
 use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
-use embassy_time::{Duration, Timer};
 
 use rs_matter_stack::matter::error::Error;
 use rs_matter_stack::netif::{Netif, NetifConf};
 
-const TIMEOUT_PERIOD_SECS: u8 = 5;
+/// The state of an `embassy-net` stack, as used by `EmbassyNetif`
+trait StackState {
+    /// The IPv4 address, if IPv4 is configured
+    fn ipv4(&self) -> Option<Ipv4Addr>;
+
+    /// The IPv6 address, if IPv6 is configured
+    fn ipv6(&self) -> Option<Ipv6Addr>;
+
+    /// The MAC address of the interface
+    fn mac(&self) -> [u8; 6];
+
+    /// Whether the link is up
+    fn is_link_up(&self) -> bool;
+}
+
+impl StackState for Stack<'_> {
+    fn ipv4(&self) -> Option<Ipv4Addr> {
+        self.config_v4().map(|v4| v4.address.address())
+    }
+
+    fn ipv6(&self) -> Option<Ipv6Addr> {
+        self.config_v6().map(|v6| v6.address.address())
+    }
+
+    fn mac(&self) -> [u8; 6] {
+        #[allow(irrefutable_let_patterns)]
+        let HardwareAddress::Ethernet(addr) = self.hardware_address() else {
+            panic!("Invalid hardware address");
+        };
+
+        addr.0
+    }
+
+    fn is_link_up(&self) -> bool {
+        Stack::is_link_up(self)
+    }
+}
+
+/// Return the configuration of the interface, if it is operational for Matter.
+///
+/// Matter only needs IPv6, so the interface is operational as soon as IPv6 is configured;
+/// IPv4 is optional (i.e. on IPv6-only networks, or if DHCPv4 fails), and reported as unspecified when missing.
+fn netif_conf(stack: &impl StackState) -> Option<NetifConf> {
+    let ipv6 = stack.ipv6()?;
+
+    Some(NetifConf {
+        ipv4: stack.ipv4().unwrap_or(Ipv4Addr::UNSPECIFIED),
+        ipv6,
+        interface: 0,
+        mac: stack.mac(),
+    })
+}
+
+fn snapshot(stack: &impl StackState) -> Snapshot {
+    Snapshot {
+        conf: netif_conf(stack),
+        link_up: stack.is_link_up(),
+    }
+}
+
+/// The state of the interface last reported to the Matter stack
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Snapshot {
+    conf: Option<NetifConf>,
+    link_up: bool,
+}
 
 /// A `Netif` trait implementation for `embassy-net`
 pub struct EmbassyNetif<'d> {
     stack: Stack<'d>,
-    up: Mutex<CriticalSectionRawMutex, Cell<bool>>,
+    snapshot: Mutex<CriticalSectionRawMutex, RefCell<Option<Snapshot>>>,
 }
 
 impl<'d> EmbassyNetif<'d> {
@@ -23,50 +90,56 @@ impl<'d> EmbassyNetif<'d> {
     pub fn new(stack: Stack<'d>) -> Self {
         Self {
             stack,
-            up: Mutex::new(Cell::new(false)),
+            snapshot: Mutex::new(RefCell::new(None)),
         }
     }
 
     fn get_conf(&self) -> Option<NetifConf> {
-        let v4 = self.stack.config_v4()?;
-        let v6 = self.stack.config_v6()?;
-
-        let conf = NetifConf {
-            ipv4: v4.address.address(),
-            ipv6: v6.address.address(),
-            interface: 0,
-            mac: {
-                #[allow(irrefutable_let_patterns)]
-                let HardwareAddress::Ethernet(addr) = self.stack.hardware_address() else {
-                    panic!("Invalid hardware address");
-                };
-
-                addr.0
-            },
-        };
+        let snapshot = self.snapshot();
+        let conf = snapshot.conf.clone();
 
-        Some(conf)
+        // Changes are detected relative to the configuration last reported to the Matter stack
+        self.snapshot
+            .lock(|last| *last.borrow_mut() = Some(snapshot));
+
+        conf
     }
 
-    async fn wait_conf_change(&self) {
-        // Embassy does have a `wait_config_up/down` but no `wait_config_change`
-        // Use a timer as a workaround
-
-        if self.up.lock(|up| up.get()) {
-            select(
-                self.stack.wait_config_down(),
-                Timer::after(Duration::from_secs(TIMEOUT_PERIOD_SECS as _)),
-            )
-            .await;
-        } else {
-            select(
-                self.stack.wait_config_up(),
-                Timer::after(Duration::from_secs(TIMEOUT_PERIOD_SECS as _)),
-            )
-            .await;
-        }
+    fn snapshot(&self) -> Snapshot {
+        snapshot(&self.stack)
+    }
 
-        self.up.lock(|up| up.set(self.stack.is_config_up()));
+    async fn wait_conf_change(&self) {
+        let last = self
+            .snapshot
+            .lock(|last| last.borrow().clone())
+            .unwrap_or_else(|| self.snapshot());
+
+        poll_fn(|cx| {
+            // Embassy does have `wait_config_up/down` and `wait_link_up/down` but no `wait_config_change`;
+            // however, all of them share the same waker, which the stack wakes on any link or configuration change
+            // (including a new address while the configuration stays up), so - by polling whichever of them
+            // is pending - we get woken on any change.
+            //
+            // Register the waker first, so that a change right after the comparison below is not missed
+            let _ = if self.stack.is_config_up() {
+                pin!(self.stack.wait_config_down()).poll(cx)
+            } else {
+                pin!(self.stack.wait_config_up()).poll(cx)
+            };
+
+            let current = self.snapshot();
+
+            if current != last {
+                self.snapshot
+                    .lock(|last| *last.borrow_mut() = Some(current));
+
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
     }
 }
 
@@ -81,3 +154,95 @@ impl Netif for EmbassyNetif<'_> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use core::cell::Cell;
+    use core::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::{netif_conf, snapshot, StackState};
+
+    const MAC: [u8; 6] = [0x02, 0x00, 0x00, 0x12, 0x34, 0x56];
+
+    const IPV4: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 42);
+    const IPV6: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0xff, 0xfe12, 0x3456);
+
+    #[derive(Default)]
+    struct FakeStack {
+        ipv4: Cell<Option<Ipv4Addr>>,
+        ipv6: Cell<Option<Ipv6Addr>>,
+        link_up: Cell<bool>,
+    }
+
+    impl StackState for FakeStack {
+        fn ipv4(&self) -> Option<Ipv4Addr> {
+            self.ipv4.get()
+        }
+
+        fn ipv6(&self) -> Option<Ipv6Addr> {
+            self.ipv6.get()
+        }
+
+        fn mac(&self) -> [u8; 6] {
+            MAC
+        }
+
+        fn is_link_up(&self) -> bool {
+            self.link_up.get()
+        }
+    }
+
+    #[test]
+    fn dual_stack() {
+        let stack = FakeStack::default();
+        stack.ipv4.set(Some(IPV4));
+        stack.ipv6.set(Some(IPV6));
+
+        let conf = netif_conf(&stack).unwrap();
+
+        assert_eq!(conf.ipv4, IPV4);
+        assert_eq!(conf.ipv6, IPV6);
+        assert_eq!(conf.mac, MAC);
+    }
+
+    #[test]
+    fn ipv6_only() {
+        let stack = FakeStack::default();
+        stack.ipv6.set(Some(IPV6));
+
+        let conf = netif_conf(&stack).unwrap();
+
+        assert_eq!(conf.ipv4, Ipv4Addr::UNSPECIFIED);
+        assert_eq!(conf.ipv6, IPV6);
+
+        // No IPv6, no Matter
+        let stack = FakeStack::default();
+        stack.ipv4.set(Some(IPV4));
+
+        assert!(netif_conf(&stack).is_none());
+    }
+
+    #[test]
+    fn changes() {
+        let stack = FakeStack::default();
+        stack.ipv6.set(Some(IPV6));
+        stack.link_up.set(true);
+
+        let last = snapshot(&stack);
+        assert_eq!(snapshot(&stack), last);
+
+        // DHCPv4 completes later
+        stack.ipv4.set(Some(IPV4));
+        assert_ne!(snapshot(&stack), last);
+        let last = snapshot(&stack);
+
+        // DHCPv4 renews with a different address
+        stack.ipv4.set(Some(Ipv4Addr::new(192, 168, 1, 43)));
+        assert_ne!(snapshot(&stack), last);
+        let last = snapshot(&stack);
+
+        // The link goes down, while the configuration stays
+        stack.link_up.set(false);
+        assert_ne!(snapshot(&stack), last);
+    }
+}
@@ -1,21 +1,194 @@
 //! Persistence: `EmbassyPersist` - an implementation of the `Persist` trait that uses the `sequential_storage::map` API
 
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
+use core::fmt::Display;
 use core::ops::Range;
 
-use embedded_storage_async::nor_flash::MultiwriteNorFlash;
+use embedded_storage_async::nor_flash::{MultiwriteNorFlash, ReadNorFlash};
 
 use log::info;
 
-use rs_matter_stack::matter::error::Error;
+use rs_matter_stack::matter::error::{Error, ErrorCode};
 use rs_matter_stack::persist::{Key, KvBlobStore, KvPersist};
 
-use sequential_storage::cache::NoCache;
+use sequential_storage::cache::{KeyCacheImpl, KeyPointerCache, NoCache, PagePointerCache};
 use sequential_storage::map::{SerializationError, Value};
 
-use crate::error::to_persist_error;
+use crate::error::{to_flash_error, to_persist_error};
+use crate::redact::Redacted;
 
-pub type EmbassyPersist<'a, S, N> = KvPersist<'a, EmbassyKvBlobStore<S>, N>;
+pub mod deferred;
+pub mod encrypted;
+pub mod fs;
+pub mod mirror;
+pub mod shared;
+
+pub use shared::SharedKvBlobStore;
+
+pub type EmbassyPersist<'a, S, N, C = NoCache> = KvPersist<'a, EmbassyKvBlobStore<S, C>, N>;
+
+/// The maximum number of keys that `KeyCache` keeps track of.
+///
+/// Sized so that all keys used by the Matter stack fit in the cache, with some room to spare.
+pub const CACHED_KEYS: usize = 8;
+
+/// A type alias for a `sequential_storage` cache that keeps - in RAM - the flash addresses of the
+/// stored items, as well as the state of the flash pages, thus avoiding the need to scan the flash on
+/// each `load` / `store` / `remove` operation.
+///
+/// This is the recommended cache type for `EmbassyKvBlobStore`, as long as a few bytes of RAM
+/// per flash page and per cached key can be spared.
+///
+/// `PAGES` should be equal to the number of flash pages in the flash range used by `EmbassyKvBlobStore`.
+pub type KeyCache<const PAGES: usize> = KeyPointerCache<PAGES, u8, CACHED_KEYS>;
+
+/// A type alias for a `sequential_storage` cache that only keeps track of the state of the flash pages
+/// and the first free address in each page.
+///
+/// Uses less RAM than `KeyCache`, but the flash still needs to be scanned when loading an item.
+///
+/// `PAGES` should be equal to the number of flash pages in the flash range used by `EmbassyKvBlobStore`.
+pub type PageCache<const PAGES: usize> = PagePointerCache<PAGES>;
+
+/// The schema version assumed for flash ranges without a schema version record,
+/// i.e. ones written before schema versioning was introduced, or empty ones.
+pub const UNVERSIONED: u32 = 0;
+
+/// The key of the schema version record; disjoint from the keys used by the Matter stack
+const SCHEMA_VERSION_KEY: u8 = 0xff;
+
+/// The first raw key of the application namespace; keys below it are reserved for the Matter stack
+const APP_KEY_BASE: u8 = 0x80;
+
+/// The number of keys available in the application namespace
+pub const MAX_APP_KEYS: u8 = SCHEMA_VERSION_KEY - APP_KEY_BASE;
+
+/// A key in the application namespace of `EmbassyKvBlobStore`.
+///
+/// Application keys are disjoint from the keys used by the Matter stack (`rs_matter_stack::persist::Key`),
+/// so that application data (i.e. scenes, calibration, user preferences) can be stored in the same flash range
+/// as the Matter state, and benefit from the same wear-levelling.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct AppKey(u8);
+
+impl AppKey {
+    /// Create a new application key.
+    ///
+    /// # Panics
+    /// If `id` is not less than `MAX_APP_KEYS`.
+    pub const fn new(id: u8) -> Self {
+        assert!(id < MAX_APP_KEYS, "Application key out of range");
+
+        Self(id)
+    }
+
+    /// Return the ID of the application key, as provided to `new`.
+    pub const fn id(&self) -> u8 {
+        self.0
+    }
+
+    const fn raw(&self) -> u8 {
+        APP_KEY_BASE + self.0
+    }
+}
+
+impl Display for AppKey {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "App({})", self.0)
+    }
+}
+
+/// Storage usage statistics of an `EmbassyKvBlobStore` flash range, see `EmbassyKvBlobStore::stats`.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct PersistStats {
+    /// The size of the flash range
+    pub total_bytes: usize,
+    /// The bytes which cannot be written without erasing a page, including stale (overwritten or removed) items
+    pub used_bytes: usize,
+    /// The bytes which can still be written without erasing a page
+    pub free_bytes: usize,
+    /// The number of pages in the flash range
+    pub pages: usize,
+    /// The number of fully erased pages
+    pub erased_pages: usize,
+    /// The number of blobs stored by the Matter stack
+    pub matter_blobs: usize,
+    /// The total size of the blobs stored by the Matter stack
+    pub matter_bytes: usize,
+    /// The number of blobs stored in the application namespace
+    pub app_blobs: usize,
+    /// The total size of the blobs stored in the application namespace
+    pub app_bytes: usize,
+}
+
+/// What `EmbassyKvBlobStore::factory_reset` erases.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ResetScope {
+    /// Only the Matter state (fabrics, networks, etc.); application data and the schema version are kept
+    Matter,
+    /// The whole flash range, including the application data
+    All,
+}
+
+/// A migration of the blobs stored by `EmbassyKvBlobStore` from one schema version to another.
+///
+/// See `EmbassyKvBlobStore::migrate`.
+pub trait Migration {
+    /// Migrate the blobs in `kvs` from schema version `from` to schema version `to`.
+    ///
+    /// `from` is `UNVERSIONED` for flash ranges written before schema versioning was introduced.
+    /// Note that `from` might also be greater than `to`, if the firmware was downgraded.
+    ///
+    /// Migrations MUST be idempotent: the new schema version is only recorded once the migration completes,
+    /// so - if the power is lost in the meantime - the migration is run again on the next startup, on blobs
+    /// which might already be (fully or partially) migrated. Use e.g. a marker in the migrated blobs
+    /// to skip the ones which are already in the new layout.
+    ///
+    /// `buf` is a scratch buffer, which is big enough for loading and storing any of the blobs.
+    async fn migrate<K>(
+        &mut self,
+        kvs: &mut K,
+        buf: &mut [u8],
+        from: u32,
+        to: u32,
+    ) -> Result<(), Error>
+    where
+        K: KvBlobStore;
+}
+
+impl<T> Migration for &mut T
+where
+    T: Migration,
+{
+    async fn migrate<K>(
+        &mut self,
+        kvs: &mut K,
+        buf: &mut [u8],
+        from: u32,
+        to: u32,
+    ) -> Result<(), Error>
+    where
+        K: KvBlobStore,
+    {
+        (*self).migrate(kvs, buf, from, to).await
+    }
+}
+
+/// A no-op migration, for when the layout of the stored blobs is compatible across schema versions.
+impl Migration for () {
+    async fn migrate<K>(
+        &mut self,
+        _kvs: &mut K,
+        _buf: &mut [u8],
+        _from: u32,
+        _to: u32,
+    ) -> Result<(), Error>
+    where
+        K: KvBlobStore,
+    {
+        Ok(())
+    }
+}
 
 /// We expect closures, but `sequential_storage::map` operates on `Value` instances
 /// (which is less flexible).
@@ -24,18 +197,25 @@ pub type EmbassyPersist<'a, S, N> = KvPersist<'a, EmbassyKvBlobStore<S>, N>;
 /// Only used during serialization.
 ///
 /// (For deserialization, we take advantage of zero-copy, and pass `&[u8]` as `Value`.)
-struct StoreValue<F>(Key, RefCell<Option<F>>);
+///
+/// Since `sequential_storage` can only report a generic serialization error, the error returned by the closure
+/// is stashed in the value, so that it can be reported as-is.
+struct StoreValue<D, F>(D, RefCell<Option<F>>, Cell<Option<Error>>);
 
-impl<'d, F> Value<'d> for StoreValue<F>
+impl<'d, D, F> Value<'d> for StoreValue<D, F>
 where
+    D: Display,
     F: FnOnce(&mut [u8]) -> Result<usize, Error>,
 {
     fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
         let f = self.1.borrow_mut().take().unwrap();
 
-        let len = f(buffer).map_err(|_| SerializationError::InvalidData)?; // TODO
+        let len = f(buffer).map_err(|err| {
+            self.2.set(Some(err));
+            SerializationError::InvalidData
+        })?;
 
-        info!("Blob {}: stored {} bytes {:?}", self.0, len, &buffer[..len]);
+        info!("Blob {}: stored {}", self.0, Redacted(&buffer[..len]));
 
         Ok(len)
     }
@@ -51,89 +231,423 @@ where
 
 /// A `KvBlobStore`` implementation that uses the `sequential_storage::map` API
 /// on top of NOR Flash.
-pub struct EmbassyKvBlobStore<S> {
+///
+/// By default, no caching is done, so every operation scans the flash range.
+/// Use `new_with_cache` with a `KeyCache` to speed things up; this is recommended for all but
+/// the most RAM-constrained devices.
+///
+/// `NoCache` remains the default, because the page-indexed caches need the number of flash pages
+/// as a const generic (which `new` cannot infer from the flash range), and because a cache which
+/// does not match the flash range would panic, rather than just be slow.
+pub struct EmbassyKvBlobStore<S, C = NoCache> {
     flash: S,
     flash_range: Range<u32>,
-    cache: NoCache,
+    cache: C,
 }
 
 impl<S> EmbassyKvBlobStore<S>
 where
     S: MultiwriteNorFlash,
 {
-    /// Create a new KV blob store instance.
+    /// Create a new KV blob store instance that does not use a cache.
     pub fn new(flash: S, flash_range: Range<u32>) -> Self {
+        Self::new_with_cache(flash, flash_range, NoCache::new())
+    }
+}
+
+impl<S, C> EmbassyKvBlobStore<S, C>
+where
+    S: MultiwriteNorFlash,
+    C: KeyCacheImpl<u8>,
+{
+    /// Create a new KV blob store instance that uses the provided cache.
+    ///
+    /// The cache should be freshly created, i.e. it should not be shared with or
+    /// re-used from another KV blob store instance.
+    pub fn new_with_cache(flash: S, flash_range: Range<u32>, cache: C) -> Self {
         Self {
             flash,
             flash_range,
-            cache: NoCache::new(),
+            cache,
+        }
+    }
+
+    /// Return the schema version recorded in the flash range, or `None` if there is no schema version record.
+    pub async fn schema_version(&mut self, buf: &mut [u8]) -> Result<Option<u32>, Error> {
+        let mut version = None;
+
+        self.load_raw(SCHEMA_VERSION_KEY, "schema-version", buf, |data| {
+            if let Some(data) = data {
+                let data = data.try_into().map_err(|_| ErrorCode::InvalidData)?;
+                version = Some(u32::from_le_bytes(data));
+            }
+
+            Ok(())
+        })
+        .await?;
+
+        Ok(version)
+    }
+
+    /// Bring the blobs stored in the flash range to schema version `version`.
+    ///
+    /// If the schema version recorded in the flash range is different from `version`, `migration` is run
+    /// and - once it succeeds - `version` is recorded as the new schema version. Otherwise, this is a no-op.
+    ///
+    /// As the migration is re-run if interrupted before the schema version is recorded, it must be idempotent
+    /// (see `Migration::migrate`).
+    ///
+    /// Should be called on startup, before the Matter stack loads its state from the store
+    /// (i.e. before `KvPersist::load`), with the schema version of the current firmware.
+    pub async fn migrate<M>(
+        &mut self,
+        version: u32,
+        mut migration: M,
+        buf: &mut [u8],
+    ) -> Result<(), Error>
+    where
+        M: Migration,
+    {
+        let stored = self.schema_version(buf).await?.unwrap_or(UNVERSIONED);
+
+        if stored != version {
+            info!("Migrating the persisted blobs from schema version {stored} to {version}");
+
+            migration.migrate(self, buf, stored, version).await?;
+
+            self.store_raw(SCHEMA_VERSION_KEY, "schema-version", buf, |buf| {
+                let data = version.to_le_bytes();
+
+                buf.get_mut(..data.len())
+                    .ok_or(ErrorCode::NoSpace)?
+                    .copy_from_slice(&data);
+
+                Ok(data.len())
+            })
+            .await?;
+
+            info!("Migration to schema version {version} complete");
+        }
+
+        Ok(())
+    }
+
+    /// Return the length of the blob stored under `key`, or `None` if there is no blob stored under `key`.
+    pub async fn blob_len(&mut self, key: Key, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+        self.raw_len(key as u8, buf).await
+    }
+
+    /// Return the storage usage statistics of the flash range.
+    ///
+    /// The whole flash range is read, so this is an expensive operation
+    /// and should only be done occasionally, i.e. for diagnostics.
+    ///
+    /// For erase counts, wrap the flash in a `flash::WearFlash`.
+    pub async fn stats(&mut self, buf: &mut [u8]) -> Result<PersistStats, Error> {
+        let page_size = S::ERASE_SIZE;
+        let chunk_size = buf.len().min(page_size) / S::READ_SIZE * S::READ_SIZE;
+        if chunk_size == 0 {
+            return Err(ErrorCode::BufferTooSmall.into());
+        }
+
+        let mut stats = PersistStats {
+            total_bytes: self.flash_range.len(),
+            ..Default::default()
+        };
+
+        // `sequential_storage` fills the pages sequentially, so everything after the last
+        // non-erased byte of a page can still be written
+        for page in self.flash_range.clone().step_by(page_size) {
+            let mut used = 0;
+
+            for offset in (0..page_size).step_by(chunk_size) {
+                let chunk = &mut buf[..chunk_size.min(page_size - offset)];
+
+                self.flash
+                    .read(page + offset as u32, chunk)
+                    .await
+                    .map_err(to_flash_error)?;
+
+                if let Some(index) = chunk.iter().rposition(|b| *b != 0xff) {
+                    used = offset + index + 1;
+                }
+            }
+
+            stats.pages += 1;
+            stats.used_bytes += used;
+            stats.free_bytes += page_size - used;
+
+            if used == 0 {
+                stats.erased_pages += 1;
+            }
+        }
+
+        // The length of the newest version of each stored item, collected in a single pass over the map.
+        // The map does not erase the stale versions of overwritten items, so the iteration might return
+        // the same key more than once, with the newest version last
+        let mut lens = [None::<u16>; 256];
+
+        let mut items = sequential_storage::map::fetch_all_items(
+            &mut self.flash,
+            self.flash_range.clone(),
+            &mut self.cache,
+            buf,
+        )
+        .await
+        .map_err(to_persist_error)?;
+
+        loop {
+            let item: Option<(u8, &[u8])> = items.next(buf).await.map_err(to_persist_error)?;
+
+            let Some((key, data)) = item else {
+                break;
+            };
+
+            lens[key as usize] = Some(data.len() as u16);
+        }
+
+        for (key, len) in lens.iter().enumerate().take(SCHEMA_VERSION_KEY as usize) {
+            let Some(len) = len.map(|len| len as usize) else {
+                continue;
+            };
+
+            if key < APP_KEY_BASE as usize {
+                stats.matter_blobs += 1;
+                stats.matter_bytes += len;
+            } else {
+                stats.app_blobs += 1;
+                stats.app_bytes += len;
+            }
+        }
+
+        info!("Persistence stats: {stats:?}");
+
+        Ok(stats)
+    }
+
+    /// Erase the persisted state, as per `scope`.
+    ///
+    /// With `ResetScope::All`, the flash range is physically erased, so nothing persisted remains in the flash.
+    /// With `ResetScope::Matter`, the Matter blobs are removed from the map, but their (stale) content
+    /// only disappears from the flash once the map garbage-collects the pages where it lives.
+    /// The fabrics are removed last, so if the reset is interrupted, the device still has its fabrics
+    /// (and the reset can be redone) unless all other Matter blobs are gone already.
+    ///
+    /// The Matter stack keeps its state in RAM, so after a factory reset the device should be restarted
+    /// (or at least the Matter stack should be re-created). If the Matter stack is running, the store
+    /// should be shared with it via `SharedKvBlobStore`, so that the reset does not interleave with a store
+    /// operation of the stack.
+    pub async fn factory_reset(&mut self, scope: ResetScope, buf: &mut [u8]) -> Result<(), Error>
+    where
+        C: Default,
+    {
+        match scope {
+            ResetScope::Matter => {
+                // The Matter keys actually stored, collected in a single pass over the map,
+                // so that only these are removed
+                let mut stored = [false; APP_KEY_BASE as usize];
+
+                {
+                    let mut items = sequential_storage::map::fetch_all_items(
+                        &mut self.flash,
+                        self.flash_range.clone(),
+                        &mut self.cache,
+                        buf,
+                    )
+                    .await
+                    .map_err(to_persist_error)?;
+
+                    loop {
+                        let item: Option<(u8, &[u8])> =
+                            items.next(buf).await.map_err(to_persist_error)?;
+
+                        let Some((key, _)) = item else {
+                            break;
+                        };
+
+                        if key < APP_KEY_BASE {
+                            stored[key as usize] = true;
+                        }
+                    }
+                }
+
+                // The fabrics go last, so that a reset interrupted halfway never leaves a device
+                // which looks decommissioned, yet still has some of its Matter state
+                let fabrics = Key::Fabrics as u8;
+
+                let keys = (0..APP_KEY_BASE)
+                    .filter(|key| *key != fabrics)
+                    .chain(core::iter::once(fabrics))
+                    .filter(|key| stored[*key as usize]);
+
+                for key in keys {
+                    sequential_storage::map::remove_item(
+                        &mut self.flash,
+                        self.flash_range.clone(),
+                        &mut self.cache,
+                        buf,
+                        &key,
+                    )
+                    .await
+                    .map_err(to_persist_error)?;
+                }
+            }
+            ResetScope::All => {
+                // The cache is out of sync with the flash after the erase, so start with a fresh one
+                self.cache = C::default();
+
+                sequential_storage::erase_all(&mut self.flash, self.flash_range.clone())
+                    .await
+                    .map_err(to_persist_error)?;
+            }
         }
+
+        info!("Factory reset ({scope:?}) complete");
+
+        Ok(())
+    }
+
+    /// Load the application blob stored under `key`.
+    ///
+    /// `cb` is called with the blob, or with `None` if there is no blob stored under `key`.
+    /// `buf` should be big enough to hold the blob.
+    pub async fn load_app<F>(&mut self, key: AppKey, buf: &mut [u8], cb: F) -> Result<(), Error>
+    where
+        F: FnOnce(Option<&[u8]>) -> Result<(), Error>,
+    {
+        self.load_raw(key.raw(), key, buf, cb).await
+    }
+
+    /// Store an application blob under `key`.
+    ///
+    /// `cb` should serialize the blob in the provided buffer and return its length.
+    pub async fn store_app<F>(&mut self, key: AppKey, buf: &mut [u8], cb: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut [u8]) -> Result<usize, Error>,
+    {
+        self.store_raw(key.raw(), key, buf, cb).await
+    }
+
+    /// Remove the application blob stored under `key`, if any.
+    pub async fn remove_app(&mut self, key: AppKey, buf: &mut [u8]) -> Result<(), Error> {
+        self.remove_raw(key.raw(), key, buf).await
     }
 
     async fn load<F>(&mut self, key: Key, buf: &mut [u8], cb: F) -> Result<(), Error>
     where
         F: FnOnce(Option<&[u8]>) -> Result<(), Error>,
+    {
+        self.load_raw(key as u8, key, buf, cb).await
+    }
+
+    async fn store<F>(&mut self, key: Key, buf: &mut [u8], cb: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut [u8]) -> Result<usize, Error>,
+    {
+        self.store_raw(key as u8, key, buf, cb).await
+    }
+
+    async fn remove(&mut self, key: Key, buf: &mut [u8]) -> Result<(), Error> {
+        self.remove_raw(key as u8, key, buf).await
+    }
+
+    async fn raw_len(&mut self, key: u8, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+        let data: Option<&[u8]> = sequential_storage::map::fetch_item(
+            &mut self.flash,
+            self.flash_range.clone(),
+            &mut self.cache,
+            buf,
+            &key,
+        )
+        .await
+        .map_err(to_persist_error)?;
+
+        Ok(data.map(|data| data.len()))
+    }
+
+    async fn load_raw<D, F>(
+        &mut self,
+        key: u8,
+        label: D,
+        buf: &mut [u8],
+        cb: F,
+    ) -> Result<(), Error>
+    where
+        D: Display,
+        F: FnOnce(Option<&[u8]>) -> Result<(), Error>,
     {
         let data: Option<&[u8]> = sequential_storage::map::fetch_item(
             &mut self.flash,
             self.flash_range.clone(),
             &mut self.cache,
             buf,
-            &[key as u8],
+            &key,
         )
         .await
         .map_err(to_persist_error)?;
 
         cb(data)?;
 
-        info!(
-            "Blob {key}: loaded {:?} bytes {data:?}",
-            data.map(|data| data.len())
-        );
+        if let Some(data) = data {
+            info!("Blob {label}: loaded {}", Redacted(data));
+        } else {
+            info!("Blob {label}: not found");
+        }
 
         Ok(())
     }
 
-    async fn store<F>(&mut self, key: Key, buf: &mut [u8], cb: F) -> Result<(), Error>
+    async fn store_raw<D, F>(
+        &mut self,
+        key: u8,
+        label: D,
+        buf: &mut [u8],
+        cb: F,
+    ) -> Result<(), Error>
     where
+        D: Display,
         F: FnOnce(&mut [u8]) -> Result<usize, Error>,
     {
-        let value = StoreValue(key, RefCell::new(Some(cb)));
+        let value = StoreValue(label, RefCell::new(Some(cb)), Cell::new(None));
 
         sequential_storage::map::store_item(
             &mut self.flash,
             self.flash_range.clone(),
             &mut self.cache,
             buf,
-            &(key as u8),
+            &key,
             &value,
         )
         .await
-        .map_err(to_persist_error)?;
+        .map_err(|err| value.2.take().unwrap_or_else(|| to_persist_error(err)))?;
 
         Ok(())
     }
 
-    async fn remove(&mut self, key: Key, buf: &mut [u8]) -> Result<(), Error> {
+    async fn remove_raw<D>(&mut self, key: u8, label: D, buf: &mut [u8]) -> Result<(), Error>
+    where
+        D: Display,
+    {
         sequential_storage::map::remove_item(
             &mut self.flash,
             self.flash_range.clone(),
             &mut self.cache,
             buf,
-            &[key as u8],
+            &key,
         )
         .await
         .map_err(to_persist_error)?;
 
-        info!("Blob {key}: removed");
+        info!("Blob {label}: removed");
 
         Ok(())
     }
 }
 
-impl<S> KvBlobStore for EmbassyKvBlobStore<S>
+impl<S, C> KvBlobStore for EmbassyKvBlobStore<S, C>
 where
     S: MultiwriteNorFlash,
+    C: KeyCacheImpl<u8>,
 {
     async fn load<F>(&mut self, key: Key, buf: &mut [u8], f: F) -> Result<(), Error>
     where
@@ -153,3 +667,896 @@ where
         EmbassyKvBlobStore::remove(self, key, buf).await
     }
 }
+
+#[cfg(test)]
+mod test {
+    use embassy_futures::block_on;
+
+    use rs_matter_stack::matter::error::ErrorCode;
+    use rs_matter_stack::matter::utils::storage::Vec;
+    use rs_matter_stack::persist::{Key, KvBlobStore};
+
+    use crate::flash::{RamFlash, RamFlashOp};
+
+    use super::{
+        AppKey, EmbassyKvBlobStore, KeyCache, Migration, PageCache, ResetScope, MAX_APP_KEYS,
+        UNVERSIONED,
+    };
+
+    use crate::flash::WearFlash;
+
+    /// All keys used by the Matter stack
+    pub(crate) const KEYS: &[Key] = &[Key::Fabrics, Key::BasicInfo, Key::Networks];
+
+    pub(crate) const BUF_SIZE: usize = 1024;
+
+    pub(crate) type TestFlash = RamFlash<{ 4 * 4096 }>;
+
+    /// Generate a test blob, which is unique for the key and the "generation"
+    pub(crate) fn blob(key: Key, gen: u8, len: usize) -> Vec<u8, BUF_SIZE> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31) ^ (key as u8) ^ gen.wrapping_mul(97))
+            .collect()
+    }
+
+    pub(crate) async fn store<S: KvBlobStore>(kvs: &mut S, key: Key, data: &[u8]) {
+        let mut buf = [0; BUF_SIZE];
+
+        kvs.store(key, &mut buf, |buf| {
+            buf[..data.len()].copy_from_slice(data);
+            Ok(data.len())
+        })
+        .await
+        .unwrap();
+    }
+
+    pub(crate) async fn load<S: KvBlobStore>(kvs: &mut S, key: Key) -> Option<Vec<u8, BUF_SIZE>> {
+        let mut buf = [0; BUF_SIZE];
+        let mut result = None;
+
+        kvs.load(key, &mut buf, |data| {
+            result = data.map(|data| Vec::from_slice(data).unwrap());
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        result
+    }
+
+    pub(crate) async fn remove<S: KvBlobStore>(kvs: &mut S, key: Key) {
+        let mut buf = [0; BUF_SIZE];
+
+        kvs.remove(key, &mut buf).await.unwrap();
+    }
+
+    #[test]
+    fn load_store_remove() {
+        let mut flash = TestFlash::new();
+        let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+
+        block_on(async {
+            for key in KEYS {
+                assert_eq!(load(&mut kvs, *key).await, None);
+            }
+
+            for key in KEYS {
+                store(&mut kvs, *key, &blob(*key, 0, 100)).await;
+            }
+
+            for key in KEYS {
+                assert_eq!(load(&mut kvs, *key).await, Some(blob(*key, 0, 100)));
+            }
+
+            for key in KEYS {
+                remove(&mut kvs, *key).await;
+                assert_eq!(load(&mut kvs, *key).await, None);
+            }
+        });
+    }
+
+    /// Round-trip the Matter state through `KvPersist` (the way the Matter stack uses the store):
+    /// whatever one stack instance stores should load in a fresh one after a "reboot", and `reset`
+    /// should remove the blobs of all keys.
+    #[test]
+    fn persist() {
+        extern crate std;
+
+        use std::boxed::Box;
+
+        use rs_matter_stack::matter::data_model::cluster_basic_information::BasicInfoConfig;
+        use rs_matter_stack::matter::utils::init::InitMaybeUninit;
+        use rs_matter_stack::test_device::{
+            TEST_BASIC_COMM_DATA, TEST_DEV_ATT, TEST_PID, TEST_VID,
+        };
+        use rs_matter_stack::MdnsType;
+
+        use crate::epoch::epoch;
+        use crate::EmbassyEthMatterStack;
+
+        use super::EmbassyPersist;
+
+        const BASIC_INFO: BasicInfoConfig<'static> = BasicInfoConfig {
+            vid: TEST_VID,
+            pid: TEST_PID,
+            hw_ver: 2,
+            sw_ver: 1,
+            sw_ver_str: "1",
+            serial_no: "aabbccdd",
+            device_name: "MyLight",
+            product_name: "ACME Light",
+            vendor_name: "ACME",
+        };
+
+        fn rand(buf: &mut [u8]) {
+            buf.fill(0x5a);
+        }
+
+        fn new_stack() -> &'static EmbassyEthMatterStack<'static, ()> {
+            Box::leak(Box::new_uninit()).init_with(EmbassyEthMatterStack::<()>::init(
+                &BASIC_INFO,
+                TEST_BASIC_COMM_DATA,
+                &TEST_DEV_ATT,
+                MdnsType::Builtin,
+                epoch,
+                rand,
+            ))
+        }
+
+        let mut flash = TestFlash::new();
+
+        block_on(async {
+            let mut persist = EmbassyPersist::new_eth(
+                EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096),
+                new_stack(),
+            );
+
+            // Nothing to load from an empty store
+            persist.load().await.unwrap();
+            persist.store().await.unwrap();
+        });
+
+        let mut stored = std::vec::Vec::new();
+
+        block_on(async {
+            let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+
+            for key in KEYS {
+                stored.push(load(&mut kvs, *key).await);
+            }
+        });
+
+        block_on(async {
+            // "Reboot"
+            let mut persist = EmbassyPersist::new_eth(
+                EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096),
+                new_stack(),
+            );
+
+            persist.load().await.unwrap();
+
+            // The loaded state is unchanged, so storing it should not change what is persisted
+            persist.store().await.unwrap();
+        });
+
+        block_on(async {
+            let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+
+            for (key, stored) in KEYS.iter().zip(&stored) {
+                assert_eq!(&load(&mut kvs, *key).await, stored, "Key {key}");
+            }
+
+            // Blobs for all keys, including the networks one, which is not used by Ethernet stacks
+            for key in KEYS {
+                store(&mut kvs, *key, &blob(*key, 0, 100)).await;
+            }
+        });
+
+        block_on(async {
+            let mut persist = EmbassyPersist::new_eth(
+                EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096),
+                new_stack(),
+            );
+
+            persist.reset().await.unwrap();
+        });
+
+        block_on(async {
+            let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+
+            for key in KEYS {
+                assert_eq!(load(&mut kvs, *key).await, None, "Key {key}");
+            }
+        });
+    }
+
+    #[test]
+    fn app_keys() {
+        const APP_KEYS: &[AppKey] = &[
+            AppKey::new(0),
+            AppKey::new(1),
+            AppKey::new(MAX_APP_KEYS - 1),
+        ];
+
+        let mut flash = TestFlash::new();
+        let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+
+        let mut buf = [0; BUF_SIZE];
+
+        block_on(async {
+            for key in KEYS {
+                store(&mut kvs, *key, &blob(*key, 0, 100)).await;
+            }
+
+            for (index, key) in APP_KEYS.iter().enumerate() {
+                kvs.store_app(*key, &mut buf, |buf| {
+                    buf[..10].fill(index as u8);
+                    Ok(10)
+                })
+                .await
+                .unwrap();
+            }
+
+            // Removing the Matter state should leave the application data intact, and vice versa
+            for key in KEYS {
+                remove(&mut kvs, *key).await;
+            }
+
+            for (index, key) in APP_KEYS.iter().enumerate() {
+                kvs.load_app(*key, &mut buf, |data| {
+                    assert_eq!(data, Some([index as u8; 10].as_slice()));
+                    Ok(())
+                })
+                .await
+                .unwrap();
+
+                kvs.remove_app(*key, &mut buf).await.unwrap();
+
+                kvs.load_app(*key, &mut buf, |data| {
+                    assert_eq!(data, None);
+                    Ok(())
+                })
+                .await
+                .unwrap();
+            }
+
+            for key in KEYS {
+                store(&mut kvs, *key, &blob(*key, 1, 100)).await;
+            }
+
+            kvs.remove_app(APP_KEYS[0], &mut buf).await.unwrap();
+
+            for key in KEYS {
+                assert_eq!(load(&mut kvs, *key).await, Some(blob(*key, 1, 100)));
+            }
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn app_key_out_of_range() {
+        AppKey::new(MAX_APP_KEYS);
+    }
+
+    #[test]
+    fn factory_reset() {
+        const APP_KEY: AppKey = AppKey::new(0);
+
+        let mut flash = TestFlash::new();
+        let mut kvs =
+            EmbassyKvBlobStore::new_with_cache(&mut flash, 0..4 * 4096, KeyCache::<4>::new());
+
+        let mut buf = [0; BUF_SIZE];
+
+        block_on(async {
+            kvs.migrate(1, (), &mut buf).await.unwrap();
+
+            for key in KEYS {
+                store(&mut kvs, *key, &blob(*key, 0, 100)).await;
+            }
+
+            kvs.store_app(APP_KEY, &mut buf, |buf| {
+                buf[..10].fill(0x55);
+                Ok(10)
+            })
+            .await
+            .unwrap();
+
+            kvs.factory_reset(ResetScope::Matter, &mut buf)
+                .await
+                .unwrap();
+
+            for key in KEYS {
+                assert_eq!(load(&mut kvs, *key).await, None);
+            }
+
+            kvs.load_app(APP_KEY, &mut buf, |data| {
+                assert_eq!(data, Some([0x55; 10].as_slice()));
+                Ok(())
+            })
+            .await
+            .unwrap();
+            assert_eq!(kvs.schema_version(&mut buf).await.unwrap(), Some(1));
+
+            for key in KEYS {
+                store(&mut kvs, *key, &blob(*key, 1, 100)).await;
+            }
+
+            kvs.factory_reset(ResetScope::All, &mut buf).await.unwrap();
+
+            for key in KEYS {
+                assert_eq!(load(&mut kvs, *key).await, None);
+            }
+
+            kvs.load_app(APP_KEY, &mut buf, |data| {
+                assert_eq!(data, None);
+                Ok(())
+            })
+            .await
+            .unwrap();
+            assert_eq!(kvs.schema_version(&mut buf).await.unwrap(), None);
+
+            // The store should be usable after the reset
+            for key in KEYS {
+                store(&mut kvs, *key, &blob(*key, 2, 100)).await;
+                assert_eq!(load(&mut kvs, *key).await, Some(blob(*key, 2, 100)));
+            }
+        });
+    }
+
+    #[test]
+    fn factory_reset_power_loss() {
+        for cut in 0.. {
+            let mut flash = TestFlash::new();
+
+            let reset = block_on(async {
+                let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+
+                for key in KEYS {
+                    store(&mut kvs, *key, &blob(*key, 0, 100)).await;
+                }
+
+                kvs.flash.cut_power_after(cut);
+
+                let mut buf = [0; BUF_SIZE];
+
+                kvs.factory_reset(ResetScope::Matter, &mut buf)
+                    .await
+                    .is_ok()
+            });
+
+            // "Reboot"
+            flash.restore_power();
+
+            block_on(async {
+                let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+
+                if load(&mut kvs, Key::Fabrics).await.is_none() {
+                    for key in KEYS {
+                        assert_eq!(
+                            load(&mut kvs, *key).await,
+                            None,
+                            "Key {key}, cut point {cut}: left behind without fabrics"
+                        );
+                    }
+                } else {
+                    assert!(!reset, "Cut point {cut}: fabrics left behind");
+                }
+            });
+
+            if reset {
+                // No more cut points to try, as the reset did not hit the power cut
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn stats() {
+        let mut flash = WearFlash::<_, 4>::new(TestFlash::new(), 0);
+        let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+
+        let mut buf = [0; BUF_SIZE];
+
+        block_on(async {
+            let stats = kvs.stats(&mut buf).await.unwrap();
+            assert_eq!(stats.total_bytes, 4 * 4096);
+            assert_eq!(stats.free_bytes, 4 * 4096);
+            assert_eq!(stats.erased_pages, 4);
+            assert_eq!(stats.matter_blobs, 0);
+
+            store(&mut kvs, Key::Fabrics, &blob(Key::Fabrics, 0, 100)).await;
+            store(&mut kvs, Key::Networks, &blob(Key::Networks, 0, 200)).await;
+            kvs.store_app(AppKey::new(3), &mut buf, |buf| {
+                buf[..50].fill(0);
+                Ok(50)
+            })
+            .await
+            .unwrap();
+
+            assert_eq!(
+                kvs.blob_len(Key::Fabrics, &mut buf).await.unwrap(),
+                Some(100)
+            );
+            assert_eq!(kvs.blob_len(Key::BasicInfo, &mut buf).await.unwrap(), None);
+
+            let stats = kvs.stats(&mut buf).await.unwrap();
+            assert_eq!(stats.matter_blobs, 2);
+            assert_eq!(stats.matter_bytes, 300);
+            assert_eq!(stats.app_blobs, 1);
+            assert_eq!(stats.app_bytes, 50);
+            assert!(stats.used_bytes >= 350);
+            assert_eq!(stats.used_bytes + stats.free_bytes, stats.total_bytes);
+            assert!(stats.erased_pages < 4);
+
+            // Enough overwrites so that pages get erased
+            for gen in 0..100 {
+                store(&mut kvs, Key::Fabrics, &blob(Key::Fabrics, gen, 500)).await;
+            }
+
+            let stats = kvs.stats(&mut buf).await.unwrap();
+            assert_eq!(stats.matter_blobs, 2);
+            assert_eq!(stats.matter_bytes, 700);
+        });
+
+        assert!(flash.total_erases() > 0);
+        assert_eq!(flash.total_erases(), flash.flash().stats().erases as u32);
+    }
+
+    #[test]
+    fn overwrite() {
+        let mut flash = TestFlash::new();
+        let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+
+        block_on(async {
+            // Enough generations so that the map has to wrap around and garbage-collect pages
+            for gen in 0..100 {
+                for key in KEYS {
+                    store(&mut kvs, *key, &blob(*key, gen, 200 + gen as usize)).await;
+                }
+
+                for key in KEYS {
+                    assert_eq!(
+                        load(&mut kvs, *key).await,
+                        Some(blob(*key, gen, 200 + gen as usize))
+                    );
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn reload() {
+        let mut flash = TestFlash::new();
+
+        block_on(async {
+            let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+
+            for key in KEYS {
+                store(&mut kvs, *key, &blob(*key, 0, 300)).await;
+            }
+
+            // "Reboot"
+            let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+
+            for key in KEYS {
+                assert_eq!(load(&mut kvs, *key).await, Some(blob(*key, 0, 300)));
+            }
+        });
+    }
+
+    /// Cut the power at every possible step (word write or page erase) during a `store` and check that -
+    /// after a reboot - either the old or the new blob is loaded, but never a corrupted one.
+    ///
+    /// Run once on a mostly empty flash, and once on a flash which is full enough for the `store`
+    /// to trigger garbage collection (i.e. migration of items to a new page).
+    #[test]
+    fn power_loss() {
+        for prefill in [1, 30] {
+            for key in KEYS {
+                for cut in 0.. {
+                    let mut flash = TestFlash::new();
+
+                    let stored = block_on(async {
+                        let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+
+                        for gen in 0..prefill {
+                            for key in KEYS {
+                                store(&mut kvs, *key, &blob(*key, gen, 300)).await;
+                            }
+                        }
+
+                        kvs.flash.cut_power_after(cut);
+
+                        let mut buf = [0; BUF_SIZE];
+                        let new = blob(*key, prefill, 300);
+
+                        kvs.store(*key, &mut buf, |buf| {
+                            buf[..new.len()].copy_from_slice(&new);
+                            Ok(new.len())
+                        })
+                        .await
+                        .is_ok()
+                    });
+
+                    // "Reboot"
+                    flash.restore_power();
+
+                    block_on(async {
+                        let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+
+                        let old = blob(*key, prefill - 1, 300);
+                        let new = blob(*key, prefill, 300);
+
+                        let loaded = load(&mut kvs, *key).await.unwrap();
+                        assert!(
+                            loaded == new || (!stored && loaded == old),
+                            "Key {key}, cut point {cut}: corrupted blob"
+                        );
+
+                        for other in KEYS.iter().filter(|other| *other != key) {
+                            assert_eq!(
+                                load(&mut kvs, *other).await,
+                                Some(blob(*other, prefill - 1, 300)),
+                                "Key {other}, cut point {cut}: corrupted blob"
+                            );
+                        }
+
+                        // The store should be usable after the power loss
+                        let newer = blob(*key, prefill + 1, 300);
+                        store(&mut kvs, *key, &newer).await;
+                        assert_eq!(load(&mut kvs, *key).await, Some(newer));
+                    });
+
+                    if stored {
+                        // No more cut points to try, as the `store` did not hit the power cut
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Compare the flash operations needed for a typical workload
+    /// (a few stores, followed by many loads) with and without a cache.
+    #[test]
+    fn cache() {
+        async fn workload<S: KvBlobStore>(kvs: &mut S) {
+            for gen in 0..10 {
+                for key in KEYS {
+                    store(kvs, *key, &blob(*key, gen, 300)).await;
+                }
+            }
+
+            for _ in 0..10 {
+                for key in KEYS {
+                    assert_eq!(load(kvs, *key).await, Some(blob(*key, 9, 300)));
+                }
+            }
+        }
+
+        let mut no_cache = TestFlash::new();
+        block_on(workload(&mut EmbassyKvBlobStore::new(
+            &mut no_cache,
+            0..4 * 4096,
+        )));
+
+        let mut page_cache = TestFlash::new();
+        block_on(workload(&mut EmbassyKvBlobStore::new_with_cache(
+            &mut page_cache,
+            0..4 * 4096,
+            PageCache::<4>::new(),
+        )));
+
+        let mut key_cache = TestFlash::new();
+        block_on(workload(&mut EmbassyKvBlobStore::new_with_cache(
+            &mut key_cache,
+            0..4 * 4096,
+            KeyCache::<4>::new(),
+        )));
+
+        // The cache does not change what is written
+        assert_eq!(no_cache.data(), key_cache.data());
+        assert_eq!(no_cache.data(), page_cache.data());
+        assert_eq!(no_cache.stats().writes, key_cache.stats().writes);
+        assert_eq!(no_cache.stats().erases, key_cache.stats().erases);
+
+        // ... but it saves reads
+        assert!(page_cache.stats().reads < no_cache.stats().reads);
+        assert!(key_cache.stats().reads < page_cache.stats().reads);
+        assert!(page_cache.stats().read_bytes < no_cache.stats().read_bytes);
+        assert!(key_cache.stats().read_bytes < page_cache.stats().read_bytes);
+    }
+
+    /// The marker of the (made-up) schema version 1 layout
+    const PREFIX_MAGIC: u8 = 0xa5;
+
+    /// A migration from a (made-up) unversioned layout, where the fabrics blob was stored as-is,
+    /// to a layout where the blob is prefixed with a marker and its length (schema version 1).
+    ///
+    /// Idempotent, as blobs which are already prefixed are skipped.
+    #[derive(Default)]
+    struct PrefixMigration {
+        runs: usize,
+    }
+
+    fn prefixed(data: &[u8]) -> Vec<u8, BUF_SIZE> {
+        let mut prefixed = Vec::new();
+        prefixed.push(PREFIX_MAGIC).unwrap();
+        prefixed.push(data.len() as u8).unwrap();
+        prefixed.extend_from_slice(data).unwrap();
+
+        prefixed
+    }
+
+    impl Migration for PrefixMigration {
+        async fn migrate<K>(
+            &mut self,
+            kvs: &mut K,
+            buf: &mut [u8],
+            from: u32,
+            to: u32,
+        ) -> Result<(), rs_matter_stack::matter::error::Error>
+        where
+            K: KvBlobStore,
+        {
+            assert_eq!((from, to), (UNVERSIONED, 1));
+
+            self.runs += 1;
+
+            let mut old = Vec::<u8, BUF_SIZE>::new();
+            kvs.load(Key::Fabrics, buf, |data| {
+                if let Some(data) = data {
+                    let migrated = data.len() >= 2
+                        && data[0] == PREFIX_MAGIC
+                        && data[1] as usize == data.len() - 2;
+
+                    if !migrated {
+                        old.extend_from_slice(data).unwrap();
+                    }
+                }
+
+                Ok(())
+            })
+            .await?;
+
+            if !old.is_empty() {
+                let new = prefixed(&old);
+
+                kvs.store(Key::Fabrics, buf, |buf| {
+                    buf[..new.len()].copy_from_slice(&new);
+
+                    Ok(new.len())
+                })
+                .await?;
+            }
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn migrate() {
+        let mut flash = TestFlash::new();
+
+        block_on(async {
+            let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+            let mut buf = [0; BUF_SIZE];
+
+            // The prior, unversioned layout
+            store(&mut kvs, Key::Fabrics, &blob(Key::Fabrics, 0, 100)).await;
+            store(&mut kvs, Key::BasicInfo, &blob(Key::BasicInfo, 0, 100)).await;
+
+            assert_eq!(kvs.schema_version(&mut buf).await.unwrap(), None);
+
+            let mut migration = PrefixMigration::default();
+            kvs.migrate(1, &mut migration, &mut buf).await.unwrap();
+
+            assert_eq!(migration.runs, 1);
+            assert_eq!(kvs.schema_version(&mut buf).await.unwrap(), Some(1));
+
+            assert_eq!(
+                load(&mut kvs, Key::Fabrics).await,
+                Some(prefixed(&blob(Key::Fabrics, 0, 100)))
+            );
+            assert_eq!(
+                load(&mut kvs, Key::BasicInfo).await,
+                Some(blob(Key::BasicInfo, 0, 100))
+            );
+
+            // "Reboot"; the migration should not run again
+            let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+
+            kvs.migrate(1, &mut migration, &mut buf).await.unwrap();
+            assert_eq!(migration.runs, 1);
+        });
+    }
+
+    /// Cut the power at every possible step of a `store` which needs to erase a page - including in the middle
+    /// of the erase itself - and check that - after a reboot - either the old or the new blob is loaded.
+    #[test]
+    fn power_loss_erase() {
+        // Find out how many stores fit in the flash range before a store needs to erase a page
+        let mut flash = TestFlash::new();
+
+        let stores = block_on(async {
+            let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+            let mut gen = 0;
+
+            loop {
+                let erases = kvs.flash.stats().erases;
+
+                store(&mut kvs, Key::Fabrics, &blob(Key::Fabrics, gen, 300)).await;
+
+                if kvs.flash.stats().erases > erases {
+                    break gen;
+                }
+
+                gen += 1;
+            }
+        });
+
+        let mut erase_interrupted = false;
+
+        for cut in 0.. {
+            let mut flash = TestFlash::new();
+
+            let stored = block_on(async {
+                let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+
+                for gen in 0..stores {
+                    store(&mut kvs, Key::Fabrics, &blob(Key::Fabrics, gen, 300)).await;
+                }
+
+                kvs.flash.cut_power_after(cut);
+
+                let mut buf = [0; BUF_SIZE];
+                let new = blob(Key::Fabrics, stores, 300);
+
+                kvs.store(Key::Fabrics, &mut buf, |buf| {
+                    buf[..new.len()].copy_from_slice(&new);
+                    Ok(new.len())
+                })
+                .await
+                .is_ok()
+            });
+
+            erase_interrupted |= flash.power_lost_during() == Some(RamFlashOp::Erase);
+
+            // "Reboot"
+            flash.restore_power();
+
+            block_on(async {
+                let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+
+                let old = blob(Key::Fabrics, stores - 1, 300);
+                let new = blob(Key::Fabrics, stores, 300);
+
+                let loaded = load(&mut kvs, Key::Fabrics).await.unwrap();
+                assert!(
+                    loaded == new || (!stored && loaded == old),
+                    "Cut point {cut}: corrupted blob"
+                );
+
+                // The store should be usable after the power loss
+                let newer = blob(Key::Fabrics, stores + 1, 300);
+                store(&mut kvs, Key::Fabrics, &newer).await;
+                assert_eq!(load(&mut kvs, Key::Fabrics).await, Some(newer));
+            });
+
+            if stored {
+                break;
+            }
+        }
+
+        assert!(erase_interrupted);
+    }
+
+    /// Cut the power at every possible step during a migration and check that - after a reboot -
+    /// re-running the (idempotent) migration results in the migrated blobs.
+    #[test]
+    fn migrate_power_loss() {
+        for cut in 0.. {
+            let mut flash = TestFlash::new();
+
+            let migrated = block_on(async {
+                let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+                let mut buf = [0; BUF_SIZE];
+
+                store(&mut kvs, Key::Fabrics, &blob(Key::Fabrics, 0, 100)).await;
+
+                kvs.flash.cut_power_after(cut);
+
+                kvs.migrate(1, PrefixMigration::default(), &mut buf)
+                    .await
+                    .is_ok()
+            });
+
+            // "Reboot"
+            flash.restore_power();
+
+            block_on(async {
+                let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+                let mut buf = [0; BUF_SIZE];
+
+                let mut migration = PrefixMigration::default();
+                kvs.migrate(1, &mut migration, &mut buf).await.unwrap();
+
+                // Only re-run if the schema version had not been recorded yet
+                assert!(migration.runs <= 1);
+                assert_eq!(kvs.schema_version(&mut buf).await.unwrap(), Some(1));
+                assert_eq!(
+                    load(&mut kvs, Key::Fabrics).await,
+                    Some(prefixed(&blob(Key::Fabrics, 0, 100))),
+                    "Cut point {cut}: corrupted blob"
+                );
+            });
+
+            if migrated {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn migrate_empty() {
+        let mut flash = TestFlash::new();
+
+        block_on(async {
+            let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+            let mut buf = [0; BUF_SIZE];
+
+            kvs.migrate(3, (), &mut buf).await.unwrap();
+            assert_eq!(kvs.schema_version(&mut buf).await.unwrap(), Some(3));
+
+            for key in KEYS {
+                assert_eq!(load(&mut kvs, *key).await, None);
+            }
+        });
+    }
+
+    #[test]
+    fn errors() {
+        let mut flash = TestFlash::new();
+
+        block_on(async {
+            let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096);
+            let mut buf = [0; BUF_SIZE];
+
+            // Errors returned by the serialization callback are reported as-is
+            let err = kvs
+                .store(Key::Fabrics, &mut buf, |_| Err(ErrorCode::Invalid.into()))
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), ErrorCode::Invalid);
+
+            kvs.flash.fail_after(RamFlashOp::Write, 0);
+
+            let err = kvs
+                .store(Key::Fabrics, &mut buf, |buf| {
+                    buf[0] = 1;
+                    Ok(1)
+                })
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), ErrorCode::StdIoError);
+        });
+
+        // A blob bigger than a flash page
+        let mut flash = RamFlash::<1024, 256, 4>::new();
+
+        block_on(async {
+            let mut kvs = EmbassyKvBlobStore::new(&mut flash, 0..1024);
+            let mut buf = [0; BUF_SIZE];
+
+            let err = kvs
+                .store(Key::Fabrics, &mut buf, |buf| {
+                    buf[..300].fill(1);
+                    Ok(300)
+                })
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), ErrorCode::NoSpace);
+        });
+    }
+}
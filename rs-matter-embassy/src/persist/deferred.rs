@@ -0,0 +1,346 @@
+//! Deferred persistence: `DeferredKvBlobStore` - a write-behind `KvBlobStore` wrapper,
+//! which coalesces repeated stores of the same key so as to reduce the flash wear
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+
+use log::{debug, info};
+
+use rs_matter_stack::matter::error::{Error, ErrorCode};
+use rs_matter_stack::matter::utils::storage::Vec;
+use rs_matter_stack::matter::utils::sync::IfMutex;
+use rs_matter_stack::persist::{Key, KvBlobStore};
+
+/// The default window during which repeated stores are coalesced
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(5);
+
+/// A write-behind wrapper around another `KvBlobStore`.
+///
+/// Stored blobs are kept in up to `N` RAM slots of `SIZE` bytes each, and are only written to the wrapped store
+/// when `flush` is called, or - if `run` is scheduled - once the coalescing window after the first pending store
+/// elapses. Thus, repeated stores of the same key within the window result in a single write to the flash.
+///
+/// Blobs bigger than `SIZE` bytes, as well as removals, are written through immediately. If all slots are taken,
+/// the pending blobs are flushed before a blob for a new key is stored.
+///
+/// NOTE: Pending blobs are lost on power loss or reset, so `flush` should be called before a planned reboot
+/// (i.e. after a factory reset or an OTA update).
+///
+/// The slots take `N * SIZE` bytes of RAM (plus a few bytes per slot), wherever the wrapper lives -
+/// typically in a `static`. Hence, there are no defaults for `N` and `SIZE`: pick `SIZE` according to the
+/// blobs which are stored often (i.e. the fabrics blob with a single fabric, rather than the maximum size of
+/// the `KvBlobStore` buffer), and `N` according to how many different keys are stored repeatedly;
+/// everything else is written through.
+///
+/// Like `SharedKvBlobStore`, `&DeferredKvBlobStore` is what implements `KvBlobStore`.
+pub struct DeferredKvBlobStore<S, const N: usize, const SIZE: usize> {
+    state: IfMutex<CriticalSectionRawMutex, Deferred<S, N, SIZE>>,
+    pending: Signal<CriticalSectionRawMutex, ()>,
+}
+
+struct Deferred<S, const N: usize, const SIZE: usize> {
+    store: S,
+    slots: heapless::Vec<(Key, Vec<u8, SIZE>), N>,
+}
+
+impl<S, const N: usize, const SIZE: usize> DeferredKvBlobStore<S, N, SIZE>
+where
+    S: KvBlobStore,
+{
+    /// Create a new write-behind wrapper around `store`.
+    pub const fn new(store: S) -> Self {
+        Self {
+            state: IfMutex::new(Deferred {
+                store,
+                slots: heapless::Vec::new(),
+            }),
+            pending: Signal::new(),
+        }
+    }
+
+    /// Return the number of blobs pending to be written to the wrapped store.
+    pub async fn pending(&self) -> usize {
+        self.state.lock().await.slots.len()
+    }
+
+    /// Write all pending blobs to the wrapped store.
+    ///
+    /// `buf` is passed to the wrapped store.
+    pub async fn flush(&self, buf: &mut [u8]) -> Result<(), Error> {
+        self.state.lock().await.flush(buf).await
+    }
+
+    /// Flush the pending blobs once `window` elapses after the first of them was stored.
+    ///
+    /// Should be run concurrently with the Matter stack (i.e. as part of the user future passed to `MatterStack::run`).
+    pub async fn run(&self, window: Duration, buf: &mut [u8]) -> Result<(), Error> {
+        loop {
+            self.pending.wait().await;
+
+            Timer::after(window).await;
+
+            self.flush(buf).await?;
+        }
+    }
+}
+
+impl<S, const N: usize, const SIZE: usize> Deferred<S, N, SIZE>
+where
+    S: KvBlobStore,
+{
+    async fn flush(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        while let Some((key, data)) = self.slots.first() {
+            let key = *key;
+
+            self.store
+                .store(key, buf, |buf| {
+                    buf.get_mut(..data.len())
+                        .ok_or(ErrorCode::NoSpace)?
+                        .copy_from_slice(data);
+
+                    Ok(data.len())
+                })
+                .await?;
+
+            // Only drop the blob once it is safely written
+            self.slots.remove(0);
+
+            debug!("Blob {key}: flushed");
+        }
+
+        Ok(())
+    }
+
+    fn slot(&self, key: Key) -> Option<usize> {
+        self.slots.iter().position(|(slot_key, _)| *slot_key == key)
+    }
+}
+
+impl<S, const N: usize, const SIZE: usize> KvBlobStore for &DeferredKvBlobStore<S, N, SIZE>
+where
+    S: KvBlobStore,
+{
+    async fn load<F>(&mut self, key: Key, buf: &mut [u8], f: F) -> Result<(), Error>
+    where
+        F: FnOnce(Option<&[u8]>) -> Result<(), Error>,
+    {
+        let mut state = self.state.lock().await;
+
+        if let Some(index) = state.slot(key) {
+            f(Some(&state.slots[index].1))
+        } else {
+            state.store.load(key, buf, f).await
+        }
+    }
+
+    async fn store<F>(&mut self, key: Key, buf: &mut [u8], f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut [u8]) -> Result<usize, Error>,
+    {
+        let mut state = self.state.lock().await;
+
+        if state.slot(key).is_none() && state.slots.is_full() {
+            info!("All write-behind slots are taken, flushing");
+
+            state.flush(buf).await?;
+        }
+
+        let len = f(buf)?;
+
+        if len > SIZE {
+            // Too big for a slot; write through, using the rest of `buf` as a buffer for the wrapped store
+            if let Some(index) = state.slot(key) {
+                state.slots.remove(index);
+            }
+
+            let (data, buf) = buf.split_at_mut(len);
+
+            return state
+                .store
+                .store(key, buf, |buf| {
+                    buf.get_mut(..data.len())
+                        .ok_or(ErrorCode::NoSpace)?
+                        .copy_from_slice(data);
+
+                    Ok(data.len())
+                })
+                .await;
+        }
+
+        let data = &buf[..len];
+
+        if let Some(index) = state.slot(key) {
+            let slot = &mut state.slots[index].1;
+
+            slot.clear();
+            slot.extend_from_slice(data).unwrap();
+        } else {
+            // Cannot fail, as the slots were flushed above if they were all taken, and `len <= SIZE`
+            let _ = state.slots.push((key, Vec::from_slice(data).unwrap()));
+        }
+
+        debug!("Blob {key}: deferred");
+
+        self.pending.signal(());
+
+        Ok(())
+    }
+
+    async fn remove(&mut self, key: Key, buf: &mut [u8]) -> Result<(), Error> {
+        let mut state = self.state.lock().await;
+
+        if let Some(index) = state.slot(key) {
+            state.slots.remove(index);
+        }
+
+        state.store.remove(key, buf).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use embassy_futures::block_on;
+
+    use rs_matter_stack::matter::error::Error;
+    use rs_matter_stack::persist::{Key, KvBlobStore};
+
+    use crate::persist::test::{blob, load, remove, store, TestFlash, BUF_SIZE, KEYS};
+    use crate::persist::EmbassyKvBlobStore;
+
+    use super::DeferredKvBlobStore;
+
+    const STORES: u8 = 20;
+
+    /// A `KvBlobStore` wrapper counting the stores that reach the wrapped store
+    struct CountingKvBlobStore<S> {
+        store: S,
+        stores: usize,
+    }
+
+    impl<S> KvBlobStore for CountingKvBlobStore<S>
+    where
+        S: KvBlobStore,
+    {
+        async fn load<F>(&mut self, key: Key, buf: &mut [u8], f: F) -> Result<(), Error>
+        where
+            F: FnOnce(Option<&[u8]>) -> Result<(), Error>,
+        {
+            self.store.load(key, buf, f).await
+        }
+
+        async fn store<F>(&mut self, key: Key, buf: &mut [u8], f: F) -> Result<(), Error>
+        where
+            F: FnOnce(&mut [u8]) -> Result<usize, Error>,
+        {
+            self.stores += 1;
+            self.store.store(key, buf, f).await
+        }
+
+        async fn remove(&mut self, key: Key, buf: &mut [u8]) -> Result<(), Error> {
+            self.store.remove(key, buf).await
+        }
+    }
+
+    #[test]
+    fn coalescing() {
+        let mut direct_flash = TestFlash::new();
+        let mut deferred_flash = TestFlash::new();
+
+        block_on(async {
+            let mut kvs = EmbassyKvBlobStore::new(&mut direct_flash, 0..4 * 4096);
+
+            for gen in 0..STORES {
+                for key in KEYS {
+                    store(&mut kvs, *key, &blob(*key, gen, 200)).await;
+                }
+            }
+
+            let deferred = DeferredKvBlobStore::<_, 3, 256>::new(CountingKvBlobStore {
+                store: EmbassyKvBlobStore::new(&mut deferred_flash, 0..4 * 4096),
+                stores: 0,
+            });
+            let mut kvs = &deferred;
+
+            for gen in 0..STORES {
+                for key in KEYS {
+                    store(&mut kvs, *key, &blob(*key, gen, 200)).await;
+                    assert_eq!(load(&mut kvs, *key).await, Some(blob(*key, gen, 200)));
+                }
+            }
+
+            assert_eq!(deferred.pending().await, KEYS.len());
+            assert_eq!(deferred.state.lock().await.store.stores, 0);
+
+            let mut buf = [0; BUF_SIZE];
+            deferred.flush(&mut buf).await.unwrap();
+
+            assert_eq!(deferred.pending().await, 0);
+
+            // A single write per key, regardless of how many times the key was stored
+            assert_eq!(deferred.state.lock().await.store.stores, KEYS.len());
+
+            for key in KEYS {
+                assert_eq!(
+                    load(&mut kvs, *key).await,
+                    Some(blob(*key, STORES - 1, 200))
+                );
+            }
+        });
+
+        assert_eq!(deferred_flash.stats().erases, 0);
+        assert!(deferred_flash.stats().writes * 10 < direct_flash.stats().writes);
+        assert!(deferred_flash.stats().written_bytes * 10 < direct_flash.stats().written_bytes);
+
+        // The flushed blobs should survive a "reboot"
+        block_on(async {
+            let mut kvs = EmbassyKvBlobStore::new(&mut deferred_flash, 0..4 * 4096);
+
+            for key in KEYS {
+                assert_eq!(
+                    load(&mut kvs, *key).await,
+                    Some(blob(*key, STORES - 1, 200))
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn write_through() {
+        let mut flash = TestFlash::new();
+
+        block_on(async {
+            let deferred = DeferredKvBlobStore::<_, 1, 256>::new(EmbassyKvBlobStore::new(
+                &mut flash,
+                0..4 * 4096,
+            ));
+            let mut kvs = &deferred;
+
+            // Too big for a slot
+            store(&mut kvs, Key::Fabrics, &blob(Key::Fabrics, 0, 300)).await;
+            assert_eq!(deferred.pending().await, 0);
+
+            // Takes the only slot
+            store(&mut kvs, Key::BasicInfo, &blob(Key::BasicInfo, 0, 100)).await;
+            assert_eq!(deferred.pending().await, 1);
+
+            // Flushes `BasicInfo` to free the slot
+            store(&mut kvs, Key::Networks, &blob(Key::Networks, 0, 100)).await;
+            assert_eq!(deferred.pending().await, 1);
+
+            // Removals are written through, and discard the pending blob
+            remove(&mut kvs, Key::Networks).await;
+            assert_eq!(deferred.pending().await, 0);
+
+            assert_eq!(
+                load(&mut kvs, Key::Fabrics).await,
+                Some(blob(Key::Fabrics, 0, 300))
+            );
+            assert_eq!(
+                load(&mut kvs, Key::BasicInfo).await,
+                Some(blob(Key::BasicInfo, 0, 100))
+            );
+            assert_eq!(load(&mut kvs, Key::Networks).await, None);
+        });
+    }
+}
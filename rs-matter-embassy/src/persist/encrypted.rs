@@ -0,0 +1,406 @@
+//! Encrypted persistence: `EncryptedKvBlobStore` - a `KvBlobStore` wrapper that encrypts the stored blobs
+//!
+//! Each blob is encrypted and authenticated with AES-128-CCM (the AEAD used by Matter itself),
+//! using a device-unique key and a random nonce per record. The blob `Key` is authenticated as well,
+//! so records cannot be swapped between keys without this being detected on load.
+
+use log::warn;
+
+use rs_matter_stack::matter::crypto::{
+    decrypt_in_place, encrypt_in_place, AEAD_MIC_LEN_BYTES, AEAD_NONCE_LEN_BYTES,
+    SYMM_KEY_LEN_BYTES,
+};
+use rs_matter_stack::matter::error::{Error, ErrorCode};
+use rs_matter_stack::matter::utils::rand::Rand;
+use rs_matter_stack::persist::{Key, KvBlobStore};
+
+/// The version of the record format; stored in the record and authenticated
+const VERSION: u8 = 1;
+
+/// Record layout: version (1 byte) | nonce | ciphertext | MIC
+const HEADER_LEN: usize = 1 + AEAD_NONCE_LEN_BYTES;
+
+/// A source of the device-unique key used by `EncryptedKvBlobStore`.
+///
+/// On real hardware the key would typically be derived from a secret stored in eFuse or OTP memory
+/// which is not readable by the application firmware image itself.
+pub trait DeviceKey {
+    /// Fill `key` with the device-unique encryption key.
+    fn device_key(&self, key: &mut [u8; SYMM_KEY_LEN_BYTES]) -> Result<(), Error>;
+}
+
+impl<T> DeviceKey for &T
+where
+    T: DeviceKey,
+{
+    fn device_key(&self, key: &mut [u8; SYMM_KEY_LEN_BYTES]) -> Result<(), Error> {
+        (*self).device_key(key)
+    }
+}
+
+/// A fixed key, i.e. one which is read from a protected location once, during startup.
+impl DeviceKey for [u8; SYMM_KEY_LEN_BYTES] {
+    fn device_key(&self, key: &mut [u8; SYMM_KEY_LEN_BYTES]) -> Result<(), Error> {
+        key.copy_from_slice(self);
+
+        Ok(())
+    }
+}
+
+/// A `KvBlobStore` wrapper that encrypts and authenticates each blob before passing it to the wrapped store,
+/// and decrypts and verifies it on load.
+///
+/// Loading a record which had been tampered with (or which had been stored under a different key,
+/// or with a different device key) fails with an error.
+///
+/// Note that half of the buffer passed to `load` is used for the decrypted data, so callers need to pass
+/// `load` and `store` a buffer at least twice the size of the largest (encrypted) blob. To keep the two
+/// symmetric, `store` only hands the first half of its buffer to the wrapped store as well, so a blob
+/// which would not fit the load buffer is rejected at store time rather than stored and never loaded back.
+pub struct EncryptedKvBlobStore<S, K> {
+    store: S,
+    key: K,
+    rand: Rand,
+}
+
+impl<S, K> EncryptedKvBlobStore<S, K>
+where
+    S: KvBlobStore,
+    K: DeviceKey,
+{
+    /// Create a new encrypted KV blob store wrapping the provided store.
+    ///
+    /// # Arguments
+    /// - `store`: The KV blob store where the encrypted records are stored
+    /// - `key`: The source of the device-unique encryption key
+    /// - `rand`: The random number generator used for generating the nonces
+    pub const fn new(store: S, key: K, rand: Rand) -> Self {
+        Self { store, key, rand }
+    }
+
+    /// Return a reference to the wrapped store.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Return a mutable reference to the wrapped store.
+    pub fn store_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    async fn load<F>(&mut self, key: Key, buf: &mut [u8], cb: F) -> Result<(), Error>
+    where
+        F: FnOnce(Option<&[u8]>) -> Result<(), Error>,
+    {
+        let mut device_key = [0; SYMM_KEY_LEN_BYTES];
+        self.key.device_key(&mut device_key)?;
+
+        let (buf, plain_buf) = buf.split_at_mut(buf.len() / 2);
+
+        let result = self
+            .store
+            .load(key, buf, |data| match data {
+                Some(data) => {
+                    let data = decrypt(&device_key, key, data, plain_buf).inspect_err(|_| {
+                        warn!("Blob {key}: decryption failed, the record is corrupted or had been tampered with")
+                    })?;
+
+                    cb(Some(data))
+                }
+                None => cb(None),
+            })
+            .await;
+
+        zeroize(&mut device_key);
+        zeroize(plain_buf);
+
+        result
+    }
+
+    async fn store<F>(&mut self, key: Key, buf: &mut [u8], cb: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut [u8]) -> Result<usize, Error>,
+    {
+        let mut device_key = [0; SYMM_KEY_LEN_BYTES];
+        self.key.device_key(&mut device_key)?;
+
+        let rand = self.rand;
+
+        // Same split as in `load`, which only passes the first half of its buffer to the wrapped store
+        let half = buf.len() / 2;
+
+        let result = self
+            .store
+            .store(key, &mut buf[..half], |buf| {
+                if buf.len() < HEADER_LEN + AEAD_MIC_LEN_BYTES {
+                    return Err(ErrorCode::NoSpace.into());
+                }
+
+                let (header, payload) = buf.split_at_mut(HEADER_LEN);
+
+                header[0] = VERSION;
+                rand(&mut header[1..]);
+
+                let max_len = payload.len() - AEAD_MIC_LEN_BYTES;
+                let len = cb(&mut payload[..max_len])?;
+
+                let len = encrypt_in_place(&device_key, &header[1..], &aad(key), payload, len)?;
+
+                Ok(HEADER_LEN + len)
+            })
+            .await;
+
+        zeroize(&mut device_key);
+
+        // On success, the plaintext had been encrypted in place, but not if the serialization
+        // or the encryption failed halfway
+        if result.is_err() {
+            zeroize(buf);
+        }
+
+        result
+    }
+
+    async fn remove(&mut self, key: Key, buf: &mut [u8]) -> Result<(), Error> {
+        self.store.remove(key, buf).await
+    }
+}
+
+impl<S, K> KvBlobStore for EncryptedKvBlobStore<S, K>
+where
+    S: KvBlobStore,
+    K: DeviceKey,
+{
+    async fn load<F>(&mut self, key: Key, buf: &mut [u8], f: F) -> Result<(), Error>
+    where
+        F: FnOnce(Option<&[u8]>) -> Result<(), Error>,
+    {
+        EncryptedKvBlobStore::load(self, key, buf, f).await
+    }
+
+    async fn store<F>(&mut self, key: Key, buf: &mut [u8], f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut [u8]) -> Result<usize, Error>,
+    {
+        EncryptedKvBlobStore::store(self, key, buf, f).await
+    }
+
+    async fn remove(&mut self, key: Key, buf: &mut [u8]) -> Result<(), Error> {
+        EncryptedKvBlobStore::remove(self, key, buf).await
+    }
+}
+
+/// The associated data of a record: the record format version and the blob key
+fn aad(key: Key) -> [u8; 2] {
+    [VERSION, key as u8]
+}
+
+/// Clear `buf`, in a way that the compiler cannot optimize away
+fn zeroize(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        // Safety: `b` is a valid, aligned reference
+        unsafe { core::ptr::write_volatile(b, 0) };
+    }
+
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
+
+/// Verify and decrypt `data` into `buf`, returning the decrypted blob
+fn decrypt<'a>(
+    device_key: &[u8],
+    key: Key,
+    data: &[u8],
+    buf: &'a mut [u8],
+) -> Result<&'a [u8], Error> {
+    if data.len() < HEADER_LEN + AEAD_MIC_LEN_BYTES || data[0] != VERSION {
+        return Err(ErrorCode::InvalidData.into());
+    }
+
+    let (header, payload) = data.split_at(HEADER_LEN);
+
+    let buf = buf.get_mut(..payload.len()).ok_or(ErrorCode::NoSpace)?;
+    buf.copy_from_slice(payload);
+
+    let len = decrypt_in_place(device_key, &header[1..], &aad(key), buf)?;
+
+    Ok(&buf[..len])
+}
+
+#[cfg(test)]
+mod test {
+    use embassy_futures::block_on;
+
+    use rs_matter_stack::matter::utils::storage::Vec;
+    use rs_matter_stack::persist::{Key, KvBlobStore};
+
+    use crate::persist::test::{blob, load, store, TestFlash, BUF_SIZE, KEYS};
+    use crate::persist::EmbassyKvBlobStore;
+
+    use super::EncryptedKvBlobStore;
+
+    const DEVICE_KEY: [u8; 16] = [
+        0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0xfe, 0xdc, 0xba, 0x98, 0x76, 0x54, 0x32,
+        0x10,
+    ];
+
+    fn test_rand(buf: &mut [u8]) {
+        buf.iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b = i as u8 ^ 0x5a);
+    }
+
+    #[test]
+    fn load_store() {
+        let mut flash = TestFlash::new();
+
+        block_on(async {
+            let mut kvs = EncryptedKvBlobStore::new(
+                EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096),
+                DEVICE_KEY,
+                test_rand,
+            );
+
+            for key in KEYS {
+                store(&mut kvs, *key, &blob(*key, 0, 300)).await;
+            }
+
+            for key in KEYS {
+                assert_eq!(load(&mut kvs, *key).await, Some(blob(*key, 0, 300)));
+
+                // The wrapped store should only see the encrypted record
+                let raw = load(kvs.store_mut(), *key).await.unwrap();
+                assert_ne!(&raw[raw.len() - 300..], blob(*key, 0, 300).as_slice());
+            }
+        });
+
+        // ... and so should the flash
+        let plain = blob(Key::Fabrics, 0, 300);
+        assert!(!flash.data().windows(16).any(|w| w == &plain[..16]));
+    }
+
+    #[test]
+    fn tampering() {
+        let mut flash = TestFlash::new();
+
+        block_on(async {
+            let mut kvs = EncryptedKvBlobStore::new(
+                EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096),
+                DEVICE_KEY,
+                test_rand,
+            );
+
+            store(&mut kvs, Key::Fabrics, &blob(Key::Fabrics, 0, 100)).await;
+
+            let raw = load(kvs.store_mut(), Key::Fabrics).await.unwrap();
+
+            // A record moved to another key
+            store(kvs.store_mut(), Key::BasicInfo, &raw).await;
+            assert!(try_load(&mut kvs, Key::BasicInfo).await.is_err());
+
+            // A record with a flipped bit
+            let mut tampered = raw.clone();
+            tampered[20] ^= 0x01;
+            store(kvs.store_mut(), Key::Fabrics, &tampered).await;
+            assert!(try_load(&mut kvs, Key::Fabrics).await.is_err());
+
+            // A truncated record
+            store(kvs.store_mut(), Key::Fabrics, &raw[..10]).await;
+            assert!(try_load(&mut kvs, Key::Fabrics).await.is_err());
+
+            // A record encrypted with another device key
+            store(kvs.store_mut(), Key::Fabrics, &raw).await;
+            let mut kvs = EncryptedKvBlobStore::new(kvs.store, [0; 16], test_rand);
+            assert!(try_load(&mut kvs, Key::Fabrics).await.is_err());
+        });
+    }
+
+    #[test]
+    fn plaintext_cleared() {
+        let mut flash = TestFlash::new();
+
+        block_on(async {
+            let mut kvs = EncryptedKvBlobStore::new(
+                EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096),
+                DEVICE_KEY,
+                test_rand,
+            );
+
+            let plain = blob(Key::Fabrics, 0, 300);
+            store(&mut kvs, Key::Fabrics, &plain).await;
+
+            let mut buf = [0; BUF_SIZE];
+
+            kvs.load(Key::Fabrics, &mut buf, |data| {
+                assert_eq!(data, Some(plain.as_slice()));
+                Ok(())
+            })
+            .await
+            .unwrap();
+
+            // No trace of the plaintext should be left in the buffer once `load` returns
+            assert!(!buf.windows(16).any(|w| w == &plain[..16]));
+
+            // ... nor when the serialization fails halfway
+            let err = kvs
+                .store(Key::BasicInfo, &mut buf, |buf| {
+                    buf[..plain.len()].copy_from_slice(&plain);
+                    Err(rs_matter_stack::matter::error::ErrorCode::Invalid.into())
+                })
+                .await;
+
+            assert!(err.is_err());
+            assert!(!buf.windows(16).any(|w| w == &plain[..16]));
+        });
+    }
+
+    #[test]
+    fn oversized() {
+        let mut flash = TestFlash::new();
+
+        block_on(async {
+            let mut kvs = EncryptedKvBlobStore::new(
+                EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096),
+                DEVICE_KEY,
+                test_rand,
+            );
+
+            // A blob which would not fit in the first half of the load buffer is rejected at store time...
+            let large = blob(Key::Fabrics, 0, BUF_SIZE / 2 + 100);
+            let mut buf = [0; BUF_SIZE];
+
+            let err = kvs
+                .store(Key::Fabrics, &mut buf, |buf| {
+                    let buf = buf
+                        .get_mut(..large.len())
+                        .ok_or(rs_matter_stack::matter::error::ErrorCode::NoSpace)?;
+                    buf.copy_from_slice(&large);
+                    Ok(large.len())
+                })
+                .await;
+
+            assert!(err.is_err());
+            assert_eq!(load(&mut kvs, Key::Fabrics).await, None);
+
+            // ... while one which does fit loads back
+            let fits = blob(Key::Fabrics, 0, BUF_SIZE / 2 - 100);
+            store(&mut kvs, Key::Fabrics, &fits).await;
+            assert_eq!(load(&mut kvs, Key::Fabrics).await, Some(fits));
+        });
+    }
+
+    async fn try_load<S: KvBlobStore>(
+        kvs: &mut S,
+        key: Key,
+    ) -> Result<Option<Vec<u8, BUF_SIZE>>, rs_matter_stack::matter::error::Error> {
+        let mut buf = [0; BUF_SIZE];
+        let mut result = None;
+
+        kvs.load(key, &mut buf, |data| {
+            result = data.map(|data| Vec::from_slice(data).unwrap());
+            Ok(())
+        })
+        .await?;
+
+        Ok(result)
+    }
+}
@@ -0,0 +1,425 @@
+//! File system persistence: `FsKvBlobStore` - a `KvBlobStore` implementation on top of an embedded file system
+//!
+//! Stores each blob in its own file, so that the Matter state can live alongside other application files
+//! (i.e. on a `littlefs` partition) rather than in a dedicated `sequential_storage` flash range.
+
+use core::fmt::{Debug, Write};
+
+use log::{error, info};
+
+use rs_matter_stack::matter::error::{Error, ErrorCode};
+use rs_matter_stack::persist::{Key, KvBlobStore};
+
+use crate::redact::Redacted;
+
+/// The maximum length of a blob file path
+const MAX_PATH_LEN: usize = 64;
+
+/// The minimal set of file system operations needed by `FsKvBlobStore`.
+pub trait BlobFs {
+    /// The error type of the file system
+    type Error: Debug;
+
+    /// Read the whole content of the file at `path` into `buf`.
+    ///
+    /// Return the number of bytes read, or `None` if the file does not exist.
+    fn read(&mut self, path: &str, buf: &mut [u8]) -> Result<Option<usize>, Self::Error>;
+
+    /// Create (or truncate) the file at `path` and write `data` into it.
+    fn write(&mut self, path: &str, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Atomically rename the file at `from` to `to`, replacing `to` if it exists.
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), Self::Error>;
+
+    /// Remove the file at `path`; removing a file which does not exist is not an error.
+    fn remove(&mut self, path: &str) -> Result<(), Self::Error>;
+}
+
+impl<T> BlobFs for &mut T
+where
+    T: BlobFs,
+{
+    type Error = T::Error;
+
+    fn read(&mut self, path: &str, buf: &mut [u8]) -> Result<Option<usize>, Self::Error> {
+        (*self).read(path, buf)
+    }
+
+    fn write(&mut self, path: &str, data: &[u8]) -> Result<(), Self::Error> {
+        (*self).write(path, data)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), Self::Error> {
+        (*self).rename(from, to)
+    }
+
+    fn remove(&mut self, path: &str) -> Result<(), Self::Error> {
+        (*self).remove(path)
+    }
+}
+
+/// A `KvBlobStore` implementation which stores each blob in a separate file
+/// of an embedded file system.
+///
+/// Blobs are first written to a temporary file, which is then atomically renamed over the blob file,
+/// so an interrupted `store` leaves the previous version of the blob intact.
+pub struct FsKvBlobStore<F> {
+    fs: F,
+    dir: &'static str,
+}
+
+impl<F> FsKvBlobStore<F>
+where
+    F: BlobFs,
+{
+    /// Create a new KV blob store instance.
+    ///
+    /// # Arguments
+    /// - `fs`: The file system
+    /// - `dir`: The (pre-existing) directory where the blob files are stored, i.e. `/matter`
+    pub const fn new(fs: F, dir: &'static str) -> Self {
+        Self { fs, dir }
+    }
+
+    async fn load<C>(&mut self, key: Key, buf: &mut [u8], cb: C) -> Result<(), Error>
+    where
+        C: FnOnce(Option<&[u8]>) -> Result<(), Error>,
+    {
+        let path = self.path(key, false)?;
+
+        let len = self.fs.read(&path, buf).map_err(to_fs_error)?;
+        let data = len.map(|len| &buf[..len]);
+
+        cb(data)?;
+
+        if let Some(data) = data {
+            info!("Blob {key}: loaded {}", Redacted(data));
+        } else {
+            info!("Blob {key}: not found");
+        }
+
+        Ok(())
+    }
+
+    async fn store<C>(&mut self, key: Key, buf: &mut [u8], cb: C) -> Result<(), Error>
+    where
+        C: FnOnce(&mut [u8]) -> Result<usize, Error>,
+    {
+        let len = cb(buf)?;
+        let data = &buf[..len];
+
+        let path = self.path(key, false)?;
+        let tmp_path = self.path(key, true)?;
+
+        self.fs.write(&tmp_path, data).map_err(to_fs_error)?;
+        self.fs.rename(&tmp_path, &path).map_err(to_fs_error)?;
+
+        info!("Blob {key}: stored {}", Redacted(data));
+
+        Ok(())
+    }
+
+    async fn remove(&mut self, key: Key, _buf: &mut [u8]) -> Result<(), Error> {
+        let path = self.path(key, false)?;
+
+        self.fs.remove(&path).map_err(to_fs_error)?;
+
+        info!("Blob {key}: removed");
+
+        Ok(())
+    }
+
+    fn path(&self, key: Key, tmp: bool) -> Result<heapless::String<MAX_PATH_LEN>, Error> {
+        let mut path = heapless::String::new();
+
+        write!(
+            &mut path,
+            "{}/matter-{:02x}{}",
+            self.dir.trim_end_matches('/'),
+            key as u8,
+            if tmp { ".tmp" } else { "" }
+        )
+        .map_err(|_| ErrorCode::NoSpace)?;
+
+        Ok(path)
+    }
+}
+
+impl<F> KvBlobStore for FsKvBlobStore<F>
+where
+    F: BlobFs,
+{
+    async fn load<C>(&mut self, key: Key, buf: &mut [u8], f: C) -> Result<(), Error>
+    where
+        C: FnOnce(Option<&[u8]>) -> Result<(), Error>,
+    {
+        FsKvBlobStore::load(self, key, buf, f).await
+    }
+
+    async fn store<C>(&mut self, key: Key, buf: &mut [u8], f: C) -> Result<(), Error>
+    where
+        C: FnOnce(&mut [u8]) -> Result<usize, Error>,
+    {
+        FsKvBlobStore::store(self, key, buf, f).await
+    }
+
+    async fn remove(&mut self, key: Key, buf: &mut [u8]) -> Result<(), Error> {
+        FsKvBlobStore::remove(self, key, buf).await
+    }
+}
+
+fn to_fs_error<E: Debug>(err: E) -> Error {
+    error!("File system error: {err:?}");
+
+    ErrorCode::StdIoError.into()
+}
+
+/// `BlobFs` implementation for the `littlefs2` crate.
+#[cfg(feature = "littlefs")]
+mod littlefs {
+    use littlefs2::driver::Storage;
+    use littlefs2::fs::Filesystem;
+    use littlefs2::io::{Error, Read, Result};
+    use littlefs2::path::PathBuf;
+
+    fn to_path(path: &str) -> Result<PathBuf> {
+        PathBuf::try_from(path).map_err(|_| Error::INVALID)
+    }
+
+    impl<S> super::BlobFs for Filesystem<'_, S>
+    where
+        S: Storage,
+    {
+        type Error = Error;
+
+        fn read(&mut self, path: &str, buf: &mut [u8]) -> Result<Option<usize>> {
+            let result = self.open_file_and_then(&to_path(path)?, |file| {
+                let len = file.len()?;
+                if len > buf.len() {
+                    return Err(Error::NO_SPACE);
+                }
+
+                file.read(&mut buf[..len])
+            });
+
+            match result {
+                Ok(len) => Ok(Some(len)),
+                Err(err) if err == Error::NO_SUCH_ENTRY => Ok(None),
+                Err(err) => Err(err),
+            }
+        }
+
+        fn write(&mut self, path: &str, data: &[u8]) -> Result<()> {
+            Filesystem::write(self, &to_path(path)?, data)
+        }
+
+        fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+            Filesystem::rename(self, &to_path(from)?, &to_path(to)?)
+        }
+
+        fn remove(&mut self, path: &str) -> Result<()> {
+            match Filesystem::remove(self, &to_path(path)?) {
+                Err(err) if err == Error::NO_SUCH_ENTRY => Ok(()),
+                other => other,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use embassy_futures::block_on;
+
+        use littlefs2::fs::Filesystem;
+
+        use crate::persist::test::{blob, load, remove, store, KEYS};
+
+        use super::super::FsKvBlobStore;
+
+        littlefs2::ram_storage!(large);
+
+        #[test]
+        fn load_store_remove() {
+            let mut ram = Ram::default();
+            let mut storage = RamStorage::new(&mut ram);
+
+            Filesystem::format(&mut storage).unwrap();
+
+            {
+                let mut alloc = Filesystem::allocate();
+                let mut fs = Filesystem::mount(&mut alloc, &mut storage).unwrap();
+
+                block_on(async {
+                    let mut kvs = FsKvBlobStore::new(&mut fs, "/");
+
+                    for key in KEYS {
+                        assert_eq!(load(&mut kvs, *key).await, None);
+                        store(&mut kvs, *key, &blob(*key, 0, 100)).await;
+                        store(&mut kvs, *key, &blob(*key, 1, 200)).await;
+                    }
+
+                    remove(&mut kvs, KEYS[0]).await;
+                    assert_eq!(load(&mut kvs, KEYS[0]).await, None);
+
+                    // Removing a blob which does not exist is not an error
+                    remove(&mut kvs, KEYS[0]).await;
+                });
+            }
+
+            // "Reboot"
+            let mut alloc = Filesystem::allocate();
+            let mut fs = Filesystem::mount(&mut alloc, &mut storage).unwrap();
+
+            block_on(async {
+                let mut kvs = FsKvBlobStore::new(&mut fs, "/");
+
+                assert_eq!(load(&mut kvs, KEYS[0]).await, None);
+
+                for key in &KEYS[1..] {
+                    assert_eq!(load(&mut kvs, *key).await, Some(blob(*key, 1, 200)));
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use embassy_futures::block_on;
+
+    use rs_matter_stack::matter::utils::storage::Vec;
+    use rs_matter_stack::persist::Key;
+
+    use crate::persist::test::{blob, load, remove, store, BUF_SIZE, KEYS};
+
+    use super::{BlobFs, FsKvBlobStore};
+
+    /// A RAM file system, which can simulate a power loss in the middle of writing a file
+    #[derive(Default)]
+    struct RamFs {
+        files: heapless::Vec<(heapless::String<64>, Vec<u8, BUF_SIZE>), 8>,
+        fail_writes: bool,
+    }
+
+    impl RamFs {
+        fn find(&self, path: &str) -> Option<usize> {
+            self.files.iter().position(|(p, _)| p == path)
+        }
+    }
+
+    impl BlobFs for RamFs {
+        type Error = ();
+
+        fn read(&mut self, path: &str, buf: &mut [u8]) -> Result<Option<usize>, ()> {
+            Ok(self.find(path).map(|index| {
+                let data = &self.files[index].1;
+                buf[..data.len()].copy_from_slice(data);
+                data.len()
+            }))
+        }
+
+        fn write(&mut self, path: &str, data: &[u8]) -> Result<(), ()> {
+            self.remove(path)?;
+
+            // Simulate a power loss after writing half of the file
+            let len = if self.fail_writes {
+                data.len() / 2
+            } else {
+                data.len()
+            };
+
+            self.files
+                .push((
+                    path.try_into().unwrap(),
+                    Vec::from_slice(&data[..len]).unwrap(),
+                ))
+                .map_err(|_| ())?;
+
+            if self.fail_writes {
+                Err(())
+            } else {
+                Ok(())
+            }
+        }
+
+        fn rename(&mut self, from: &str, to: &str) -> Result<(), ()> {
+            self.find(from).ok_or(())?;
+            self.remove(to)?;
+
+            let index = self.find(from).unwrap();
+            self.files[index].0 = to.try_into().unwrap();
+
+            Ok(())
+        }
+
+        fn remove(&mut self, path: &str) -> Result<(), ()> {
+            if let Some(index) = self.find(path) {
+                self.files.swap_remove(index);
+            }
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn load_store_remove() {
+        let mut fs = RamFs::default();
+
+        block_on(async {
+            let mut kvs = FsKvBlobStore::new(&mut fs, "/matter/");
+
+            for key in KEYS {
+                assert_eq!(load(&mut kvs, *key).await, None);
+                store(&mut kvs, *key, &blob(*key, 0, 100)).await;
+                store(&mut kvs, *key, &blob(*key, 1, 200)).await;
+            }
+
+            for key in KEYS {
+                assert_eq!(load(&mut kvs, *key).await, Some(blob(*key, 1, 200)));
+                remove(&mut kvs, *key).await;
+                assert_eq!(load(&mut kvs, *key).await, None);
+            }
+        });
+
+        assert!(fs.files.is_empty());
+    }
+
+    #[test]
+    fn interrupted_store() {
+        let mut fs = RamFs::default();
+
+        block_on(async {
+            let mut kvs = FsKvBlobStore::new(&mut fs, "/matter");
+
+            store(&mut kvs, Key::Fabrics, &blob(Key::Fabrics, 0, 100)).await;
+
+            kvs.fs.fail_writes = true;
+
+            let mut buf = [0; BUF_SIZE];
+            assert!(kvs
+                .store(Key::Fabrics, &mut buf, |buf| {
+                    buf[..200].fill(0x55);
+                    Ok(200)
+                })
+                .await
+                .is_err());
+
+            kvs.fs.fail_writes = false;
+
+            // The old blob should be intact
+            assert_eq!(
+                load(&mut kvs, Key::Fabrics).await,
+                Some(blob(Key::Fabrics, 0, 100))
+            );
+
+            // ... and the store should recover from the leftover temporary file
+            store(&mut kvs, Key::Fabrics, &blob(Key::Fabrics, 1, 100)).await;
+            assert_eq!(
+                load(&mut kvs, Key::Fabrics).await,
+                Some(blob(Key::Fabrics, 1, 100))
+            );
+        });
+
+        assert_eq!(fs.files.len(), 1);
+        assert_eq!(fs.files[0].0, "/matter/matter-00");
+    }
+}
@@ -0,0 +1,637 @@
+//! Mirrored persistence: `MirrorKvBlobStore` - a `KvBlobStore` wrapper that keeps each blob in two
+//! independent stores, so that the corruption of one of them does not result in data loss
+//!
+//! Each copy is stored as a record with a generation counter and a CRC, so that on load the newest valid copy
+//! can be selected, even if the device lost power between updating the two copies.
+//!
+//! Removals are recorded as tombstones - records without data, with an inverted CRC - so that a copy which could
+//! not be removed is never healed back onto the other one.
+
+use log::warn;
+
+use rs_matter_stack::matter::error::{Error, ErrorCode};
+use rs_matter_stack::persist::{Key, KvBlobStore};
+
+use crate::crc::crc32;
+
+/// Record layout: generation (`u32`, LE) | CRC-32 of the generation and the data (`u32`, LE) | data
+///
+/// A tombstone is a record without data, whose CRC is inverted.
+const HEADER_LEN: usize = 8;
+
+/// A `KvBlobStore` wrapper which writes each blob to two independent stores (i.e. two `EmbassyKvBlobStore`
+/// instances on two different flash ranges), and - on load - returns the newest valid copy, healing the other copy
+/// if it is missing, stale or corrupted.
+///
+/// A store or a remove only fails if it fails for both copies. A remove first replaces both copies with a tombstone
+/// of a newer generation, and only drops the tombstones once both are written, so that an interrupted or partially
+/// failed remove still results in the blob being removed on the next load.
+///
+/// Note that half of the buffer passed to `load` and `store` is used for the blob itself, so the buffer needs to be
+/// at least twice the size of the largest blob.
+pub struct MirrorKvBlobStore<A, B> {
+    a: A,
+    b: B,
+}
+
+/// The state of one of the copies of a blob
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum CopyState {
+    /// The copy is valid, with the provided generation and data length
+    Valid(u32, usize),
+    /// The copy is a tombstone, with the provided generation
+    Removed(u32),
+    /// There is no copy
+    Missing,
+    /// The copy could not be loaded, or is corrupted
+    Invalid,
+}
+
+impl<A, B> MirrorKvBlobStore<A, B>
+where
+    A: KvBlobStore,
+    B: KvBlobStore,
+{
+    /// Create a new mirrored KV blob store.
+    ///
+    /// # Arguments
+    /// - `a`: The primary store; written first
+    /// - `b`: The secondary store
+    pub const fn new(a: A, b: B) -> Self {
+        Self { a, b }
+    }
+
+    /// Return references to the wrapped stores.
+    pub fn stores(&self) -> (&A, &B) {
+        (&self.a, &self.b)
+    }
+
+    /// Return mutable references to the wrapped stores.
+    pub fn stores_mut(&mut self) -> (&mut A, &mut B) {
+        (&mut self.a, &mut self.b)
+    }
+
+    async fn load<F>(&mut self, key: Key, buf: &mut [u8], cb: F) -> Result<(), Error>
+    where
+        F: FnOnce(Option<&[u8]>) -> Result<(), Error>,
+    {
+        let (record_buf, buf) = buf.split_at_mut(buf.len() / 2);
+
+        // Load copy A into `record_buf`
+        let mut a = CopyState::Missing;
+        let result = self
+            .a
+            .load(key, buf, |data| {
+                if let Some(data) = data {
+                    a = parse(data);
+
+                    if data.len() > record_buf.len() {
+                        a = CopyState::Invalid;
+                    } else if a != CopyState::Invalid {
+                        record_buf[..data.len()].copy_from_slice(data);
+                    }
+                }
+
+                Ok(())
+            })
+            .await;
+
+        if let Err(err) = result {
+            warn!("Blob {key}: copy A cannot be loaded: {err:?}");
+            a = CopyState::Invalid;
+        }
+
+        // Load copy B, and keep it in `record_buf` only if it is newer than copy A
+        let mut b = CopyState::Missing;
+        let result = self
+            .b
+            .load(key, buf, |data| {
+                if let Some(data) = data {
+                    b = parse(data);
+
+                    if data.len() > record_buf.len() {
+                        b = CopyState::Invalid;
+                    } else if newer(b, a) {
+                        record_buf[..data.len()].copy_from_slice(data);
+                    }
+                }
+
+                Ok(())
+            })
+            .await;
+
+        if let Err(err) = result {
+            warn!("Blob {key}: copy B cannot be loaded: {err:?}");
+            b = CopyState::Invalid;
+        }
+
+        let winner = if newer(b, a) { b } else { a };
+
+        let (gen, len) = match winner {
+            CopyState::Valid(gen, len) => (gen, Some(len)),
+            CopyState::Removed(gen) => (gen, None),
+            CopyState::Missing if b == CopyState::Missing => return cb(None),
+            _ => {
+                warn!("Blob {key}: no valid copy (A: {a:?}, B: {b:?})");
+                return Err(ErrorCode::InvalidData.into());
+            }
+        };
+
+        // For a tombstone, healing propagates the removal to the other copy
+        let record = &record_buf[..HEADER_LEN + len.unwrap_or(0)];
+
+        if a != winner {
+            warn!("Blob {key}: copy A is {a:?}, healing from copy B (generation {gen})");
+            heal(&mut self.a, key, buf, record).await;
+        }
+
+        if b != winner {
+            warn!("Blob {key}: copy B is {b:?}, healing from copy A (generation {gen})");
+            heal(&mut self.b, key, buf, record).await;
+        }
+
+        cb(len.map(|len| &record_buf[HEADER_LEN..HEADER_LEN + len]))
+    }
+
+    async fn store<F>(&mut self, key: Key, buf: &mut [u8], cb: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut [u8]) -> Result<usize, Error>,
+    {
+        let (record_buf, buf) = buf.split_at_mut(buf.len() / 2);
+
+        let gen = self
+            .generation(key, buf)
+            .await
+            .map_or(1, |gen| gen.wrapping_add(1));
+
+        let (header, data_buf) = record_buf
+            .split_at_mut_checked(HEADER_LEN)
+            .ok_or(ErrorCode::NoSpace)?;
+
+        let len = cb(data_buf)?;
+
+        header[..4].copy_from_slice(&gen.to_le_bytes());
+        let crc = crc32_record(&header[..4], &data_buf[..len]);
+        header[4..].copy_from_slice(&crc.to_le_bytes());
+
+        let record = &record_buf[..HEADER_LEN + len];
+
+        let result_a = store_record(&mut self.a, key, buf, record).await;
+        let result_b = store_record(&mut self.b, key, buf, record).await;
+
+        match (result_a, result_b) {
+            (Err(err), Err(_)) => Err(err),
+            (Err(err), _) => {
+                warn!("Blob {key}: copy A cannot be stored: {err:?}");
+                Ok(())
+            }
+            (_, Err(err)) => {
+                warn!("Blob {key}: copy B cannot be stored: {err:?}");
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn remove(&mut self, key: Key, buf: &mut [u8]) -> Result<(), Error> {
+        if let Some(gen) = self.generation(key, buf).await {
+            let record = tombstone(gen.wrapping_add(1));
+
+            let result_a = store_record(&mut self.a, key, buf, &record).await;
+            let result_b = store_record(&mut self.b, key, buf, &record).await;
+
+            match (result_a, result_b) {
+                (Err(err), Err(_)) => return Err(err),
+                (Err(err), _) | (_, Err(err)) => {
+                    // Keep the tombstone that was written, so that the next load propagates the removal
+                    warn!("Blob {key}: one of the copies cannot be marked as removed: {err:?}");
+                    return Ok(());
+                }
+                _ => (),
+            }
+        }
+
+        // Both copies are now tombstones (or were not valid in the first place), so dropping them one by one
+        // cannot bring back the blob
+        let result_a = self.a.remove(key, buf).await;
+        let result_b = self.b.remove(key, buf).await;
+
+        match (result_a, result_b) {
+            (Err(err), Err(_)) => Err(err),
+            (Err(err), _) | (_, Err(err)) => {
+                warn!("Blob {key}: one of the copies cannot be removed: {err:?}");
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Return the newest generation of the blob stored under `key` (including tombstones),
+    /// or `None` if there is no valid copy
+    async fn generation(&mut self, key: Key, buf: &mut [u8]) -> Option<u32> {
+        let mut gen = None;
+
+        let mut update = |data: Option<&[u8]>| {
+            if let Some(copy) = data.map(parse) {
+                if newer(copy, gen.map_or(CopyState::Missing, CopyState::Removed)) {
+                    gen = copy.generation();
+                }
+            }
+
+            Ok::<_, Error>(())
+        };
+
+        // Errors are not fatal here; the copies are rewritten anyway
+        let _ = self.a.load(key, buf, &mut update).await;
+        let _ = self.b.load(key, buf, &mut update).await;
+
+        gen
+    }
+}
+
+impl<A, B> KvBlobStore for MirrorKvBlobStore<A, B>
+where
+    A: KvBlobStore,
+    B: KvBlobStore,
+{
+    async fn load<F>(&mut self, key: Key, buf: &mut [u8], f: F) -> Result<(), Error>
+    where
+        F: FnOnce(Option<&[u8]>) -> Result<(), Error>,
+    {
+        MirrorKvBlobStore::load(self, key, buf, f).await
+    }
+
+    async fn store<F>(&mut self, key: Key, buf: &mut [u8], f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut [u8]) -> Result<usize, Error>,
+    {
+        MirrorKvBlobStore::store(self, key, buf, f).await
+    }
+
+    async fn remove(&mut self, key: Key, buf: &mut [u8]) -> Result<(), Error> {
+        MirrorKvBlobStore::remove(self, key, buf).await
+    }
+}
+
+impl CopyState {
+    /// Return the generation of the copy, if it is valid or a tombstone
+    const fn generation(&self) -> Option<u32> {
+        match self {
+            Self::Valid(gen, _) | Self::Removed(gen) => Some(*gen),
+            Self::Missing | Self::Invalid => None,
+        }
+    }
+}
+
+/// Return `true` if `copy` is valid or a tombstone, and newer than `other`
+fn newer(copy: CopyState, other: CopyState) -> bool {
+    match (copy.generation(), other.generation()) {
+        (Some(gen), Some(other_gen)) => {
+            // Wrapping comparison, so that the generation counter can overflow
+            (gen.wrapping_sub(other_gen) as i32) > 0
+        }
+        (Some(_), None) => true,
+        _ => false,
+    }
+}
+
+/// Parse and validate a record
+fn parse(record: &[u8]) -> CopyState {
+    if record.len() < HEADER_LEN {
+        return CopyState::Invalid;
+    }
+
+    let (header, data) = record.split_at(HEADER_LEN);
+
+    let gen = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let expected = crc32_record(&header[..4], data);
+
+    if crc == expected {
+        CopyState::Valid(gen, data.len())
+    } else if data.is_empty() && crc == !expected {
+        CopyState::Removed(gen)
+    } else {
+        CopyState::Invalid
+    }
+}
+
+/// Return the tombstone record of the provided generation
+fn tombstone(gen: u32) -> [u8; HEADER_LEN] {
+    let mut record = [0; HEADER_LEN];
+
+    record[..4].copy_from_slice(&gen.to_le_bytes());
+    let crc = !crc32_record(&record[..4], &[]);
+    record[4..].copy_from_slice(&crc.to_le_bytes());
+
+    record
+}
+
+fn crc32_record(gen: &[u8], data: &[u8]) -> u32 {
+    // The generation is authenticated too; chaining the two CRCs is good enough for detecting corruption
+    crc32(data) ^ crc32(gen).rotate_left(1)
+}
+
+async fn store_record<S>(
+    store: &mut S,
+    key: Key,
+    buf: &mut [u8],
+    record: &[u8],
+) -> Result<(), Error>
+where
+    S: KvBlobStore,
+{
+    store
+        .store(key, buf, |buf| {
+            buf.get_mut(..record.len())
+                .ok_or(ErrorCode::NoSpace)?
+                .copy_from_slice(record);
+
+            Ok(record.len())
+        })
+        .await
+}
+
+async fn heal<S>(store: &mut S, key: Key, buf: &mut [u8], record: &[u8])
+where
+    S: KvBlobStore,
+{
+    // A failure to heal is not fatal, as there is still one valid copy
+    if let Err(err) = store_record(store, key, buf, record).await {
+        warn!("Blob {key}: healing failed: {err:?}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use embassy_futures::block_on;
+
+    use rs_matter_stack::persist::{Key, KvBlobStore};
+
+    use crate::flash::RamFlashOp;
+    use crate::persist::test::{blob, load, remove, store, TestFlash, BUF_SIZE, KEYS};
+    use crate::persist::EmbassyKvBlobStore;
+
+    use super::{parse, CopyState, MirrorKvBlobStore, HEADER_LEN};
+
+    const RANGE: core::ops::Range<u32> = 0..4 * 4096;
+
+    /// Load the raw record of one of the copies, and return its generation and data
+    async fn load_copy<S: KvBlobStore>(
+        store: &mut S,
+        key: Key,
+    ) -> Option<(u32, heapless::Vec<u8, BUF_SIZE>)> {
+        let record = load(store, key).await?;
+        let CopyState::Valid(gen, _) = parse(&record) else {
+            panic!("Not a valid copy");
+        };
+
+        Some((
+            gen,
+            heapless::Vec::from_slice(&record[HEADER_LEN..]).unwrap(),
+        ))
+    }
+
+    #[test]
+    fn load_store_remove() {
+        let mut flash_a = TestFlash::new();
+        let mut flash_b = TestFlash::new();
+
+        block_on(async {
+            let mut kvs = MirrorKvBlobStore::new(
+                EmbassyKvBlobStore::new(&mut flash_a, RANGE),
+                EmbassyKvBlobStore::new(&mut flash_b, RANGE),
+            );
+
+            for key in KEYS {
+                assert_eq!(load(&mut kvs, *key).await, None);
+                store(&mut kvs, *key, &blob(*key, 0, 100)).await;
+                store(&mut kvs, *key, &blob(*key, 1, 200)).await;
+            }
+
+            for key in KEYS {
+                assert_eq!(load(&mut kvs, *key).await, Some(blob(*key, 1, 200)));
+
+                let (a, b) = kvs.stores_mut();
+                assert_eq!(load_copy(a, *key).await, Some((2, blob(*key, 1, 200))));
+                assert_eq!(load_copy(b, *key).await, Some((2, blob(*key, 1, 200))));
+
+                remove(&mut kvs, *key).await;
+                assert_eq!(load(&mut kvs, *key).await, None);
+            }
+        });
+    }
+
+    #[test]
+    fn lost_copy() {
+        let mut flash_a = TestFlash::new();
+        let mut flash_b = TestFlash::new();
+
+        block_on(async {
+            let mut kvs = MirrorKvBlobStore::new(
+                EmbassyKvBlobStore::new(&mut flash_a, RANGE),
+                EmbassyKvBlobStore::new(&mut flash_b, RANGE),
+            );
+
+            for key in KEYS {
+                store(&mut kvs, *key, &blob(*key, 0, 100)).await;
+            }
+        });
+
+        // Simulate a completely lost copy A
+        flash_a.data_mut().fill(0xff);
+
+        block_on(async {
+            let mut kvs = MirrorKvBlobStore::new(
+                EmbassyKvBlobStore::new(&mut flash_a, RANGE),
+                EmbassyKvBlobStore::new(&mut flash_b, RANGE),
+            );
+
+            for key in KEYS {
+                assert_eq!(load(&mut kvs, *key).await, Some(blob(*key, 0, 100)));
+            }
+        });
+
+        // Copy A should be healed
+        block_on(async {
+            let mut a = EmbassyKvBlobStore::new(&mut flash_a, RANGE);
+
+            for key in KEYS {
+                assert_eq!(load_copy(&mut a, *key).await, Some((1, blob(*key, 0, 100))));
+            }
+        });
+    }
+
+    #[test]
+    fn corrupted_copy() {
+        let mut flash_a = TestFlash::new();
+        let mut flash_b = TestFlash::new();
+
+        block_on(async {
+            let mut kvs = MirrorKvBlobStore::new(
+                EmbassyKvBlobStore::new(&mut flash_a, RANGE),
+                EmbassyKvBlobStore::new(&mut flash_b, RANGE),
+            );
+
+            for key in KEYS {
+                store(&mut kvs, *key, &blob(*key, 0, 100)).await;
+            }
+        });
+
+        // Simulate a bad sector in copy B
+        flash_b.data_mut()[..4096].fill(0);
+
+        block_on(async {
+            let mut kvs = MirrorKvBlobStore::new(
+                EmbassyKvBlobStore::new(&mut flash_a, RANGE),
+                EmbassyKvBlobStore::new(&mut flash_b, RANGE),
+            );
+
+            for key in KEYS {
+                assert_eq!(load(&mut kvs, *key).await, Some(blob(*key, 0, 100)));
+            }
+        });
+
+        // The loads should have healed copy B
+        block_on(async {
+            let mut b = EmbassyKvBlobStore::new(&mut flash_b, RANGE);
+
+            for key in KEYS {
+                assert_eq!(load_copy(&mut b, *key).await, Some((1, blob(*key, 0, 100))));
+            }
+        });
+    }
+
+    #[test]
+    fn interrupted_store() {
+        let mut flash_a = TestFlash::new();
+        let mut flash_b = TestFlash::new();
+
+        block_on(async {
+            let mut kvs = MirrorKvBlobStore::new(
+                EmbassyKvBlobStore::new(&mut flash_a, RANGE),
+                EmbassyKvBlobStore::new(&mut flash_b, RANGE),
+            );
+
+            store(&mut kvs, Key::Fabrics, &blob(Key::Fabrics, 0, 100)).await;
+        });
+
+        // Copy B is not updated, as if the power was lost after updating copy A
+        flash_b.fail_after(RamFlashOp::Write, 0);
+
+        block_on(async {
+            let mut kvs = MirrorKvBlobStore::new(
+                EmbassyKvBlobStore::new(&mut flash_a, RANGE),
+                EmbassyKvBlobStore::new(&mut flash_b, RANGE),
+            );
+
+            store(&mut kvs, Key::Fabrics, &blob(Key::Fabrics, 1, 100)).await;
+        });
+
+        flash_b.clear_failure();
+
+        block_on(async {
+            let mut kvs = MirrorKvBlobStore::new(
+                EmbassyKvBlobStore::new(&mut flash_a, RANGE),
+                EmbassyKvBlobStore::new(&mut flash_b, RANGE),
+            );
+
+            let (_, b) = kvs.stores_mut();
+            assert_eq!(
+                load_copy(b, Key::Fabrics).await,
+                Some((1, blob(Key::Fabrics, 0, 100)))
+            );
+
+            // The newer copy A wins, and copy B is healed
+            assert_eq!(
+                load(&mut kvs, Key::Fabrics).await,
+                Some(blob(Key::Fabrics, 1, 100))
+            );
+
+            let (_, b) = kvs.stores_mut();
+            assert_eq!(
+                load_copy(b, Key::Fabrics).await,
+                Some((2, blob(Key::Fabrics, 1, 100)))
+            );
+        });
+    }
+
+    #[test]
+    fn interrupted_remove() {
+        let mut flash_a = TestFlash::new();
+        let mut flash_b = TestFlash::new();
+
+        block_on(async {
+            let mut kvs = MirrorKvBlobStore::new(
+                EmbassyKvBlobStore::new(&mut flash_a, RANGE),
+                EmbassyKvBlobStore::new(&mut flash_b, RANGE),
+            );
+
+            store(&mut kvs, Key::Fabrics, &blob(Key::Fabrics, 0, 100)).await;
+        });
+
+        // Copy B cannot be updated, so it keeps the stale blob
+        flash_b.fail_after(RamFlashOp::Write, 0);
+
+        block_on(async {
+            let mut kvs = MirrorKvBlobStore::new(
+                EmbassyKvBlobStore::new(&mut flash_a, RANGE),
+                EmbassyKvBlobStore::new(&mut flash_b, RANGE),
+            );
+
+            remove(&mut kvs, Key::Fabrics).await;
+        });
+
+        flash_b.clear_failure();
+
+        block_on(async {
+            let mut kvs = MirrorKvBlobStore::new(
+                EmbassyKvBlobStore::new(&mut flash_a, RANGE),
+                EmbassyKvBlobStore::new(&mut flash_b, RANGE),
+            );
+
+            let (a, b) = kvs.stores_mut();
+            assert_eq!(
+                parse(&load(a, Key::Fabrics).await.unwrap()),
+                CopyState::Removed(2)
+            );
+            assert_eq!(
+                load_copy(b, Key::Fabrics).await,
+                Some((1, blob(Key::Fabrics, 0, 100)))
+            );
+
+            // The stale copy B must not be healed back onto copy A; rather, the removal is propagated to copy B
+            assert_eq!(load(&mut kvs, Key::Fabrics).await, None);
+
+            let (_, b) = kvs.stores_mut();
+            assert_eq!(
+                parse(&load(b, Key::Fabrics).await.unwrap()),
+                CopyState::Removed(2)
+            );
+
+            // A new blob supersedes the tombstones
+            store(&mut kvs, Key::Fabrics, &blob(Key::Fabrics, 1, 100)).await;
+            assert_eq!(
+                load(&mut kvs, Key::Fabrics).await,
+                Some(blob(Key::Fabrics, 1, 100))
+            );
+
+            let (a, b) = kvs.stores_mut();
+            assert_eq!(
+                load_copy(a, Key::Fabrics).await,
+                Some((3, blob(Key::Fabrics, 1, 100)))
+            );
+            assert_eq!(
+                load_copy(b, Key::Fabrics).await,
+                Some((3, blob(Key::Fabrics, 1, 100)))
+            );
+
+            // A complete remove drops both tombstones
+            remove(&mut kvs, Key::Fabrics).await;
+
+            let (a, b) = kvs.stores_mut();
+            assert_eq!(load(a, Key::Fabrics).await, None);
+            assert_eq!(load(b, Key::Fabrics).await, None);
+        });
+    }
+}
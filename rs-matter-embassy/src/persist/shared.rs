@@ -0,0 +1,113 @@
+//! Shared persistence: `SharedKvBlobStore` - a `KvBlobStore` which can be used by the Matter stack
+//! and by the application at the same time
+
+use core::ops::DerefMut;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+use rs_matter_stack::matter::error::Error;
+use rs_matter_stack::matter::utils::sync::IfMutex;
+use rs_matter_stack::persist::{Key, KvBlobStore};
+
+/// A wrapper which allows a `KvBlobStore` to be shared between a running Matter stack and the application.
+///
+/// `&SharedKvBlobStore` implements `KvBlobStore` by locking the wrapped store for the duration of each operation,
+/// so it can be passed to the Matter stack (i.e. to `KvPersist::new`), while the application can still
+/// `lock` the store to e.g. do a factory reset or access its own data, without interleaving with a store
+/// operation of the Matter stack.
+pub struct SharedKvBlobStore<T>(IfMutex<CriticalSectionRawMutex, T>);
+
+impl<T> SharedKvBlobStore<T> {
+    /// Create a new shared KV blob store wrapping the provided store.
+    pub const fn new(store: T) -> Self {
+        Self(IfMutex::new(store))
+    }
+
+    /// Lock the wrapped store for exclusive access.
+    ///
+    /// While the returned guard is alive, the operations of the Matter stack on the store are blocked.
+    pub async fn lock(&self) -> impl DerefMut<Target = T> + '_ {
+        self.0.lock().await
+    }
+}
+
+impl<T> KvBlobStore for &SharedKvBlobStore<T>
+where
+    T: KvBlobStore,
+{
+    async fn load<F>(&mut self, key: Key, buf: &mut [u8], f: F) -> Result<(), Error>
+    where
+        F: FnOnce(Option<&[u8]>) -> Result<(), Error>,
+    {
+        self.lock().await.load(key, buf, f).await
+    }
+
+    async fn store<F>(&mut self, key: Key, buf: &mut [u8], f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut [u8]) -> Result<usize, Error>,
+    {
+        self.lock().await.store(key, buf, f).await
+    }
+
+    async fn remove(&mut self, key: Key, buf: &mut [u8]) -> Result<(), Error> {
+        self.lock().await.remove(key, buf).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use embassy_futures::block_on;
+    use embassy_futures::join::join;
+    use embassy_futures::yield_now;
+
+    use crate::persist::test::{blob, load, store, TestFlash, BUF_SIZE, KEYS};
+    use crate::persist::{EmbassyKvBlobStore, ResetScope};
+
+    use super::SharedKvBlobStore;
+
+    #[test]
+    fn factory_reset() {
+        let mut flash = TestFlash::new();
+        let shared = SharedKvBlobStore::new(EmbassyKvBlobStore::new(&mut flash, 0..4 * 4096));
+
+        block_on(async {
+            let mut kvs = &shared;
+
+            for key in KEYS {
+                store(&mut kvs, *key, &blob(*key, 0, 100)).await;
+            }
+
+            // Reset while the "Matter stack" keeps storing; the reset should happen between two stores
+            join(
+                async {
+                    let mut kvs = &shared;
+
+                    for gen in 1..10 {
+                        for key in KEYS {
+                            store(&mut kvs, *key, &blob(*key, gen, 100)).await;
+                            yield_now().await;
+                        }
+                    }
+                },
+                async {
+                    yield_now().await;
+
+                    let mut buf = [0; BUF_SIZE];
+                    let mut kvs = shared.lock().await;
+
+                    kvs.factory_reset(ResetScope::All, &mut buf).await.unwrap();
+
+                    // Nothing stored before the reset should survive it
+                    for key in KEYS {
+                        assert_eq!(load(&mut *kvs, *key).await, None);
+                    }
+                },
+            )
+            .await;
+
+            for key in KEYS {
+                assert_eq!(load(&mut kvs, *key).await, Some(blob(*key, 9, 100)));
+            }
+        });
+    }
+}
@@ -1,30 +1,70 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+pub mod crypto_rng;
+pub mod drbg;
+pub mod health;
+
+/// A global slot for the state of a `rand` fn of this module (an RNG, a DRBG, an entropy source)
+///
+/// To erase generics, `Matter` takes a rand `fn` rather than a trait or a closure,
+/// so the state behind each such `fn` needs to live in a global variable.
+pub(crate) struct RandCell<T> {
+    name: &'static str,
+    state: Mutex<CriticalSectionRawMutex, RefCell<Option<T>>>,
+}
+
+impl<T> RandCell<T> {
+    /// Create a new, uninitialized slot; `name` is used in the panic message when it is used uninitialized
+    pub(crate) const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            state: Mutex::new(RefCell::new(None)),
+        }
+    }
+
+    /// Initialize the slot, replacing its previous state, if any
+    pub(crate) fn init(&self, state: T) {
+        self.state.lock(|s| *s.borrow_mut() = Some(state));
+    }
+
+    /// Call `f` with the state of the slot
+    ///
+    /// # Panics
+    /// If the slot was not initialized.
+    pub(crate) fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+
+            f(state
+                .as_mut()
+                .unwrap_or_else(|| panic!("{} not initialized", self.name)))
+        })
+    }
+}
+
 /// `rand` function for the esp chips family.
 #[cfg(feature = "esp")]
 pub mod esp {
-    use core::cell::RefCell;
-
-    use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+    use super::RandCell;
 
-    // ... To erase generics, `Matter` takes a rand `fn` rather than a trait or a closure,
-    // so we need to store the `esp_hal::Rng` in a global variable
-    static RAND: Mutex<CriticalSectionRawMutex, RefCell<Option<esp_hal::rng::Rng>>> =
-        Mutex::new(RefCell::new(None));
+    static RAND: RandCell<esp_hal::rng::Rng> = RandCell::new("ESP RNG");
 
     /// Initialize the esp-specific `rand` implementation
     /// Need to do this only once
     pub fn esp_init_rand(rng: esp_hal::rng::Rng) {
-        RAND.lock(|r| *r.borrow_mut() = Some(rng));
+        RAND.init(rng);
     }
 
     /// Generate random bytes using the esp-specific `rand` implementation
-    // TODO: Not cryptographically secure?
+    ///
+    /// This is the raw output of the hardware RNG; use it as the entropy source of `drbg::init_drbg`.
     pub fn esp_rand(buf: &mut [u8]) {
-        RAND.lock(|rng| {
-            let mut rng = rng.borrow_mut();
-
-            buf.iter_mut()
-                .for_each(|byte| *byte = rng.as_mut().unwrap().random() as _);
-        })
+        RAND.with(|rng| buf.iter_mut().for_each(|byte| *byte = rng.random() as _))
     }
 }
 
@@ -32,7 +72,9 @@ pub mod esp {
 pub mod rp {
     use embassy_rp::clocks::RoscRng;
 
-    // TODO: Not cryptographically secure?
+    /// Generate random bytes from the ROSC of the RP2040
+    ///
+    /// This is raw entropy, which is not uniformly distributed; use it as the entropy source of `drbg::init_drbg`.
     pub fn rp_rand(buf: &mut [u8]) {
         use rand_core::RngCore;
 
@@ -40,3 +82,12 @@ pub mod rp {
         rng.fill_bytes(buf);
     }
 }
+
+/// `rand` function for hosted (`std`) targets, like Linux.
+#[cfg(feature = "std")]
+pub mod std {
+    /// Generate random bytes using the random number generator of the host OS
+    pub fn std_rand(buf: &mut [u8]) {
+        getrandom::getrandom(buf).unwrap();
+    }
+}
@@ -0,0 +1,58 @@
+//! Generic `rand`: installs any `rand_core::RngCore + CryptoRng` as the `Rand` fn of the Matter stack
+//!
+//! Useful for chips which are not covered by the chip-specific `rand` modules, i.e. the nRF or the STM32 RNG
+//! peripherals of `embassy-nrf` and `embassy-stm32`.
+
+use rand_core::CryptoRngCore;
+
+use super::RandCell;
+
+/// A cryptographically secure RNG which can be installed with `init_crypto_rng`
+pub type DynCryptoRng = dyn CryptoRngCore + Send;
+
+static RNG: RandCell<&'static mut DynCryptoRng> = RandCell::new("Crypto RNG");
+
+/// Install `rng` as the RNG used by `crypto_rng_rand`
+///
+/// The RNG needs to live forever, so it is typically allocated with a `static_cell::StaticCell`.
+/// Need to do this only once; a subsequent call replaces the previously installed RNG.
+pub fn init_crypto_rng(rng: &'static mut DynCryptoRng) {
+    RNG.init(rng);
+}
+
+/// Generate random bytes using the RNG installed with `init_crypto_rng`
+///
+/// # Panics
+/// If `init_crypto_rng` was not called.
+pub fn crypto_rng_rand(buf: &mut [u8]) {
+    RNG.with(|rng| rng.fill_bytes(buf))
+}
+
+#[cfg(test)]
+mod test {
+    use rand_chacha::ChaCha20Rng;
+    use rand_core::{RngCore, SeedableRng};
+
+    use static_cell::StaticCell;
+
+    use super::{crypto_rng_rand, init_crypto_rng};
+
+    #[test]
+    fn global() {
+        static RNG: StaticCell<ChaCha20Rng> = StaticCell::new();
+
+        init_crypto_rng(RNG.init(ChaCha20Rng::from_seed([0x42; 32])));
+
+        let mut expected = ChaCha20Rng::from_seed([0x42; 32]);
+
+        for len in [0, 1, 31, 64, 100] {
+            let mut buf = [0; 100];
+            crypto_rng_rand(&mut buf[..len]);
+
+            let mut expected_buf = [0; 100];
+            expected.fill_bytes(&mut expected_buf[..len]);
+
+            assert_eq!(buf, expected_buf);
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! DRBG: `Drbg` - a ChaCha20-based deterministic random bit generator, seeded and periodically reseeded
+//! from a (hardware) entropy source
+//!
+//! Raw hardware entropy sources (like the ESP RNG or the RP2040 ROSC) are not necessarily uniformly distributed,
+//! nor fast. The DRBG conditions their output - by hashing `ENTROPY_LEN` raw bytes with SHA-256 into each seed -
+//! and stretches it, so that it can be used for key generation by the Matter stack.
+//!
+//! To use it, call `init_drbg` once with the raw entropy source (i.e. `esp_rand` or `rp_rand`),
+//! and pass `drbg_rand` to the Matter stack.
+
+use rand_chacha::ChaCha20Rng;
+
+use rs_matter::crypto::Sha256;
+
+use super::RandCell;
+
+use rand_core::{CryptoRng, RngCore, SeedableRng};
+
+/// A raw entropy source, with the signature of the `Rand` fn of the Matter stack
+pub type Entropy = fn(&mut [u8]);
+
+/// The length of the DRBG seed
+pub const SEED_LEN: usize = 32;
+
+/// The number of raw entropy bytes conditioned into each seed
+///
+/// Twice the seed length, so that a full-entropy seed results even if each raw byte carries only 4 bits of entropy.
+pub const ENTROPY_LEN: usize = 2 * SEED_LEN;
+
+/// The default number of bytes generated between two reseeds
+pub const RESEED_INTERVAL: usize = 64 * 1024;
+
+/// A ChaCha20-based DRBG.
+///
+/// The DRBG is seeded from the entropy source on creation, and reseeded every `reseed_interval` generated bytes.
+/// Each seed is conditioned from `ENTROPY_LEN` raw entropy bytes with SHA-256. On reseed, the new seed is the XOR
+/// of the DRBG output and freshly conditioned entropy, so the DRBG state never depends on the entropy source alone.
+pub struct Drbg {
+    rng: ChaCha20Rng,
+    entropy: Entropy,
+    reseed_interval: usize,
+    generated: usize,
+}
+
+impl Drbg {
+    /// Create a new DRBG, seeded from `entropy`, and reseeded every `RESEED_INTERVAL` bytes.
+    pub fn new(entropy: Entropy) -> Self {
+        Self::new_with_reseed_interval(entropy, RESEED_INTERVAL)
+    }
+
+    /// Create a new DRBG, seeded from `entropy`, and reseeded every `reseed_interval` bytes.
+    pub fn new_with_reseed_interval(entropy: Entropy, reseed_interval: usize) -> Self {
+        Self::from_seed(condition(entropy), entropy, reseed_interval)
+    }
+
+    /// Create a new DRBG from an explicit seed, i.e. for known-answer tests.
+    pub fn from_seed(seed: [u8; SEED_LEN], entropy: Entropy, reseed_interval: usize) -> Self {
+        Self {
+            rng: ChaCha20Rng::from_seed(seed),
+            entropy,
+            reseed_interval,
+            generated: 0,
+        }
+    }
+
+    /// Reseed the DRBG from the entropy source.
+    pub fn reseed(&mut self) {
+        let mut seed = [0; SEED_LEN];
+        self.rng.fill_bytes(&mut seed);
+
+        let entropy = condition(self.entropy);
+
+        seed.iter_mut()
+            .zip(entropy.iter())
+            .for_each(|(seed, entropy)| *seed ^= *entropy);
+
+        self.rng = ChaCha20Rng::from_seed(seed);
+        self.generated = 0;
+    }
+
+    /// Fill `buf` with random bytes, reseeding first if the reseed interval elapsed.
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        if self.generated >= self.reseed_interval {
+            self.reseed();
+        }
+
+        self.rng.fill_bytes(buf);
+        self.generated = self.generated.saturating_add(buf.len());
+    }
+}
+
+impl RngCore for Drbg {
+    fn next_u32(&mut self) -> u32 {
+        let mut buf = [0; 4];
+        self.fill(&mut buf);
+
+        u32::from_le_bytes(buf)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut buf = [0; 8];
+        self.fill(&mut buf);
+
+        u64::from_le_bytes(buf)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.fill(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill(dest);
+
+        Ok(())
+    }
+}
+
+impl CryptoRng for Drbg {}
+
+/// Condition `ENTROPY_LEN` raw bytes from `entropy` into a seed, by hashing them with SHA-256
+fn condition(entropy: Entropy) -> [u8; SEED_LEN] {
+    let mut raw = [0; ENTROPY_LEN];
+    entropy(&mut raw);
+
+    let mut seed = [0; SEED_LEN];
+
+    // The `rustcrypto` SHA-256 is infallible
+    let mut hasher = Sha256::new().expect("SHA-256 failed");
+    hasher.update(&raw).expect("SHA-256 failed");
+    hasher.finish(&mut seed).expect("SHA-256 failed");
+
+    raw.fill(0);
+
+    seed
+}
+
+static DRBG: RandCell<Drbg> = RandCell::new("DRBG");
+
+/// Initialize the global DRBG used by `drbg_rand`, seeding it from `entropy`
+/// Need to do this only once
+pub fn init_drbg(entropy: Entropy) {
+    DRBG.init(Drbg::new(entropy));
+}
+
+/// Generate random bytes using the global DRBG
+///
+/// # Panics
+/// If `init_drbg` was not called.
+pub fn drbg_rand(buf: &mut [u8]) {
+    DRBG.with(|drbg| drbg.fill(buf))
+}
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::{drbg_rand, init_drbg, Drbg, ENTROPY_LEN};
+
+    /// ChaCha20 keystream for an all-zero key and nonce (RFC 8439, A.1, test vectors #1 and #2)
+    const ZERO_KEY_KEYSTREAM: [u8; 128] = [
+        0x76, 0xb8, 0xe0, 0xad, 0xa0, 0xf1, 0x3d, 0x90, 0x40, 0x5d, 0x6a, 0xe5, 0x53, 0x86, 0xbd,
+        0x28, 0xbd, 0xd2, 0x19, 0xb8, 0xa0, 0x8d, 0xed, 0x1a, 0xa8, 0x36, 0xef, 0xcc, 0x8b, 0x77,
+        0x0d, 0xc7, 0xda, 0x41, 0x59, 0x7c, 0x51, 0x57, 0x48, 0x8d, 0x77, 0x24, 0xe0, 0x3f, 0xb8,
+        0xd8, 0x4a, 0x37, 0x6a, 0x43, 0xb8, 0xf4, 0x15, 0x18, 0xa1, 0x1c, 0xc3, 0x87, 0xb6, 0x69,
+        0xb2, 0xee, 0x65, 0x86, 0x9f, 0x07, 0xe7, 0xbe, 0x55, 0x51, 0x38, 0x7a, 0x98, 0xba, 0x97,
+        0x7c, 0x73, 0x2d, 0x08, 0x0d, 0xcb, 0x0f, 0x29, 0xa0, 0x48, 0xe3, 0x65, 0x69, 0x12, 0xc6,
+        0x53, 0x3e, 0x32, 0xee, 0x7a, 0xed, 0x29, 0xb7, 0x21, 0x76, 0x9c, 0xe6, 0x4e, 0x43, 0xd5,
+        0x71, 0x33, 0xb0, 0x74, 0xd8, 0x39, 0xd5, 0x31, 0xed, 0x1f, 0x28, 0x51, 0x0a, 0xfb, 0x45,
+        0xac, 0xe1, 0x0a, 0x1f, 0x4b, 0x79, 0x4d, 0x6f,
+    ];
+
+    /// SHA-256 of `ENTROPY_LEN` zero bytes: the seed conditioned from an all-zero entropy source
+    const ZERO_ENTROPY_SEED: [u8; 32] = [
+        0xf5, 0xa5, 0xfd, 0x42, 0xd1, 0x6a, 0x20, 0x30, 0x27, 0x98, 0xef, 0x6e, 0xd3, 0x09, 0x97,
+        0x9b, 0x43, 0x00, 0x3d, 0x23, 0x20, 0xd9, 0xf0, 0xe8, 0xea, 0x98, 0x31, 0xa9, 0x27, 0x59,
+        0xfb, 0x4b,
+    ];
+
+    fn zero_entropy(buf: &mut [u8]) {
+        buf.fill(0);
+    }
+
+    #[test]
+    fn known_answer() {
+        let mut drbg = Drbg::from_seed([0; 32], zero_entropy, usize::MAX);
+
+        let mut buf = [0; 128];
+        drbg.fill(&mut buf[..7]);
+        drbg.fill(&mut buf[7..64]);
+        drbg.fill(&mut buf[64..]);
+
+        assert_eq!(buf, ZERO_KEY_KEYSTREAM);
+
+        // Seeding from an all-zero entropy source conditions the raw bytes rather than using them as the key
+        let mut drbg = Drbg::new_with_reseed_interval(zero_entropy, usize::MAX);
+        let mut expected = Drbg::from_seed(ZERO_ENTROPY_SEED, zero_entropy, usize::MAX);
+
+        let mut buf = [0; 128];
+        drbg.fill(&mut buf);
+
+        let mut expected_buf = [0; 128];
+        expected.fill(&mut expected_buf);
+
+        assert_eq!(buf, expected_buf);
+        assert_ne!(buf, ZERO_KEY_KEYSTREAM);
+    }
+
+    #[test]
+    fn reseed() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        fn counting_entropy(buf: &mut [u8]) {
+            assert_eq!(buf.len(), ENTROPY_LEN);
+
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            buf.fill(0);
+        }
+
+        let mut drbg = Drbg::from_seed([0; 32], counting_entropy, 64);
+
+        let mut buf = [0; 64];
+        drbg.fill(&mut buf);
+        assert_eq!(buf, ZERO_KEY_KEYSTREAM[..64]);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 0);
+
+        // The reseed interval elapsed; even with zero entropy, the new seed mixes in the next DRBG output,
+        // so the stream continues with different data
+        drbg.fill(&mut buf);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+        assert_ne!(buf, ZERO_KEY_KEYSTREAM[64..]);
+
+        let mut seed = ZERO_ENTROPY_SEED;
+        seed.iter_mut()
+            .zip(&ZERO_KEY_KEYSTREAM[64..96])
+            .for_each(|(seed, output)| *seed ^= *output);
+
+        let mut expected = Drbg::from_seed(seed, zero_entropy, usize::MAX);
+        let mut expected_buf = [0; 64];
+        expected.fill(&mut expected_buf);
+        assert_eq!(buf, expected_buf);
+    }
+
+    #[test]
+    fn global() {
+        init_drbg(zero_entropy);
+
+        let mut buf = [0; 16];
+        drbg_rand(&mut buf);
+
+        let mut expected = Drbg::from_seed(ZERO_ENTROPY_SEED, zero_entropy, usize::MAX);
+        let mut expected_buf = [0; 16];
+        expected.fill(&mut expected_buf);
+
+        assert_eq!(buf, expected_buf);
+    }
+}
@@ -0,0 +1,353 @@
+//! Health tests: `HealthTests` - NIST SP 800-90B startup and continuous health tests for raw (hardware) entropy sources
+//!
+//! Implements the Repetition Count Test (RCT) and the Adaptive Proportion Test (APT) of SP 800-90B, section 4.4,
+//! with byte-sized samples and a false positive probability of 2^-20 per test.
+//!
+//! To use it, call `init_health_checked` once with the raw entropy source (i.e. `esp_rand` or `rp_rand`),
+//! and use `health_checked_rand` as the entropy source of the DRBG (`drbg::init_drbg`).
+
+use log::{error, info};
+
+use super::drbg::Entropy;
+use super::RandCell;
+
+/// The number of samples tested on startup, before any output is used
+pub const STARTUP_SAMPLES: usize = 1024;
+
+/// The window size of the Adaptive Proportion Test
+pub const APT_WINDOW: u32 = 512;
+
+/// The APT cutoffs for a window of 512 samples, indexed by the claimed min-entropy per sample (1 to 8 bits) minus 1
+const APT_CUTOFFS: [u32; 8] = [311, 177, 103, 62, 39, 25, 18, 13];
+
+/// The error returned when a health test fails
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum HealthError {
+    /// The Repetition Count Test failed, i.e. the source is stuck
+    RepetitionCount,
+    /// The Adaptive Proportion Test failed, i.e. the source is biased
+    AdaptiveProportion,
+}
+
+/// What to do when a health test fails
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum FailurePolicy {
+    /// Refuse to use the entropy source: the startup tests return an error,
+    /// and a failure of the continuous tests panics
+    Refuse,
+    /// Log the failure and continue using the entropy source
+    Log,
+}
+
+/// The configuration of the health tests
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct HealthConfig {
+    /// The RCT fails when the same sample is repeated this many times in a row
+    pub rct_cutoff: u32,
+    /// The APT fails when the first sample of a window is repeated this many times in the window
+    pub apt_cutoff: u32,
+}
+
+impl HealthConfig {
+    /// Create a configuration for a source with the provided min-entropy per byte sample (1 to 8 bits).
+    pub const fn new(min_entropy: u8) -> Self {
+        assert!(matches!(min_entropy, 1..=8));
+
+        Self {
+            rct_cutoff: 1 + 20_u32.div_ceil(min_entropy as u32),
+            apt_cutoff: APT_CUTOFFS[min_entropy as usize - 1],
+        }
+    }
+}
+
+impl Default for HealthConfig {
+    /// A conservative configuration, claiming 4 bits of min-entropy per byte
+    fn default() -> Self {
+        Self::new(4)
+    }
+}
+
+/// The state of the RCT and APT health tests.
+#[derive(Debug, Clone)]
+pub struct HealthTests {
+    config: HealthConfig,
+    rct_sample: Option<u8>,
+    rct_count: u32,
+    apt_sample: u8,
+    apt_count: u32,
+    apt_index: u32,
+}
+
+impl HealthTests {
+    /// Create new health tests with the provided configuration.
+    pub const fn new(config: HealthConfig) -> Self {
+        Self {
+            config,
+            rct_sample: None,
+            rct_count: 0,
+            apt_sample: 0,
+            apt_count: 0,
+            apt_index: 0,
+        }
+    }
+
+    /// Feed a sample to the health tests.
+    pub fn sample(&mut self, sample: u8) -> Result<(), HealthError> {
+        // Repetition Count Test
+        if self.rct_sample == Some(sample) {
+            self.rct_count += 1;
+        } else {
+            self.rct_sample = Some(sample);
+            self.rct_count = 1;
+        }
+
+        // Adaptive Proportion Test
+        if self.apt_index == 0 {
+            self.apt_sample = sample;
+            self.apt_count = 1;
+        } else if self.apt_sample == sample {
+            self.apt_count += 1;
+        }
+
+        self.apt_index = (self.apt_index + 1) % APT_WINDOW;
+
+        if self.rct_count >= self.config.rct_cutoff {
+            Err(HealthError::RepetitionCount)
+        } else if self.apt_count >= self.config.apt_cutoff {
+            Err(HealthError::AdaptiveProportion)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Feed all samples in `samples` to the health tests, returning the first failure, if any.
+    pub fn samples(&mut self, samples: &[u8]) -> Result<(), HealthError> {
+        let mut result = Ok(());
+
+        for sample in samples {
+            let sample_result = self.sample(*sample);
+
+            if result.is_ok() {
+                result = sample_result;
+            }
+        }
+
+        result
+    }
+}
+
+/// A raw entropy source, whose output is continuously checked by `HealthTests`.
+pub struct HealthCheckedEntropy {
+    entropy: Entropy,
+    tests: HealthTests,
+    policy: FailurePolicy,
+    failures: u32,
+}
+
+impl HealthCheckedEntropy {
+    /// Create a new health-checked entropy source, and run the startup tests on `STARTUP_SAMPLES` samples.
+    ///
+    /// With `FailurePolicy::Refuse`, a failure of the startup tests is returned as an error.
+    pub fn new(
+        entropy: Entropy,
+        config: HealthConfig,
+        policy: FailurePolicy,
+    ) -> Result<Self, HealthError> {
+        let mut this = Self {
+            entropy,
+            tests: HealthTests::new(config),
+            policy,
+            failures: 0,
+        };
+
+        let mut buf = [0; 64];
+        for _ in 0..STARTUP_SAMPLES / buf.len() {
+            (this.entropy)(&mut buf);
+
+            if let Err(err) = this.tests.samples(&buf) {
+                this.failures += 1;
+
+                error!("Entropy source startup health test failed: {err:?}");
+
+                if policy == FailurePolicy::Refuse {
+                    return Err(err);
+                }
+            }
+        }
+
+        info!("Entropy source startup health tests complete");
+
+        Ok(this)
+    }
+
+    /// Return the number of health test failures so far.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Fill `buf` with raw entropy, feeding it to the continuous health tests.
+    ///
+    /// With `FailurePolicy::Log`, failures are only logged and counted, and `Ok` is returned.
+    pub fn fill(&mut self, buf: &mut [u8]) -> Result<(), HealthError> {
+        (self.entropy)(buf);
+
+        if let Err(err) = self.tests.samples(buf) {
+            self.failures += 1;
+
+            error!("Entropy source health test failed: {err:?}");
+
+            if self.policy == FailurePolicy::Refuse {
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+static ENTROPY: RandCell<HealthCheckedEntropy> = RandCell::new("Health-checked entropy");
+
+/// Initialize the global health-checked entropy source used by `health_checked_rand`, running the startup tests
+/// Need to do this only once
+pub fn init_health_checked(
+    entropy: Entropy,
+    config: HealthConfig,
+    policy: FailurePolicy,
+) -> Result<(), HealthError> {
+    ENTROPY.init(HealthCheckedEntropy::new(entropy, config, policy)?);
+
+    Ok(())
+}
+
+/// Generate raw entropy from the global health-checked entropy source
+///
+/// # Panics
+/// If `init_health_checked` was not called, or - with `FailurePolicy::Refuse` - if a health test fails.
+pub fn health_checked_rand(buf: &mut [u8]) {
+    ENTROPY.with(|entropy| {
+        entropy
+            .fill(buf)
+            .expect("Entropy source failed a health test")
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    use super::{
+        FailurePolicy, HealthCheckedEntropy, HealthConfig, HealthError, HealthTests, APT_WINDOW,
+    };
+
+    /// A good entropy source (for the purposes of the health tests): xorshift32
+    fn good(buf: &mut [u8]) {
+        static STATE: AtomicU32 = AtomicU32::new(0x1234_5678);
+
+        for byte in buf {
+            let mut x = STATE.load(Ordering::Relaxed);
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            STATE.store(x, Ordering::Relaxed);
+
+            *byte = (x >> 24) as u8;
+        }
+    }
+
+    /// A stuck entropy source
+    fn stuck(buf: &mut [u8]) {
+        buf.fill(0x42);
+    }
+
+    /// A biased entropy source: every other byte is 0, but there are never repetitions
+    fn biased(buf: &mut [u8]) {
+        good(buf);
+
+        for (index, byte) in buf.iter_mut().enumerate() {
+            if index % 2 == 0 {
+                *byte = 0;
+            } else if *byte == 0 {
+                *byte = 1;
+            }
+        }
+    }
+
+    #[test]
+    fn config() {
+        assert_eq!(
+            HealthConfig::new(8),
+            HealthConfig {
+                rct_cutoff: 4,
+                apt_cutoff: 13
+            }
+        );
+        assert_eq!(
+            HealthConfig::new(1),
+            HealthConfig {
+                rct_cutoff: 21,
+                apt_cutoff: 311
+            }
+        );
+    }
+
+    #[test]
+    fn rct() {
+        let mut tests = HealthTests::new(HealthConfig::new(8));
+
+        assert!(tests.samples(&[1, 2, 2, 2, 3]).is_ok());
+        assert_eq!(
+            tests.samples(&[4, 4, 4, 4]),
+            Err(HealthError::RepetitionCount)
+        );
+    }
+
+    #[test]
+    fn apt() {
+        let mut tests = HealthTests::new(HealthConfig::new(8));
+
+        // 12 occurrences of the first sample in a window are still fine
+        let mut window = [0; APT_WINDOW as usize];
+        for (index, sample) in window.iter_mut().enumerate() {
+            *sample = if index % 43 == 0 {
+                7
+            } else {
+                index as u8 | 0x80
+            };
+        }
+        assert!(tests.samples(&window).is_ok());
+        assert!(tests.samples(&window).is_ok());
+
+        // ... but 13 are not
+        window[APT_WINDOW as usize - 1] = 7;
+        assert_eq!(tests.samples(&window), Err(HealthError::AdaptiveProportion));
+    }
+
+    #[test]
+    fn sources() {
+        let config = HealthConfig::default();
+
+        let mut entropy = HealthCheckedEntropy::new(good, config, FailurePolicy::Refuse).unwrap();
+        let mut buf = [0; 256];
+        for _ in 0..100 {
+            entropy.fill(&mut buf).unwrap();
+        }
+        assert_eq!(entropy.failures(), 0);
+
+        assert_eq!(
+            HealthCheckedEntropy::new(stuck, config, FailurePolicy::Refuse).err(),
+            Some(HealthError::RepetitionCount)
+        );
+        assert_eq!(
+            HealthCheckedEntropy::new(biased, config, FailurePolicy::Refuse).err(),
+            Some(HealthError::AdaptiveProportion)
+        );
+
+        // With the `Log` policy, the source is still used, but the failures are counted
+        let mut entropy = HealthCheckedEntropy::new(stuck, config, FailurePolicy::Log).unwrap();
+        assert!(entropy.failures() > 0);
+
+        let failures = entropy.failures();
+        entropy.fill(&mut buf).unwrap();
+        assert_eq!(entropy.failures(), failures + 1);
+    }
+}
@@ -0,0 +1,67 @@
+//! Redaction: a logging policy for sensitive data
+//!
+//! Persisted blobs (fabrics, ACLs, Wifi credentials) and BTP payloads contain secrets that should not
+//! end up in the logs of a production device. All such data is logged via the `Redacted` wrapper,
+//! which renders it according to the currently active `LogPolicy`.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// The policy for logging sensitive data
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[repr(u8)]
+pub enum LogPolicy {
+    /// Sensitive data is not logged at all
+    Off = 0,
+    /// Only the size of the sensitive data is logged (the default)
+    Sizes = 1,
+    /// The sensitive data is logged as a hex dump
+    ///
+    /// Only available with the `log-secrets` feature, which should never be enabled in production builds.
+    #[cfg(feature = "log-secrets")]
+    Dump = 2,
+}
+
+impl LogPolicy {
+    const fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Off,
+            #[cfg(feature = "log-secrets")]
+            2 => Self::Dump,
+            _ => Self::Sizes,
+        }
+    }
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(LogPolicy::Sizes as u8);
+
+/// Set the policy for logging sensitive data
+pub fn set_log_policy(policy: LogPolicy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// Get the currently active policy for logging sensitive data
+pub fn log_policy() -> LogPolicy {
+    LogPolicy::from_u8(POLICY.load(Ordering::Relaxed))
+}
+
+/// A wrapper that renders sensitive bytes according to the currently active `LogPolicy`
+/// when used with `Display` or `Debug`.
+pub struct Redacted<'a>(pub &'a [u8]);
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match log_policy() {
+            LogPolicy::Off => write!(f, "(redacted)"),
+            LogPolicy::Sizes => write!(f, "{} bytes", self.0.len()),
+            #[cfg(feature = "log-secrets")]
+            LogPolicy::Dump => write!(f, "{} bytes {:02x?}", self.0.len(), self.0),
+        }
+    }
+}
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
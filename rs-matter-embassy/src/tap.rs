@@ -0,0 +1,290 @@
+//! TAP: `TapDriver` - an `embassy-net` `Driver` implementation on top of a Linux TAP interface
+//!
+//! Allows running the very same `embassy-net`-based Matter stack assemblies (i.e. `EmbassyEthMatterStack`)
+//! on a Linux host, which is useful for development and testing without flashing an MCU.
+//!
+//! The TAP interface should be created upfront, i.e.:
+//! ```sh
+//! sudo ip tuntap add name tap0 mode tap user $USER
+//! sudo ip link set tap0 up
+//! ```
+
+use core::task::Context;
+
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::vec;
+use std::vec::Vec;
+
+use async_io::Async;
+
+use embassy_net::driver::{self, Capabilities, Driver, HardwareAddress, LinkState};
+
+use log::warn;
+
+const TUNSETIFF: libc::c_ulong = 0x400454CA;
+const SIOCGIFMTU: libc::c_ulong = 0x8921;
+const IFF_TAP: libc::c_int = 0x0002;
+const IFF_NO_PI: libc::c_int = 0x1000;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+
+/// The size of the `ifr_ifru` union of `struct ifreq`, whose largest member is `struct ifmap`
+/// (24 bytes on 64-bit targets)
+const IFRU_LEN: usize = 24;
+
+/// `struct ifreq`, with the `ifr_ifru` union reduced to its `ifru_ivalue` member, and padded to the size of the union,
+/// as the kernel copies the whole struct
+#[repr(C)]
+#[derive(Debug)]
+struct IfReq {
+    ifr_name: [libc::c_char; libc::IF_NAMESIZE],
+    ifr_data: libc::c_int,
+    _pad: [u8; IFRU_LEN - core::mem::size_of::<libc::c_int>()],
+}
+
+impl IfReq {
+    fn new(name: &str) -> io::Result<Self> {
+        let mut ifreq = Self {
+            ifr_name: [0; libc::IF_NAMESIZE],
+            ifr_data: 0,
+            _pad: [0; IFRU_LEN - core::mem::size_of::<libc::c_int>()],
+        };
+
+        if name.len() >= ifreq.ifr_name.len() {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+
+        for (dst, src) in ifreq.ifr_name.iter_mut().zip(name.as_bytes()) {
+            *dst = *src as _;
+        }
+
+        Ok(ifreq)
+    }
+
+    fn ioctl(&mut self, fd: RawFd, cmd: libc::c_ulong) -> io::Result<libc::c_int> {
+        let res = unsafe { libc::ioctl(fd, cmd as _, self as *mut Self) };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(self.ifr_data)
+    }
+}
+
+/// A raw, non-blocking Linux TAP interface file descriptor.
+#[derive(Debug)]
+pub struct Tap {
+    fd: RawFd,
+    mtu: usize,
+}
+
+impl Tap {
+    /// Open the TAP interface with the provided name (i.e. `tap0`).
+    ///
+    /// The interface needs to exist and the current user needs to have access to it.
+    pub fn new(name: &str) -> io::Result<Self> {
+        let fd = unsafe {
+            libc::open(
+                c"/dev/net/tun".as_ptr(),
+                libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC,
+            )
+        };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Construct `Self` early, so that the FD is closed on error
+        let mut tap = Self { fd, mtu: 0 };
+
+        let mut ifreq = IfReq::new(name)?;
+        ifreq.ifr_data = IFF_TAP | IFF_NO_PI;
+        ifreq.ioctl(tap.fd, TUNSETIFF)?;
+
+        let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, libc::IPPROTO_IP) };
+        if socket == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mtu = IfReq::new(name)?.ioctl(socket, SIOCGIFMTU);
+
+        unsafe {
+            libc::close(socket);
+        }
+
+        // SIOCGIFMTU returns the IP MTU, while we need the size of the whole Ethernet frame
+        tap.mtu = mtu? as usize + ETHERNET_HEADER_LEN;
+
+        Ok(tap)
+    }
+
+    /// Return the maximum size of an Ethernet frame that can be sent or received over the interface.
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+        if len == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(len as usize)
+        }
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let len = unsafe { libc::write(self.fd, buf.as_ptr() as *const _, buf.len()) };
+        if len == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(len as usize)
+        }
+    }
+}
+
+impl AsRawFd for Tap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl AsFd for Tap {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
+    }
+}
+
+impl Drop for Tap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// An `embassy-net` `Driver` implementation on top of a Linux TAP interface.
+pub struct TapDriver {
+    tap: Async<Tap>,
+    mac: [u8; 6],
+    rx_buf: Vec<u8>,
+    tx_buf: Vec<u8>,
+}
+
+impl TapDriver {
+    /// Create a new driver instance for the TAP interface with the provided name (i.e. `tap0`).
+    ///
+    /// # Arguments
+    /// - `name`: The name of the TAP interface; the interface needs to exist
+    /// - `mac`: The MAC address that the `embassy-net` stack should use on the interface;
+    ///   should be different from the MAC address that the host OS uses for the TAP interface itself
+    pub fn new(name: &str, mac: [u8; 6]) -> io::Result<Self> {
+        let tap = Tap::new(name)?;
+        let mtu = tap.mtu();
+
+        Ok(Self {
+            tap: Async::new(tap)?,
+            mac,
+            rx_buf: vec![0; mtu],
+            tx_buf: vec![0; mtu],
+        })
+    }
+}
+
+impl Driver for TapDriver {
+    type RxToken<'a>
+        = TapRxToken<'a>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = TapTxToken<'a>
+    where
+        Self: 'a;
+
+    fn receive(&mut self, cx: &mut Context) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        loop {
+            match self.tap.get_ref().read(&mut self.rx_buf) {
+                Ok(len) => {
+                    return Some((
+                        TapRxToken {
+                            buf: &mut self.rx_buf[..len],
+                        },
+                        TapTxToken {
+                            tap: &self.tap,
+                            buf: &mut self.tx_buf,
+                        },
+                    ));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    // Re-try the read if the FD became readable in the meantime,
+                    // otherwise `poll_readable` had registered the waker
+                    if self.tap.poll_readable(cx).is_pending() {
+                        return None;
+                    }
+                }
+                Err(e) => panic!("TAP read error: {e:?}"),
+            }
+        }
+    }
+
+    fn transmit(&mut self, _cx: &mut Context) -> Option<Self::TxToken<'_>> {
+        Some(TapTxToken {
+            tap: &self.tap,
+            buf: &mut self.tx_buf,
+        })
+    }
+
+    fn link_state(&mut self, _cx: &mut Context) -> LinkState {
+        LinkState::Up
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let mut caps = Capabilities::default();
+        caps.max_transmission_unit = self.tap.get_ref().mtu();
+
+        caps
+    }
+
+    fn hardware_address(&self) -> HardwareAddress {
+        HardwareAddress::Ethernet(self.mac)
+    }
+}
+
+/// The `RxToken` of `TapDriver`
+#[doc(hidden)]
+pub struct TapRxToken<'a> {
+    buf: &'a mut [u8],
+}
+
+impl driver::RxToken for TapRxToken<'_> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(self.buf)
+    }
+}
+
+/// The `TxToken` of `TapDriver`
+#[doc(hidden)]
+pub struct TapTxToken<'a> {
+    tap: &'a Async<Tap>,
+    buf: &'a mut [u8],
+}
+
+impl driver::TxToken for TapTxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let buf = &mut self.buf[..len];
+        let result = f(buf);
+
+        // TAP writes are never partial, and a full TX queue just means the frame is dropped,
+        // as it would have been on a real Ethernet link
+        if let Err(e) = self.tap.get_ref().write(buf) {
+            warn!("TAP write error: {e:?}");
+        }
+
+        result
+    }
+}
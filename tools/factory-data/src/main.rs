@@ -0,0 +1,305 @@
+//! A host tool which generates factory data images, in the format read by `rs_matter_embassy::factory::FactoryData`,
+//! and prints the onboarding QR code payload and manual pairing code of the unit.
+//!
+//! The generated image should be flashed at the start of the factory data flash range of the unit.
+
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context};
+
+use clap::{Parser, ValueEnum};
+
+use rs_matter_embassy::factory::{FactoryDataWriter, FactoryTag};
+use rs_matter_embassy::matter::pairing::DiscoveryCapabilities;
+
+mod onboarding;
+
+/// Generate a factory data image for an rs-matter-embassy device
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    /// Vendor ID (i.e. 0xFFF1)
+    #[arg(long, value_parser = parse_u16)]
+    vid: u16,
+
+    /// Product ID (i.e. 0x8000)
+    #[arg(long, value_parser = parse_u16)]
+    pid: u16,
+
+    /// Serial number of the unit
+    #[arg(long)]
+    serial_no: String,
+
+    /// Discriminator of the unit (12 bits)
+    #[arg(long)]
+    discriminator: u16,
+
+    /// Setup passcode of the unit (27 bits)
+    #[arg(long)]
+    passcode: u32,
+
+    /// Device Attestation Certificate (DER)
+    #[arg(long)]
+    dac: PathBuf,
+
+    /// DAC public key (raw uncompressed SEC1 point, 65 bytes)
+    #[arg(long)]
+    dac_pubkey: PathBuf,
+
+    /// DAC private key (raw scalar, 32 bytes)
+    #[arg(long)]
+    dac_privkey: PathBuf,
+
+    /// Product Attestation Intermediate certificate (DER)
+    #[arg(long)]
+    pai: PathBuf,
+
+    /// Certification Declaration
+    #[arg(long)]
+    cd: PathBuf,
+
+    /// Discovery capabilities to advertise in the QR code
+    #[arg(long, value_enum, default_value_t = Discovery::Ble)]
+    discovery: Discovery,
+
+    /// Pad the image with 0xFF (erased flash) to this size, i.e. the size of the factory data flash range
+    #[arg(long)]
+    size: Option<usize>,
+
+    /// Where to write the image
+    #[arg(long, short)]
+    out: PathBuf,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Discovery {
+    Ble,
+    OnNetwork,
+    SoftAp,
+}
+
+impl Discovery {
+    fn capabilities(&self) -> DiscoveryCapabilities {
+        match self {
+            Self::Ble => DiscoveryCapabilities::BLE,
+            Self::OnNetwork => DiscoveryCapabilities::IP,
+            Self::SoftAp => DiscoveryCapabilities::SOFT_AP,
+        }
+    }
+}
+
+/// The (raw) content of the factory data entries
+struct Entries {
+    vid: u16,
+    pid: u16,
+    serial_no: String,
+    discriminator: u16,
+    passcode: u32,
+    dac: Vec<u8>,
+    dac_pubkey: Vec<u8>,
+    dac_privkey: Vec<u8>,
+    pai: Vec<u8>,
+    cd: Vec<u8>,
+}
+
+impl Entries {
+    fn load(args: &Args) -> anyhow::Result<Self> {
+        let read = |path: &PathBuf| {
+            fs::read(path).with_context(|| format!("Cannot read {}", path.display()))
+        };
+
+        Ok(Self {
+            vid: args.vid,
+            pid: args.pid,
+            serial_no: args.serial_no.clone(),
+            discriminator: args.discriminator,
+            passcode: args.passcode,
+            dac: read(&args.dac)?,
+            dac_pubkey: read(&args.dac_pubkey)?,
+            dac_privkey: read(&args.dac_privkey)?,
+            pai: read(&args.pai)?,
+            cd: read(&args.cd)?,
+        })
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.discriminator > 0xfff {
+            bail!("The discriminator should be a 12-bit value");
+        }
+
+        if !onboarding::is_valid_passcode(self.passcode) {
+            bail!("Invalid passcode: {}", self.passcode);
+        }
+
+        if self.dac_pubkey.len() != 65 || self.dac_pubkey[0] != 0x04 {
+            bail!("The DAC public key should be an uncompressed SEC1 point (65 bytes)");
+        }
+
+        if self.dac_privkey.len() != 32 {
+            bail!("The DAC private key should be a raw scalar (32 bytes)");
+        }
+
+        Ok(())
+    }
+
+    /// Build the factory data image, optionally padded to `size`
+    fn image(&self, size: Option<usize>) -> anyhow::Result<Vec<u8>> {
+        self.validate()?;
+
+        let mut buf = vec![0; 16 * 1024];
+
+        let len = (|| {
+            let mut writer = FactoryDataWriter::new(&mut buf)?;
+
+            writer
+                .add(FactoryTag::Vid, &self.vid.to_le_bytes())?
+                .add(FactoryTag::Pid, &self.pid.to_le_bytes())?
+                .add(FactoryTag::SerialNo, self.serial_no.as_bytes())?
+                .add(FactoryTag::Discriminator, &self.discriminator.to_le_bytes())?
+                .add(FactoryTag::Passcode, &self.passcode.to_le_bytes())?
+                .add(FactoryTag::Dac, &self.dac)?
+                .add(FactoryTag::DacPubKey, &self.dac_pubkey)?
+                .add(FactoryTag::DacPrivKey, &self.dac_privkey)?
+                .add(FactoryTag::Pai, &self.pai)?
+                .add(FactoryTag::CertDeclaration, &self.cd)?;
+
+            writer.finish()
+        })()
+        .map_err(|e| anyhow!("Cannot build the image: {e:?}"))?;
+
+        buf.truncate(len);
+
+        if let Some(size) = size {
+            if size < len {
+                bail!("The image ({len} bytes) does not fit in {size} bytes");
+            }
+
+            buf.resize(size, 0xff);
+        }
+
+        Ok(buf)
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let entries = Entries::load(&args)?;
+    let image = entries.image(args.size)?;
+
+    fs::write(&args.out, &image).with_context(|| format!("Cannot write {}", args.out.display()))?;
+
+    println!(
+        "Image:        {} ({} bytes)",
+        args.out.display(),
+        image.len()
+    );
+    println!(
+        "QR code:      {}",
+        onboarding::qr_code(
+            args.vid,
+            args.pid,
+            args.discovery.capabilities(),
+            args.discriminator,
+            args.passcode
+        )?
+    );
+    println!(
+        "Manual code:  {}",
+        onboarding::manual_code(args.discriminator, args.passcode)
+    );
+
+    Ok(())
+}
+
+fn parse_u16(s: &str) -> Result<u16, String> {
+    let result = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u16::from_str_radix(hex, 16)
+    } else {
+        s.parse()
+    };
+
+    result.map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use rs_matter_embassy::factory::FactoryData;
+    use rs_matter_embassy::matter::data_model::sdm::dev_att::{DataType, DevAttDataFetcher};
+
+    use super::{parse_u16, Entries};
+
+    fn entries() -> Entries {
+        Entries {
+            vid: 0xfff1,
+            pid: 0x8000,
+            serial_no: "SN-0042".into(),
+            discriminator: 3840,
+            passcode: 20202021,
+            dac: vec![0xda; 480],
+            dac_pubkey: [[0x04].as_slice(), &[0x11; 64]].concat(),
+            dac_privkey: vec![0x22; 32],
+            pai: vec![0xba; 460],
+            cd: vec![0xcd; 235],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let entries = entries();
+        let image = entries.image(Some(4096)).unwrap();
+
+        assert_eq!(image.len(), 4096);
+
+        // The embedded side reads the image from the start of the flash range, and knows its length from the header
+        let len = rs_matter_embassy::factory::HEADER_LEN
+            + u32::from_le_bytes(image[8..12].try_into().unwrap()) as usize
+            + rs_matter_embassy::factory::TRAILER_LEN;
+        assert!(image[len..].iter().all(|b| *b == 0xff));
+
+        let data = FactoryData::parse(&image[..len]).unwrap();
+
+        assert_eq!(data.vid().unwrap(), entries.vid);
+        assert_eq!(data.pid().unwrap(), entries.pid);
+        assert_eq!(data.serial_no(), entries.serial_no);
+        assert_eq!(data.discriminator().unwrap(), entries.discriminator);
+        assert_eq!(data.passcode().unwrap(), entries.passcode);
+
+        let mut buf = [0; 1024];
+        for (data_type, expected) in [
+            (DataType::DAC, &entries.dac),
+            (DataType::DACPubKey, &entries.dac_pubkey),
+            (DataType::DACPrivKey, &entries.dac_privkey),
+            (DataType::PAI, &entries.pai),
+            (DataType::CertDeclaration, &entries.cd),
+        ] {
+            let len = data.get_devatt_data(data_type, &mut buf).unwrap();
+            assert_eq!(&buf[..len], expected.as_slice());
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let mut invalid = entries();
+        invalid.passcode = 12345678;
+        assert!(invalid.image(None).is_err());
+
+        let mut invalid = entries();
+        invalid.discriminator = 0x1000;
+        assert!(invalid.image(None).is_err());
+
+        let mut invalid = entries();
+        invalid.dac_privkey.pop();
+        assert!(invalid.image(None).is_err());
+
+        assert!(entries().image(Some(100)).is_err());
+    }
+
+    #[test]
+    fn u16_args() {
+        assert_eq!(parse_u16("0xFFF1"), Ok(0xfff1));
+        assert_eq!(parse_u16("32768"), Ok(0x8000));
+        assert!(parse_u16("0x10000").is_err());
+    }
+}
@@ -0,0 +1,87 @@
+//! Matter onboarding payloads: the QR code payload and the manual pairing code, as computed by `rs-matter`
+
+use anyhow::anyhow;
+
+use rs_matter_embassy::matter::data_model::cluster_basic_information::BasicInfoConfig;
+use rs_matter_embassy::matter::pairing::code::compute_pairing_code;
+use rs_matter_embassy::matter::pairing::qr::compute_qr_code_text;
+use rs_matter_embassy::matter::pairing::DiscoveryCapabilities;
+use rs_matter_embassy::matter::BasicCommData;
+
+pub use rs_matter_embassy::factory::is_valid_passcode;
+
+/// Return the QR code payload (`MT:...`) for a device using the standard commissioning flow.
+///
+/// Computed by `rs-matter` itself, so that it matches what the device prints and advertises.
+pub fn qr_code(
+    vid: u16,
+    pid: u16,
+    discovery: DiscoveryCapabilities,
+    discriminator: u16,
+    passcode: u32,
+) -> anyhow::Result<String> {
+    // Only the VID and PID are part of the payload
+    let dev_det = BasicInfoConfig {
+        vid,
+        pid,
+        hw_ver: 0,
+        sw_ver: 0,
+        sw_ver_str: "",
+        serial_no: "",
+        device_name: "",
+        product_name: "",
+        vendor_name: "",
+    };
+
+    let mut buf = [0; 128];
+
+    let payload = compute_qr_code_text(
+        &dev_det,
+        &comm_data(discriminator, passcode),
+        discovery,
+        &[],
+        &mut buf,
+    )
+    .map_err(|e| anyhow!("Cannot compute the QR code payload: {e:?}"))?;
+
+    Ok(payload.into())
+}
+
+/// Return the 11-digit manual pairing code of a device.
+///
+/// Computed by `rs-matter` itself, so that it matches what the device prints.
+pub fn manual_code(discriminator: u16, passcode: u32) -> String {
+    compute_pairing_code(&comm_data(discriminator, passcode)).to_string()
+}
+
+fn comm_data(discriminator: u16, passcode: u32) -> BasicCommData {
+    BasicCommData {
+        password: passcode,
+        discriminator,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn qr() {
+        assert_eq!(
+            qr_code(0xfff1, 0x8000, DiscoveryCapabilities::BLE, 3840, 20202021).unwrap(),
+            "MT:Y.K9042C00KA0648G00"
+        );
+    }
+
+    #[test]
+    fn manual() {
+        assert_eq!(manual_code(3840, 20202021), "34970112332");
+    }
+
+    #[test]
+    fn passcodes() {
+        assert!(is_valid_passcode(20202021));
+        assert!(!is_valid_passcode(12345678));
+        assert!(!is_valid_passcode(100_000_000));
+    }
+}